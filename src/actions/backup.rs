@@ -0,0 +1,114 @@
+use super::{ActRet, Op, PreAction};
+use crate::error::{CopyError, Result, Stage};
+use log::debug;
+use std::path::Path;
+use std::rc::Rc;
+use storage::Storage;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BackupMode {
+    Numbered,
+    Existing,
+    Simple,
+}
+
+pub struct BackupAction {
+    mode: BackupMode,
+    suffix: String,
+    storage: Rc<dyn Storage>,
+}
+
+impl BackupAction {
+    /// Returns `None` when the control value disables backups (`none`/`off`).
+    pub fn new(control: &str, suffix: String, storage: Rc<dyn Storage>) -> Result<Option<Self>> {
+        let mode = match control {
+            "none" | "off" => return Ok(None),
+            "numbered" | "t" => BackupMode::Numbered,
+            "existing" | "nil" => BackupMode::Existing,
+            "simple" | "never" => BackupMode::Simple,
+            _ => {
+                return Err(CopyError::InvalidArgument(format!(
+                    "invalid backup type: {}",
+                    control
+                )))
+            }
+        };
+        Ok(Some(BackupAction {
+            mode,
+            suffix,
+            storage,
+        }))
+    }
+
+    fn backup_path(&self, des: &str) -> Result<String> {
+        let numbered = match self.mode {
+            BackupMode::Simple => return Ok(format!("{}{}", des, self.suffix)),
+            BackupMode::Numbered => Some(last_backup_number(&*self.storage, des)?.unwrap_or(0)),
+            BackupMode::Existing => last_backup_number(&*self.storage, des)?,
+        };
+
+        match numbered {
+            Some(n) => Ok(format!("{}.~{}~", des, n + 1)),
+            None => Ok(format!("{}{}", des, self.suffix)),
+        }
+    }
+}
+
+// Find the highest N among the existing `des.~N~` backups
+fn last_backup_number(storage: &dyn Storage, des: &str) -> Result<Option<u64>> {
+    let des_path = Path::new(des);
+    let file_name = match des_path.file_name().and_then(|s| s.to_str()) {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    let parent = match des_path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let prefix = format!("{}.~", file_name);
+
+    let mut last = None;
+    let parent = parent.to_string_lossy();
+    let names = storage
+        .list(&parent)
+        .map_err(|e| CopyError::io(Stage::PreCopy, parent.clone(), e))?;
+    for name in names {
+        let n = name
+            .strip_prefix(&prefix)
+            .and_then(|s| s.strip_suffix('~'))
+            .and_then(|s| s.parse::<u64>().ok());
+        if let Some(n) = n {
+            last = last.max(Some(n));
+        }
+    }
+
+    Ok(last)
+}
+
+impl PreAction for BackupAction {
+    fn pre_run(&self, _: &str, des: &str) -> Result<ActRet> {
+        match self.storage.lstat(des) {
+            Ok(stat) if stat.is_dir() => return Ok(ActRet::GoOn),
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ActRet::GoOn),
+            Err(e) => return Err(CopyError::io(Stage::PreCopy, des, e)),
+        };
+
+        let backup = self.backup_path(des)?;
+        debug!("Backup {} to {}", des, backup);
+        self.storage
+            .rename(des, &backup)
+            .map_err(|e| CopyError::io(Stage::PreCopy, des, e))?;
+
+        Ok(ActRet::GoOn)
+    }
+
+    fn plan(&self, _: &str, des: &str) -> Result<(ActRet, Option<Op>)> {
+        match self.storage.lstat(des) {
+            Ok(stat) if stat.is_dir() => Ok((ActRet::GoOn, None)),
+            Ok(_) => Ok((ActRet::GoOn, Some(Op::Backup))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok((ActRet::GoOn, None)),
+            Err(e) => Err(CopyError::io(Stage::PreCopy, des, e)),
+        }
+    }
+}
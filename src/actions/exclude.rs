@@ -0,0 +1,56 @@
+use super::{ActRet, Op, PreAction};
+use crate::error::Result;
+use glob::Pattern;
+use std::cell::RefCell;
+use std::path::Path;
+
+pub struct ExcludeAction {
+    patterns: Vec<Pattern>,
+    // excluded directories, their entries are excluded as well
+    excluded_dirs: RefCell<Vec<String>>,
+}
+
+impl ExcludeAction {
+    pub fn new(patterns: Vec<Pattern>) -> Self {
+        ExcludeAction {
+            patterns,
+            excluded_dirs: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+pub fn is_excluded(patterns: &[Pattern], path: &str) -> bool {
+    match Path::new(path).file_name().and_then(|s| s.to_str()) {
+        Some(name) => patterns.iter().any(|p| p.matches(name)),
+        None => false,
+    }
+}
+
+pub fn is_under(dirs: &[String], path: &str) -> bool {
+    dirs.iter().any(|dir| {
+        path.strip_prefix(dir.as_str())
+            .is_some_and(|rest| rest.starts_with('/'))
+    })
+}
+
+impl PreAction for ExcludeAction {
+    fn pre_run(&self, src: &str, _: &str) -> Result<ActRet> {
+        if is_under(&self.excluded_dirs.borrow(), src) {
+            return Ok(ActRet::SkipRest);
+        }
+
+        if is_excluded(&self.patterns, src) {
+            self.excluded_dirs.borrow_mut().push(src.to_string());
+            return Ok(ActRet::SkipRest);
+        }
+
+        Ok(ActRet::GoOn)
+    }
+
+    fn plan(&self, src: &str, des: &str) -> Result<(ActRet, Option<Op>)> {
+        match self.pre_run(src, des)? {
+            ActRet::SkipRest => Ok((ActRet::SkipRest, Some(Op::Skip("excluded")))),
+            ret => Ok((ret, None)),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use super::exclude::{is_excluded, is_under};
+use crate::error::{CopyError, Result, Stage};
+use glob::Pattern;
+use log::debug;
+use std::collections::HashSet;
+use std::rc::Rc;
+use storage::Storage;
+
+pub struct Mirror {
+    excludes: Vec<Pattern>,
+    delete_excluded: bool,
+    storage: Rc<dyn Storage>,
+}
+
+impl Mirror {
+    pub fn new(excludes: Vec<Pattern>, delete_excluded: bool, storage: Rc<dyn Storage>) -> Self {
+        Mirror {
+            excludes,
+            delete_excluded,
+            storage,
+        }
+    }
+
+    /// Lists the destination entries without a counterpart in the sources.
+    pub fn extraneous(&self, src_paths: &[String], des_paths: &[String]) -> Result<Vec<String>> {
+        let wanted: HashSet<&str> = des_paths.iter().map(|s| s.as_str()).collect();
+        let mut extraneous = Vec::new();
+        // excluded or extraneous entries, nothing below them is looked at
+        let mut handled = Vec::new();
+
+        for (src, des) in src_paths.iter().zip(des_paths.iter()) {
+            let is_src_dir = self
+                .storage
+                .lstat(src)
+                .map_err(|e| CopyError::io(Stage::Mirror, src.as_str(), e))?
+                .is_dir();
+            if !is_src_dir || is_under(&handled, des) || is_excluded(&self.excludes, src) {
+                continue;
+            }
+
+            // a symlink may lead out of the destination, nothing is listed through it
+            match self.storage.lstat(des) {
+                Ok(stat) if stat.is_dir() => {}
+                Ok(_) => {
+                    handled.push(des.clone());
+                    continue;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(CopyError::io(Stage::Mirror, des.as_str(), e)),
+            }
+
+            let names = match self.storage.list(des) {
+                Ok(names) => names,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(CopyError::io(Stage::Mirror, des.as_str(), e)),
+            };
+
+            let mut children: Vec<String> = names
+                .iter()
+                .map(|name| format!("{}/{}", des, name))
+                .collect();
+            children.sort();
+
+            for child in children {
+                if is_excluded(&self.excludes, &child) {
+                    if self.delete_excluded {
+                        extraneous.push(child.clone());
+                    }
+                    handled.push(child);
+                } else if !wanted.contains(child.as_str()) {
+                    extraneous.push(child.clone());
+                    handled.push(child);
+                }
+            }
+        }
+
+        Ok(extraneous)
+    }
+
+    pub fn delete(&self, paths: &[String]) -> Result<()> {
+        for path in paths {
+            debug!("Deleting extraneous {}", path);
+            match self.storage.remove_all(path) {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(CopyError::io(Stage::Mirror, path.as_str(), e)),
+            }
+        }
+
+        Ok(())
+    }
+}
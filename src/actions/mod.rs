@@ -1,26 +1,101 @@
+use crate::error::Result;
+
 pub enum ActRet {
     GoOn,
     SkipRest,
     SkipCopy,
+    /// the source has been moved to the destination, only the post actions are left
+    Moved,
+}
+
+impl ActRet {
+    /// Merge the result of the next pre action into the accumulated one.
+    pub fn merge(self, next: ActRet) -> ActRet {
+        match (next, self) {
+            (ActRet::GoOn, pre) => pre,
+            (ActRet::Moved, _) => ActRet::Moved,
+            (ActRet::SkipRest, ActRet::SkipCopy) => ActRet::SkipCopy,
+            (ActRet::SkipRest, _) => ActRet::SkipRest,
+            (ActRet::SkipCopy, _) => ActRet::SkipCopy,
+        }
+    }
+
+    /// Whether the later pre actions must not run.
+    pub fn is_final(&self) -> bool {
+        matches!(self, ActRet::SkipRest | ActRet::Moved)
+    }
+}
+
+/// What a pre action would do to the destination, reported by `--dry-run`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    CreateDir,
+    Copy,
+    Overwrite,
+    Backup,
+    Symlink,
+    Special,
+    Rename,
+    Delete,
+    Skip(&'static str),
+}
+
+impl std::fmt::Display for Op {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Op::CreateDir => write!(f, "create-dir"),
+            Op::Copy => write!(f, "copy"),
+            Op::Overwrite => write!(f, "overwrite"),
+            Op::Backup => write!(f, "backup"),
+            Op::Symlink => write!(f, "symlink"),
+            Op::Special => write!(f, "special"),
+            Op::Rename => write!(f, "rename"),
+            Op::Delete => write!(f, "delete"),
+            Op::Skip(reason) => write!(f, "skip-{}", reason),
+        }
+    }
 }
 
 pub trait PreAction {
-    fn pre_run(&self, src: &str, dst: &str) -> anyhow::Result<ActRet>;
+    fn pre_run(&self, src: &str, dst: &str) -> Result<ActRet>;
+
+    /// Decide like `pre_run` does, without touching the file system. Actions
+    /// without a planner let the entry go on, with nothing to report.
+    fn plan(&self, _src: &str, _dst: &str) -> Result<(ActRet, Option<Op>)> {
+        Ok((ActRet::GoOn, None))
+    }
 }
 
 pub trait PostAction {
-    fn post_run(&self, src: &str, dst: &str) -> anyhow::Result<()>;
+    fn post_run(&self, src: &str, dst: &str) -> Result<()>;
+
+    /// Runs instead of `post_run` once a pre action has moved the source to
+    /// the destination, the source is gone then.
+    fn moved_run(&self, src: &str, dst: &str) -> Result<()> {
+        self.post_run(src, dst)
+    }
+}
+
+pub trait SkipAction {
+    fn skip_run(&self, src: &str, dst: &str) -> Result<()>;
 }
 
 pub trait Preparation {
-    fn get_ready(&self, total: u64) -> anyhow::Result<()>;
+    fn get_ready(&self, total: u64) -> Result<()>;
 }
 
 pub trait Ending {
-    fn done(&self) -> anyhow::Result<()>;
+    fn done(&self) -> Result<()>;
 }
 
+pub mod backup;
+pub mod exclude;
+pub mod mirror;
+pub mod mv;
+pub mod overwrite;
 pub mod preserve;
 pub mod recursive;
 pub mod showbar;
+pub mod special;
 pub mod update;
+pub mod verbose;
@@ -0,0 +1,165 @@
+use super::update::digest;
+use super::{ActRet, Ending, Op, PostAction, PreAction};
+use crate::error::{CopyError, Result, Stage};
+use log::debug;
+use std::cell::RefCell;
+use std::rc::Rc;
+use storage::{Stat, Storage};
+
+pub struct MoveAction {
+    // sources renamed as a whole, their entries need nothing more
+    renamed: RefCell<Vec<String>>,
+    // copied source directories, removed bottom-up once emptied
+    src_dirs: RefCell<Vec<String>>,
+    // false when some entries are excluded, they have to stay in the source
+    rename_dirs: bool,
+    storage: Rc<dyn Storage>,
+}
+
+impl MoveAction {
+    pub fn new(rename_dirs: bool, storage: Rc<dyn Storage>) -> Self {
+        MoveAction {
+            renamed: RefCell::new(Vec::new()),
+            src_dirs: RefCell::new(Vec::new()),
+            rename_dirs,
+            storage,
+        }
+    }
+
+    // Whether the source may be renamed as a whole
+    fn may_rename(&self, src: &str) -> Result<bool> {
+        if self.rename_dirs {
+            return Ok(true);
+        }
+        let stat = self
+            .storage
+            .lstat(src)
+            .map_err(|e| CopyError::io(Stage::PreCopy, src, e))?;
+        Ok(!stat.is_dir())
+    }
+
+    // Whether the copy has the size and the content of the source
+    fn same_content(&self, src: &str, src_stat: &Stat, des: &str) -> Result<bool> {
+        let des_len = self
+            .storage
+            .stat(des)
+            .map_err(|e| CopyError::io(Stage::PostCopy, des, e))?
+            .len;
+        if des_len != src_stat.len {
+            debug!(
+                "{} has {} bytes but {} has {}, keeping the source",
+                des, des_len, src, src_stat.len
+            );
+            return Ok(false);
+        }
+
+        let src_digest =
+            digest(&*self.storage, src).map_err(|e| CopyError::io(Stage::PostCopy, src, e))?;
+        let des_digest =
+            digest(&*self.storage, des).map_err(|e| CopyError::io(Stage::PostCopy, des, e))?;
+        if src_digest != des_digest {
+            debug!("{} differs from {}, keeping the source", des, src);
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn is_renamed(&self, src: &str) -> bool {
+        self.renamed.borrow().iter().any(|moved| {
+            src.strip_prefix(moved.as_str())
+                .is_some_and(|rest| rest.starts_with('/'))
+        })
+    }
+}
+
+impl PreAction for MoveAction {
+    fn pre_run(&self, src: &str, des: &str) -> Result<ActRet> {
+        if self.is_renamed(src) {
+            return Ok(ActRet::Moved);
+        }
+
+        // an existing destination goes through the copy path so the overwrite policies apply
+        match self.storage.lstat(des) {
+            Ok(_) => return Ok(ActRet::GoOn),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(CopyError::io(Stage::PreCopy, des, e)),
+        };
+        if !self.may_rename(src)? {
+            return Ok(ActRet::GoOn);
+        }
+
+        match self.storage.rename(src, des) {
+            Ok(_) => {
+                self.renamed.borrow_mut().push(src.to_string());
+                Ok(ActRet::Moved)
+            }
+            Err(e) => {
+                // EXDEV mostly, fall back to copy, verify and unlink
+                debug!("Failed to rename {} to {}: {}", src, des, e);
+                Ok(ActRet::GoOn)
+            }
+        }
+    }
+
+    fn plan(&self, src: &str, des: &str) -> Result<(ActRet, Option<Op>)> {
+        if self.is_renamed(src) {
+            return Ok((ActRet::Moved, None));
+        }
+
+        match self.storage.lstat(des) {
+            Ok(_) => Ok((ActRet::GoOn, None)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !self.may_rename(src)? => {
+                Ok((ActRet::GoOn, None))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                // assume the rename works, a copy across file systems ends up the same
+                self.renamed.borrow_mut().push(src.to_string());
+                Ok((ActRet::Moved, Some(Op::Rename)))
+            }
+            Err(e) => Err(CopyError::io(Stage::PreCopy, des, e)),
+        }
+    }
+}
+
+impl PostAction for MoveAction {
+    fn post_run(&self, src: &str, des: &str) -> Result<()> {
+        let src_stat = self
+            .storage
+            .lstat(src)
+            .map_err(|e| CopyError::io(Stage::PostCopy, src, e))?;
+
+        if src_stat.is_dir() {
+            self.src_dirs.borrow_mut().push(src.to_string());
+            return Ok(());
+        }
+
+        if src_stat.is_file() && !self.same_content(src, &src_stat, des)? {
+            return Err(CopyError::Mismatch {
+                stage: Stage::PostCopy,
+                src: src.to_string(),
+                des: des.to_string(),
+            });
+        }
+
+        self.storage
+            .remove_file(src)
+            .map_err(|e| CopyError::io(Stage::PostCopy, src, e))
+    }
+
+    fn moved_run(&self, _: &str, _: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Ending for MoveAction {
+    fn done(&self) -> Result<()> {
+        // directories were collected parents first
+        for dir in self.src_dirs.borrow().iter().rev() {
+            if let Err(e) = self.storage.remove_dir(dir) {
+                debug!("Failed to remove source directory {}: {}", dir, e);
+            }
+        }
+
+        Ok(())
+    }
+}
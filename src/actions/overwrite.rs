@@ -0,0 +1,116 @@
+use super::{ActRet, Op, PreAction};
+use crate::error::{CopyError, Result, Stage};
+use indicatif::MultiProgress;
+use log::debug;
+use std::cell::Cell;
+use std::io::Write;
+use std::rc::Rc;
+use storage::{Kind, Storage};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverwritePolicy {
+    Overwrite,
+    NoClobber,
+    Interactive,
+}
+
+pub struct OverwriteAction {
+    policy: OverwritePolicy,
+    force: bool,
+    // bars to suspend while prompting, None when muted
+    bars: Option<MultiProgress>,
+    // the "yes to all"/"no to all" answer once given
+    answer_all: Cell<Option<bool>>,
+    storage: Rc<dyn Storage>,
+}
+
+impl OverwriteAction {
+    pub fn new(
+        policy: OverwritePolicy,
+        force: bool,
+        bars: Option<MultiProgress>,
+        storage: Rc<dyn Storage>,
+    ) -> Self {
+        OverwriteAction {
+            policy,
+            force,
+            bars,
+            answer_all: Cell::new(None),
+            storage,
+        }
+    }
+
+    fn ask(&self, des: &str) -> Result<bool> {
+        if let Some(answer) = self.answer_all.get() {
+            return Ok(answer);
+        }
+
+        let prompt = || -> std::io::Result<String> {
+            let mut stderr = std::io::stderr();
+            write!(stderr, "pbcp: overwrite '{}'? (y/n/all/none) ", des)?;
+            stderr.flush()?;
+            let mut line = String::new();
+            std::io::stdin().read_line(&mut line)?;
+            Ok(line)
+        };
+
+        let line = match &self.bars {
+            Some(bars) => bars.suspend(prompt),
+            None => prompt(),
+        }
+        .map_err(|e| CopyError::io(Stage::PreCopy, des, e))?;
+
+        match line.trim() {
+            "y" | "Y" | "yes" => Ok(true),
+            "all" | "a" => {
+                self.answer_all.set(Some(true));
+                Ok(true)
+            }
+            "none" => {
+                self.answer_all.set(Some(false));
+                Ok(false)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+impl PreAction for OverwriteAction {
+    fn pre_run(&self, _: &str, des: &str) -> Result<ActRet> {
+        let des_kind = match self.storage.lstat(des) {
+            Ok(stat) if stat.is_dir() => return Ok(ActRet::GoOn),
+            Ok(stat) => stat.kind,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ActRet::GoOn),
+            Err(e) => return Err(CopyError::io(Stage::PreCopy, des, e)),
+        };
+
+        match self.policy {
+            OverwritePolicy::NoClobber => return Ok(ActRet::SkipRest),
+            OverwritePolicy::Interactive if !self.ask(des)? => return Ok(ActRet::SkipRest),
+            _ => {}
+        }
+
+        if self.force && des_kind == Kind::File {
+            if let Err(e) = self.storage.open_update(des) {
+                debug!("Cannot open {} for writing({}), removing it", des, e);
+                self.storage
+                    .remove_file(des)
+                    .map_err(|e| CopyError::io(Stage::PreCopy, des, e))?;
+            }
+        }
+
+        Ok(ActRet::GoOn)
+    }
+
+    fn plan(&self, _: &str, des: &str) -> Result<(ActRet, Option<Op>)> {
+        match self.storage.lstat(des) {
+            Ok(stat) if stat.is_dir() => Ok((ActRet::GoOn, None)),
+            Ok(_) if self.policy == OverwritePolicy::NoClobber => {
+                Ok((ActRet::SkipRest, Some(Op::Skip("existing"))))
+            }
+            Ok(_) => Ok((ActRet::GoOn, None)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok((ActRet::GoOn, None)),
+            Err(e) => Err(CopyError::io(Stage::PreCopy, des, e)),
+        }
+    }
+}
@@ -1,16 +1,16 @@
-use super::{ActRet, PostAction, PreAction};
-use anyhow::Context;
-use filetime;
+use super::{ActRet, Op, PostAction, PreAction};
+use crate::error::{CopyError, Result, Stage};
 use log::debug;
-use std::fs;
-use std::os::unix::fs::MetadataExt;
+use std::rc::Rc;
+use storage::{Attrs, Storage};
 
 pub struct PreserveAction {
     attrs: Vec<String>,
+    storage: Rc<dyn Storage>,
 }
 
 impl PreserveAction {
-    pub fn new(attrs: String) -> Self {
+    pub fn new(attrs: String, storage: Rc<dyn Storage>) -> Self {
         let attrs = if attrs == "all" {
             vec!["links", "mode", "ownership", "timestamps"]
                 .into_iter()
@@ -19,16 +19,21 @@ impl PreserveAction {
         } else {
             attrs.split(',').map(|s| s.to_string()).collect()
         };
-        PreserveAction { attrs }
+        PreserveAction { attrs, storage }
+    }
+
+    /// Whether the attribute, e.g. `mode` or `links`, is preserved.
+    pub fn preserves(&self, attr: &str) -> bool {
+        self.attrs.iter().any(|a| a == attr)
     }
 }
 
 impl PreAction for PreserveAction {
-    fn pre_run(&self, src: &str, des: &str) -> anyhow::Result<ActRet> {
+    fn pre_run(&self, src: &str, des: &str) -> Result<ActRet> {
         for attr in self.attrs.iter() {
             if attr == "links" {
-                if let Ok(target) = fs::read_link(src) {
-                    match std::os::unix::fs::symlink(&target, des) {
+                if let Ok(target) = self.storage.read_link(src) {
+                    match self.storage.symlink(&target, des) {
                         Ok(_) => {
                             return Ok(ActRet::SkipCopy);
                         }
@@ -37,7 +42,7 @@ impl PreAction for PreserveAction {
                         }
                         Err(e) => {
                             debug!("Failed to create symlink for: {}", des);
-                            return Err(e.into());
+                            return Err(CopyError::io(Stage::PreCopy, des, e));
                         }
                     }
                 }
@@ -46,17 +51,34 @@ impl PreAction for PreserveAction {
 
         Ok(ActRet::GoOn)
     }
+
+    fn plan(&self, src: &str, des: &str) -> Result<(ActRet, Option<Op>)> {
+        if self.preserves("links") && self.storage.read_link(src).is_ok() {
+            if self.storage.lstat(des).is_ok() {
+                return Ok((ActRet::SkipCopy, None));
+            }
+            return Ok((ActRet::SkipCopy, Some(Op::Symlink)));
+        }
+
+        Ok((ActRet::GoOn, None))
+    }
 }
 
 impl PostAction for PreserveAction {
-    fn post_run(&self, src: &str, des: &str) -> anyhow::Result<()> {
-        let src_metadata = fs::metadata(src)
-            .with_context(|| format!("Failed to get metadata of source: {}", src))?;
+    fn post_run(&self, src: &str, des: &str) -> Result<()> {
+        let src_stat = self
+            .storage
+            .stat(src)
+            .map_err(|e| CopyError::io(Stage::PostCopy, src, e))?;
 
         for attr in self.attrs.iter() {
             match attr.as_str() {
                 "mode" => {
-                    match fs::set_permissions(des, src_metadata.permissions()) {
+                    let attrs = Attrs {
+                        mode: Some(src_stat.mode),
+                        ..Default::default()
+                    };
+                    match self.storage.set_attrs(des, &attrs) {
                         Ok(_) => {}
                         Err(e) => {
                             debug!("Failed to set permissions for: {}", des);
@@ -65,9 +87,11 @@ impl PostAction for PreserveAction {
                     };
                 }
                 "ownership" => {
-                    let uid = nix::unistd::Uid::from_raw(src_metadata.uid());
-                    let gid = nix::unistd::Gid::from_raw(src_metadata.gid());
-                    match nix::unistd::chown(des, Some(uid), Some(gid)) {
+                    let attrs = Attrs {
+                        owner: Some((src_stat.uid, src_stat.gid)),
+                        ..Default::default()
+                    };
+                    match self.storage.set_attrs(des, &attrs) {
                         Ok(_) => {}
                         Err(e) => {
                             debug!("Failed to set ownership for: {}", des);
@@ -76,9 +100,11 @@ impl PostAction for PreserveAction {
                     };
                 }
                 "timestamps" => {
-                    let atime = filetime::FileTime::from_last_access_time(&src_metadata);
-                    let mtime = filetime::FileTime::from_last_modification_time(&src_metadata);
-                    match filetime::set_file_times(des, atime, mtime) {
+                    let attrs = Attrs {
+                        times: Some((src_stat.atime, src_stat.mtime)),
+                        ..Default::default()
+                    };
+                    match self.storage.set_attrs(des, &attrs) {
                         Ok(_) => {}
                         Err(e) => {
                             debug!("Failed to set timestamps for: {}", des);
@@ -92,4 +118,9 @@ impl PostAction for PreserveAction {
 
         Ok(())
     }
+
+    fn moved_run(&self, _: &str, _: &str) -> Result<()> {
+        // a rename keeps the attributes
+        Ok(())
+    }
 }
@@ -1,22 +1,50 @@
-use super::{ActRet, PreAction};
-use std::path::Path;
-pub struct RecursiveAction;
+use super::{ActRet, Op, PreAction};
+use crate::error::{CopyError, Result, Stage};
+use std::rc::Rc;
+use storage::Storage;
+
+pub struct RecursiveAction {
+    storage: Rc<dyn Storage>,
+}
+
+impl RecursiveAction {
+    pub fn new(storage: Rc<dyn Storage>) -> Self {
+        RecursiveAction { storage }
+    }
+
+    // Whether the source is a directory rather than a symlink to one
+    fn is_dir(&self, src: &str) -> Result<bool> {
+        if self.storage.stat(src).is_err() {
+            return Err(CopyError::NotFound {
+                stage: Stage::PreCopy,
+                path: src.to_string(),
+            });
+        }
+        Ok(self.storage.lstat(src).is_ok_and(|stat| stat.is_dir()))
+    }
+}
 
 impl PreAction for RecursiveAction {
-    fn pre_run(&self, src: &str, des: &str) -> anyhow::Result<ActRet> {
-        let src_path = Path::new(src);
+    fn pre_run(&self, src: &str, des: &str) -> Result<ActRet> {
+        if !self.is_dir(src)? {
+            return Ok(ActRet::GoOn);
+        }
+
+        // create directory
+        match self.storage.mkdir(des) {
+            Ok(_) => Ok(ActRet::SkipCopy),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(ActRet::SkipCopy),
+            Err(e) => Err(CopyError::io(Stage::PreCopy, des, e)),
+        }
+    }
 
-        if !src_path.exists() {
-            Err(anyhow::anyhow!("Source path does not exist: {}", src))
-        } else if src_path.is_dir() && !src_path.is_symlink() {
-            // create directory
-            match std::fs::create_dir(des) {
-                Ok(_) => Ok(ActRet::SkipCopy),
-                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(ActRet::SkipCopy),
-                Err(e) => Err(e.into()),
-            }
+    fn plan(&self, src: &str, des: &str) -> Result<(ActRet, Option<Op>)> {
+        if !self.is_dir(src)? {
+            Ok((ActRet::GoOn, None))
+        } else if self.storage.stat(des).is_ok() {
+            Ok((ActRet::SkipCopy, None))
         } else {
-            Ok(ActRet::GoOn)
+            Ok((ActRet::SkipCopy, Some(Op::CreateDir)))
         }
     }
 }
@@ -1,39 +1,74 @@
 use super::{Ending, PostAction, Preparation};
-use anyhow::Context;
+use crate::error::{CopyError, Result, Stage};
 use copier::InCopyAction;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 
 pub struct ShowBar {
-    _m: MultiProgress,
+    m: MultiProgress,
     total_pbar: ProgressBar,
     pb: ProgressBar,
+    bar_sty: ProgressStyle,
+    // for inputs of unknown length
+    spinner_sty: ProgressStyle,
+    // one per destination when copying to several
+    des_bars: Vec<ProgressBar>,
 }
 
 impl ShowBar {
-    pub fn new() -> anyhow::Result<Self> {
+    pub fn new() -> Result<Self> {
         let m = MultiProgress::new();
         let sty = ProgressStyle::with_template(
             "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
         )
-        .with_context(|| "Failed to create progress style")?
+        .map_err(|e| CopyError::other(Stage::Setup, e))?
         .progress_chars("##-");
 
         let total_pbar = m.add(ProgressBar::new(0));
         total_pbar.set_style(sty.clone());
 
         let pb = m.add(ProgressBar::new(0));
-        pb.set_style(sty);
+        pb.set_style(sty.clone());
+
+        let spinner_sty = ProgressStyle::with_template(
+            "[{elapsed_precise}] {spinner} {bytes} ({bytes_per_sec}) {msg}",
+        )
+        .map_err(|e| CopyError::other(Stage::Setup, e))?;
 
         Ok(Self {
-            _m: m,
+            m,
             total_pbar,
             pb,
+            bar_sty: sty,
+            spinner_sty,
+            des_bars: Vec::new(),
         })
     }
+
+    /// Adds a bar under the file bar for each of the destinations.
+    pub fn add_destinations(&mut self, names: &[String]) -> Result<()> {
+        let sty = ProgressStyle::with_template(
+            "  {prefix:20!} {bar:31.green/blue} {bytes:>9}/{total_bytes:9} {msg}",
+        )
+        .map_err(|e| CopyError::other(Stage::Setup, e))?
+        .progress_chars("##-");
+
+        for name in names {
+            let pb = self.m.add(ProgressBar::new(0));
+            pb.set_style(sty.clone());
+            pb.set_prefix(name.clone());
+            self.des_bars.push(pb);
+        }
+
+        Ok(())
+    }
+
+    pub fn multi_progress(&self) -> MultiProgress {
+        self.m.clone()
+    }
 }
 
 impl Preparation for ShowBar {
-    fn get_ready(&self, total: u64) -> anyhow::Result<()> {
+    fn get_ready(&self, total: u64) -> Result<()> {
         self.total_pbar.set_length(total);
         Ok(())
     }
@@ -41,17 +76,32 @@ impl Preparation for ShowBar {
 
 impl InCopyAction for ShowBar {
     fn set_length(&self, length: u64) {
+        if length == 0 {
+            self.pb.set_style(self.spinner_sty.clone());
+        } else {
+            self.pb.set_style(self.bar_sty.clone());
+        }
         self.pb.set_length(length);
+        for pb in self.des_bars.iter() {
+            pb.set_length(length);
+            pb.set_position(0);
+        }
     }
 
     fn in_copy_run(&self, copied: u64) {
         self.pb.set_position(copied);
         self.pb.set_message("bytes copied");
     }
+
+    fn in_copy_run_to(&self, index: usize, written: u64) {
+        if let Some(pb) = self.des_bars.get(index) {
+            pb.set_position(written);
+        }
+    }
 }
 
 impl PostAction for ShowBar {
-    fn post_run(&self, _: &str, _: &str) -> anyhow::Result<()> {
+    fn post_run(&self, _: &str, _: &str) -> Result<()> {
         self.total_pbar.inc(1);
         self.total_pbar.set_message("files copied");
         Ok(())
@@ -59,8 +109,11 @@ impl PostAction for ShowBar {
 }
 
 impl Ending for ShowBar {
-    fn done(&self) -> anyhow::Result<()> {
+    fn done(&self) -> Result<()> {
         self.total_pbar.finish_with_message("All files copied");
+        for pb in self.des_bars.iter() {
+            pb.finish();
+        }
         Ok(())
     }
 }
@@ -68,7 +121,7 @@ impl Ending for ShowBar {
 pub struct NoBar;
 
 impl Preparation for NoBar {
-    fn get_ready(&self, _: u64) -> anyhow::Result<()> {
+    fn get_ready(&self, _: u64) -> Result<()> {
         Ok(())
     }
 }
@@ -79,7 +132,7 @@ impl InCopyAction for NoBar {
 }
 
 impl Ending for NoBar {
-    fn done(&self) -> anyhow::Result<()> {
+    fn done(&self) -> Result<()> {
         Ok(())
     }
 }
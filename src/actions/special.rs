@@ -0,0 +1,60 @@
+use super::{ActRet, Op, PreAction};
+use crate::error::{CopyError, Result, Stage};
+use log::{debug, warn};
+use std::rc::Rc;
+use storage::Storage;
+
+pub struct SpecialAction {
+    skip: bool,
+    storage: Rc<dyn Storage>,
+}
+
+impl SpecialAction {
+    pub fn new(skip: bool, storage: Rc<dyn Storage>) -> Self {
+        SpecialAction { skip, storage }
+    }
+}
+
+impl PreAction for SpecialAction {
+    fn pre_run(&self, src: &str, des: &str) -> Result<ActRet> {
+        let src_stat = self
+            .storage
+            .lstat(src)
+            .map_err(|e| CopyError::io(Stage::PreCopy, src, e))?;
+
+        if !src_stat.kind.is_special() {
+            return Ok(ActRet::GoOn);
+        }
+
+        if self.skip {
+            warn!("Skipping special file: {}", src);
+            return Ok(ActRet::SkipRest);
+        }
+
+        match self.storage.mknod(des, &src_stat) {
+            Ok(_) => Ok(ActRet::SkipCopy),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(ActRet::SkipCopy),
+            Err(e) => {
+                debug!("Failed to create special file: {}", des);
+                Err(CopyError::io(Stage::PreCopy, des, e))
+            }
+        }
+    }
+
+    fn plan(&self, src: &str, des: &str) -> Result<(ActRet, Option<Op>)> {
+        let src_stat = self
+            .storage
+            .lstat(src)
+            .map_err(|e| CopyError::io(Stage::PreCopy, src, e))?;
+
+        if !src_stat.kind.is_special() {
+            Ok((ActRet::GoOn, None))
+        } else if self.skip {
+            Ok((ActRet::SkipRest, Some(Op::Skip("special"))))
+        } else if self.storage.lstat(des).is_ok() {
+            Ok((ActRet::SkipCopy, None))
+        } else {
+            Ok((ActRet::SkipCopy, Some(Op::Special)))
+        }
+    }
+}
@@ -1,25 +1,148 @@
-use super::{ActRet, PreAction};
-use anyhow::Context;
+use super::{ActRet, Op, PreAction};
+use crate::error::{CopyError, Result, Stage};
+use log::debug;
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+use storage::{Stat, Storage};
+use xxhash_rust::xxh3::Xxh3;
 
-pub struct UpdateAction;
+const CHECKSUM_XATTR: &str = "user.pbcp.xxh3";
 
-impl PreAction for UpdateAction {
-    fn pre_run(&self, src: &str, des: &str) -> anyhow::Result<ActRet> {
-        let des_metadata = match std::fs::metadata(des) {
-            Ok(metadata) => metadata,
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ActRet::GoOn),
-            Err(e) => return Err(e.into()),
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SkipIf {
+    /// the destination is not older than the source
+    Mtime,
+    /// both files have the same size
+    Size,
+    /// both files have the same size and modification time
+    SizeMtime,
+    /// both files have the same size and content
+    Checksum,
+}
+
+impl SkipIf {
+    pub fn parse(criterion: &str) -> Result<Self> {
+        match criterion {
+            "mtime" => Ok(SkipIf::Mtime),
+            "size" => Ok(SkipIf::Size),
+            "size+mtime" => Ok(SkipIf::SizeMtime),
+            "checksum" => Ok(SkipIf::Checksum),
+            _ => Err(CopyError::InvalidArgument(format!(
+                "invalid skip criterion: {}",
+                criterion
+            ))),
+        }
+    }
+}
+
+/// The xxh3 digest of the content of a file.
+pub fn digest(storage: &dyn Storage, path: &str) -> std::io::Result<String> {
+    let mut file = storage.open_read(path)?;
+    let mut hasher = Xxh3::new();
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => hasher.update(&buffer[..n]),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(format!("{:032x}", hasher.digest128()))
+}
+
+pub struct UpdateAction {
+    skip_if: SkipIf,
+    // keep the checksums in extended attributes to skip rehashing unchanged files
+    cache: bool,
+    storage: Rc<dyn Storage>,
+}
+
+impl UpdateAction {
+    pub fn new(skip_if: SkipIf, cache: bool, storage: Rc<dyn Storage>) -> Self {
+        UpdateAction {
+            skip_if,
+            cache,
+            storage,
+        }
+    }
+
+    // only destination files get a cached digest, the sources are left untouched
+    fn checksum(&self, path: &str, stat: &Stat, store: bool) -> Result<String> {
+        // the cached digest is only valid for the same size and modification time
+        let mtime = stat.mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let stamp = format!("{}.{}:{}", mtime.as_secs(), mtime.subsec_nanos(), stat.len);
+
+        if self.cache {
+            if let Ok(Some(value)) = xattr::get(path, CHECKSUM_XATTR) {
+                if let Some(digest) =
+                    String::from_utf8_lossy(&value).strip_prefix(&format!("{}:", stamp))
+                {
+                    return Ok(digest.to_string());
+                }
+            }
+        }
+
+        let digest =
+            digest(&*self.storage, path).map_err(|e| CopyError::io(Stage::PreCopy, path, e))?;
+
+        if self.cache && store {
+            let value = format!("{}:{}", stamp, digest);
+            if let Err(e) = xattr::set(path, CHECKSUM_XATTR, value.as_bytes()) {
+                debug!("Failed to cache checksum of {}: {}", path, e);
+            }
+        }
+
+        Ok(digest)
+    }
+
+    // Whether the destination is up to date, the cache is only written when `store` is set
+    fn up_to_date(&self, src: &str, des: &str, store: bool) -> Result<bool> {
+        let des_stat = match self.storage.stat(des) {
+            Ok(stat) => stat,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(CopyError::io(Stage::PreCopy, des, e)),
         };
-        let des_modified = des_metadata
-            .modified()
-            .with_context(|| format!("Failed to get modified time of destination: {}", des))?;
-        let src_modified = std::fs::metadata(src)
-            .with_context(|| format!("Failed to get metadata of source: {}", src))?
-            .modified()
-            .with_context(|| format!("Failed to get modified time of source: {}", src))?;
-        if src_modified <= des_modified {
+        let src_stat = self
+            .storage
+            .stat(src)
+            .map_err(|e| CopyError::io(Stage::PreCopy, src, e))?;
+
+        Ok(match self.skip_if {
+            SkipIf::Mtime => src_stat.mtime <= des_stat.mtime,
+            SkipIf::Size => src_stat.len == des_stat.len,
+            SkipIf::SizeMtime => src_stat.len == des_stat.len && src_stat.mtime == des_stat.mtime,
+            SkipIf::Checksum => {
+                src_stat.is_file()
+                    && des_stat.is_file()
+                    && src_stat.len == des_stat.len
+                    && self.checksum(src, &src_stat, false)?
+                        == self.checksum(des, &des_stat, store)?
+            }
+        })
+    }
+}
+
+impl PreAction for UpdateAction {
+    fn pre_run(&self, src: &str, des: &str) -> Result<ActRet> {
+        if self.up_to_date(src, des, true)? {
             return Ok(ActRet::SkipRest);
         }
         Ok(ActRet::GoOn)
     }
+
+    fn plan(&self, src: &str, des: &str) -> Result<(ActRet, Option<Op>)> {
+        // the copy goes on into the directories, whatever their times
+        let is_dir = self.storage.lstat(src).is_ok_and(|stat| stat.is_dir());
+        if is_dir {
+            return Ok((ActRet::GoOn, None));
+        }
+        match self.up_to_date(src, des, false)? {
+            true if self.skip_if == SkipIf::Mtime => {
+                Ok((ActRet::SkipRest, Some(Op::Skip("newer"))))
+            }
+            true => Ok((ActRet::SkipRest, Some(Op::Skip("same")))),
+            false => Ok((ActRet::GoOn, None)),
+        }
+    }
 }
@@ -0,0 +1,79 @@
+use super::exclude::is_under;
+use super::{ActRet, PostAction, PreAction, SkipAction};
+use crate::error::{CopyError, Result, Stage};
+use indicatif::MultiProgress;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use storage::Storage;
+
+pub struct VerboseAction {
+    // bars to print above, None when muted
+    bars: Option<MultiProgress>,
+    // whether the current destination existed before the pre actions
+    existed: Cell<bool>,
+    // sources renamed by --move, their entries are not reported again
+    renamed: RefCell<Vec<String>>,
+    storage: Rc<dyn Storage>,
+}
+
+impl VerboseAction {
+    pub fn new(bars: Option<MultiProgress>, storage: Rc<dyn Storage>) -> Self {
+        VerboseAction {
+            bars,
+            existed: Cell::new(false),
+            renamed: RefCell::new(Vec::new()),
+            storage,
+        }
+    }
+
+    fn print(&self, line: String) {
+        match &self.bars {
+            Some(bars) => bars.suspend(|| println!("{}", line)),
+            None => println!("{}", line),
+        }
+    }
+}
+
+impl PreAction for VerboseAction {
+    fn pre_run(&self, _: &str, des: &str) -> Result<ActRet> {
+        self.existed.set(self.storage.lstat(des).is_ok());
+        Ok(ActRet::GoOn)
+    }
+}
+
+impl PostAction for VerboseAction {
+    fn post_run(&self, src: &str, des: &str) -> Result<()> {
+        let des_stat = self
+            .storage
+            .lstat(des)
+            .map_err(|e| CopyError::io(Stage::PostCopy, des, e))?;
+
+        if des_stat.is_dir() {
+            if !self.existed.get() {
+                self.print(format!("created directory '{}'", des));
+            }
+        } else if des_stat.is_symlink() {
+            self.print(format!("linked '{}' -> '{}'", src, des));
+        } else {
+            self.print(format!("'{}' -> '{}'", src, des));
+        }
+
+        Ok(())
+    }
+
+    fn moved_run(&self, src: &str, des: &str) -> Result<()> {
+        // the entries of a renamed directory went with it
+        if !is_under(&self.renamed.borrow(), src) {
+            self.print(format!("renamed '{}' -> '{}'", src, des));
+            self.renamed.borrow_mut().push(src.to_string());
+        }
+        Ok(())
+    }
+}
+
+impl SkipAction for VerboseAction {
+    fn skip_run(&self, src: &str, _: &str) -> Result<()> {
+        self.print(format!("skipped '{}'", src));
+        Ok(())
+    }
+}
@@ -0,0 +1,470 @@
+use super::actions::{exclude, preserve::PreserveAction};
+use super::error::{CopyError, Result, Stage};
+use super::job::{scan_error, CopyReport, Progress};
+use copier::copiers::compresscopier::{self, Codec, Encoder};
+use copier::InCopyAction;
+use glob::Pattern;
+use log::{debug, trace, warn};
+use nix::sys::stat::{Mode, SFlag};
+use scanner::DirScan;
+use std::cell::Cell;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Component, Path};
+use std::rc::Rc;
+use tar::{EntryType, Header, HeaderMode};
+
+const SUFFIXES: [(&str, Option<Codec>); 7] = [
+    (".tar", None),
+    (".tar.zst", Some(Codec::Zstd)),
+    (".tzst", Some(Codec::Zstd)),
+    (".tar.gz", Some(Codec::Gzip)),
+    (".tgz", Some(Codec::Gzip)),
+    (".tar.xz", Some(Codec::Xz)),
+    (".txz", Some(Codec::Xz)),
+];
+
+/// The compression of the archive a path names, `None` when it is no archive name.
+pub fn archive_codec(path: &str) -> Option<Option<Codec>> {
+    SUFFIXES
+        .iter()
+        .find(|(suffix, _)| path.ends_with(suffix))
+        .map(|(_, codec)| *codec)
+}
+
+/// Packs trees into a tar archive, or extracts one into a directory.
+pub struct Archiver {
+    pub preserve: Option<PreserveAction>,
+    pub excludes: Vec<Pattern>,
+    pub skip_special: bool,
+    pub progress: Progress,
+}
+
+// What has been appended for a source entry
+enum Appended {
+    File(u64),
+    Other,
+    Skipped,
+}
+
+// The archive file, compressed or not
+enum Sink {
+    Plain(BufWriter<File>),
+    Compressed(Encoder<BufWriter<File>>),
+}
+
+impl Sink {
+    fn finish(self) -> io::Result<File> {
+        let writer = match self {
+            Sink::Plain(writer) => writer,
+            Sink::Compressed(encoder) => encoder.finish()?,
+        };
+        writer.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Plain(writer) => writer.write(buf),
+            Sink::Compressed(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Plain(writer) => writer.flush(),
+            Sink::Compressed(encoder) => encoder.flush(),
+        }
+    }
+}
+
+// Reports the progress of the file being appended
+struct ProgressReader<'a, R> {
+    inner: R,
+    read: u64,
+    callback: &'a dyn InCopyAction,
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        self.callback.in_copy_run(self.read);
+        Ok(n)
+    }
+}
+
+// Where the data of the entry being extracted lies in the tar stream
+struct Span {
+    start: Cell<u64>,
+    len: Cell<u64>,
+}
+
+// Reports the progress of the extracted entries while tar reads the stream
+struct TrackingReader<R> {
+    inner: R,
+    pos: u64,
+    span: Rc<Span>,
+    callback: Rc<dyn InCopyAction>,
+}
+
+impl<R: Read> Read for TrackingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.pos += n as u64;
+        let (start, len) = (self.span.start.get(), self.span.len.get());
+        if len > 0 && self.pos > start {
+            self.callback.in_copy_run((self.pos - start).min(len));
+        }
+        Ok(n)
+    }
+}
+
+impl Archiver {
+    fn preserves(&self, attr: &str) -> bool {
+        self.preserve
+            .as_ref()
+            .is_some_and(|preserve| preserve.preserves(attr))
+    }
+
+    /// Writes the scanned sources into the archive `des`.
+    pub fn pack(&self, srcs: &[String], des: &str, codec: Option<Codec>) -> Result<CopyReport> {
+        let scanner = scanner::scanners::basescanner::BaseScanner::new("");
+        let (src_paths, names) = scanner.scan(srcs, false).map_err(scan_error)?;
+        debug!("packing {:?}", src_paths);
+
+        let file = File::create(des).map_err(|e| CopyError::io(Stage::Setup, des, e))?;
+        let des_metadata = file
+            .metadata()
+            .map_err(|e| CopyError::io(Stage::Setup, des, e))?;
+        let writer = BufWriter::new(file);
+        let sink = match codec {
+            Some(codec) => Sink::Compressed(
+                Encoder::with_default_level(codec, writer)
+                    .map_err(|e| CopyError::io(Stage::Setup, des, e))?,
+            ),
+            None => Sink::Plain(writer),
+        };
+
+        let mut builder = tar::Builder::new(sink);
+        let mut report = CopyReport::default();
+        let mut excluded = Vec::new();
+        self.progress
+            .preparation
+            .get_ready(src_paths.len() as u64)?;
+
+        for (src, name) in src_paths.iter().zip(names.iter()) {
+            let name = name.trim_start_matches('/');
+            trace!("Pack {} as {}", src, name);
+
+            if exclude::is_under(&excluded, src) {
+                report.skipped += 1;
+                continue;
+            }
+            if exclude::is_excluded(&self.excludes, src) {
+                excluded.push(src.clone());
+                report.skipped += 1;
+                continue;
+            }
+            // the archive may lie in the scanned tree
+            if fs::symlink_metadata(src)
+                .is_ok_and(|m| m.dev() == des_metadata.dev() && m.ino() == des_metadata.ino())
+            {
+                report.skipped += 1;
+                continue;
+            }
+
+            match self
+                .append(&mut builder, src, name)
+                .map_err(|e| CopyError::io(Stage::Copy, src.as_str(), e))?
+            {
+                Appended::File(bytes) => {
+                    report.copied += 1;
+                    report.bytes += bytes;
+                }
+                Appended::Other => report.created += 1,
+                Appended::Skipped => {
+                    report.skipped += 1;
+                    continue;
+                }
+            }
+
+            for act in self.progress.post.iter() {
+                act.post_run(src, des)?;
+            }
+        }
+
+        let file = builder
+            .into_inner()
+            .and_then(Sink::finish)
+            .map_err(|e| CopyError::io(Stage::Copy, des, e))?;
+        report.written = file
+            .metadata()
+            .map_err(|e| CopyError::io(Stage::Copy, des, e))?
+            .len();
+
+        for ending in self.progress.endings.iter() {
+            ending.done()?;
+        }
+
+        Ok(report)
+    }
+
+    fn append<W: Write>(
+        &self,
+        builder: &mut tar::Builder<W>,
+        src: &str,
+        name: &str,
+    ) -> io::Result<Appended> {
+        // symlinks are followed unless they are preserved
+        let metadata = if self.preserves("links") {
+            fs::symlink_metadata(src)?
+        } else {
+            fs::metadata(src)?
+        };
+        let file_type = metadata.file_type();
+
+        let mut header = Header::new_gnu();
+        header.set_metadata_in_mode(&metadata, HeaderMode::Complete);
+
+        if file_type.is_file() {
+            let len = metadata.len();
+            self.progress.in_copy.set_length(len);
+            // keeps the size in the header even if the file changes meanwhile
+            let data = File::open(src)?.take(len).chain(io::repeat(0)).take(len);
+            let reader = ProgressReader {
+                inner: data,
+                read: 0,
+                callback: &*self.progress.in_copy,
+            };
+            builder.append_data(&mut header, name, reader)?;
+            return Ok(Appended::File(len));
+        }
+
+        header.set_size(0);
+        if file_type.is_dir() {
+            builder.append_data(&mut header, name, io::empty())?;
+        } else if file_type.is_symlink() {
+            builder.append_link(&mut header, name, fs::read_link(src)?)?;
+        } else if file_type.is_fifo() || file_type.is_char_device() || file_type.is_block_device() {
+            if self.skip_special {
+                warn!("Skipping special file: {}", src);
+                return Ok(Appended::Skipped);
+            }
+            let rdev = metadata.rdev();
+            header.set_device_major(nix::sys::stat::major(rdev) as u32)?;
+            header.set_device_minor(nix::sys::stat::minor(rdev) as u32)?;
+            builder.append_data(&mut header, name, io::empty())?;
+        } else {
+            warn!("Skipping socket, tar cannot hold it: {}", src);
+            return Ok(Appended::Skipped);
+        }
+
+        Ok(Appended::Other)
+    }
+
+    /// Extracts the archive `src` into the directory `des`.
+    pub fn unpack(&self, src: &str, des: &str) -> Result<CopyReport> {
+        let file = File::open(src).map_err(|e| CopyError::io(Stage::Setup, src, e))?;
+        let mut reader = BufReader::new(file);
+        let codec =
+            compresscopier::detect(&mut reader).map_err(|e| CopyError::io(Stage::Setup, src, e))?;
+
+        // only a plain archive tells its entries without reading it all
+        let total = match codec {
+            None => File::open(src)
+                .map(tar::Archive::new)
+                .and_then(|mut archive| Ok(archive.entries_with_seek()?.count()))
+                .map_err(|e| CopyError::io(Stage::Setup, src, e))?,
+            Some(_) => 0,
+        };
+
+        let input: Box<dyn Read> = match codec {
+            Some(codec) => compresscopier::decoder(codec, reader)
+                .map_err(|e| CopyError::io(Stage::Setup, src, e))?,
+            None => Box::new(reader),
+        };
+        let span = Rc::new(Span {
+            start: Cell::new(0),
+            len: Cell::new(0),
+        });
+        let mut archive = tar::Archive::new(TrackingReader {
+            inner: input,
+            pos: 0,
+            span: span.clone(),
+            callback: self.progress.in_copy.clone(),
+        });
+        archive.set_preserve_permissions(self.preserves("mode"));
+        archive.set_preserve_mtime(self.preserves("timestamps"));
+        archive.set_overwrite(true);
+
+        fs::create_dir_all(des).map_err(|e| CopyError::io(Stage::Setup, des, e))?;
+        self.progress.preparation.get_ready(total as u64)?;
+
+        let mut report = CopyReport::default();
+        let mut excluded = Vec::new();
+        // directory times change with every entry extracted into them
+        let mut dir_times = Vec::new();
+
+        let entries = archive
+            .entries()
+            .map_err(|e| CopyError::io(Stage::Copy, src, e))?;
+        for entry in entries {
+            let mut entry = entry.map_err(|e| CopyError::io(Stage::Copy, src, e))?;
+            let name = entry
+                .path()
+                .map_err(|e| CopyError::io(Stage::Copy, src, e))?
+                .to_string_lossy()
+                .trim_end_matches('/')
+                .to_string();
+            let target = Path::new(des).join(&name);
+            let target_str = target.to_string_lossy().into_owned();
+            trace!("Unpack {} to {}", name, target_str);
+
+            if exclude::is_under(&excluded, &name) {
+                report.skipped += 1;
+                continue;
+            }
+            if exclude::is_excluded(&self.excludes, &name) {
+                excluded.push(name);
+                report.skipped += 1;
+                continue;
+            }
+
+            let header = entry.header();
+            let entry_type = header.entry_type();
+            let special = matches!(
+                entry_type,
+                EntryType::Fifo | EntryType::Char | EntryType::Block
+            );
+            if special && self.skip_special {
+                warn!("Skipping special file: {}", name);
+                report.skipped += 1;
+                continue;
+            }
+            let owner = (header.uid(), header.gid());
+            let mtime = header.mtime();
+
+            let size = entry.size();
+            if entry_type.is_file() {
+                self.progress.in_copy.set_length(size);
+            }
+            span.start.set(entry.raw_file_position());
+            span.len.set(if entry_type.is_file() { size } else { 0 });
+
+            let unpacked = if special {
+                self.make_special(des, &target, entry.header())
+            } else {
+                entry.unpack_in(des)
+            }
+            .map_err(|e| CopyError::io(Stage::Copy, target_str.as_str(), e))?;
+            span.len.set(0);
+            if !unpacked {
+                warn!("Skipping {}, it points outside of {}", name, des);
+                report.skipped += 1;
+                continue;
+            }
+
+            if let (Ok(uid), Ok(gid)) = owner {
+                if self.preserves("ownership") {
+                    chown(&target, uid, gid);
+                }
+            }
+            if entry_type.is_dir() && self.preserves("timestamps") {
+                if let Ok(mtime) = mtime {
+                    dir_times.push((target.clone(), mtime));
+                }
+            }
+
+            if entry_type.is_file() {
+                report.copied += 1;
+                report.bytes += size;
+            } else {
+                report.created += 1;
+            }
+
+            for act in self.progress.post.iter() {
+                act.post_run(src, &target_str)?;
+            }
+        }
+        report.written = report.bytes;
+
+        for (dir, mtime) in dir_times.iter().rev() {
+            let mtime = filetime::FileTime::from_unix_time(*mtime as i64, 0);
+            if let Err(e) = filetime::set_file_mtime(dir, mtime) {
+                debug!("Failed to set timestamps for: {}", dir.display());
+                debug!("Error: {}", e);
+            }
+        }
+
+        for ending in self.progress.endings.iter() {
+            ending.done()?;
+        }
+
+        Ok(report)
+    }
+
+    // tar extracts special files as regular ones, they are made like the copies do
+    fn make_special(&self, des: &str, target: &Path, header: &Header) -> io::Result<bool> {
+        let inside = Path::new(des);
+        let name = target.strip_prefix(inside).unwrap_or(target);
+        if !name
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Ok(false);
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+            // a symlinked directory may lead out of the destination
+            if !parent.canonicalize()?.starts_with(inside.canonicalize()?) {
+                return Ok(false);
+            }
+        }
+
+        let mode = header.mode()?
+            & if self.preserves("mode") {
+                0o7777
+            } else {
+                0o777
+            };
+        let perm = Mode::from_bits_truncate(mode);
+        let ret = match header.entry_type() {
+            EntryType::Fifo => nix::unistd::mkfifo(target, perm),
+            entry_type => {
+                let kind = if entry_type == EntryType::Char {
+                    SFlag::S_IFCHR
+                } else {
+                    SFlag::S_IFBLK
+                };
+                let major = header.device_major()?.unwrap_or(0);
+                let minor = header.device_minor()?.unwrap_or(0);
+                let dev = nix::sys::stat::makedev(major as u64, minor as u64);
+                nix::sys::stat::mknod(target, kind, perm, dev)
+            }
+        };
+
+        match ret {
+            Ok(_) | Err(nix::errno::Errno::EEXIST) => Ok(true),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// Failing to chown is not fatal, like for the copies preserving the ownership
+fn chown(path: &Path, uid: u64, gid: u64) {
+    let uid = nix::unistd::Uid::from_raw(uid as u32);
+    let gid = nix::unistd::Gid::from_raw(gid as u32);
+    if let Err(e) = nix::unistd::fchownat(
+        None,
+        path,
+        Some(uid),
+        Some(gid),
+        nix::unistd::FchownatFlags::NoFollowSymlink,
+    ) {
+        debug!("Failed to set ownership for: {}", path.display());
+        debug!("Error: {}", e);
+    }
+}
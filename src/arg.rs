@@ -1,198 +1,221 @@
-use super::actions;
-use anyhow::Context;
-use clap::Parser;
-use scanner::DirScan;
-use std::rc::Rc;
+use clap::{ArgGroup, Parser};
+use progressbar_cp::{CopyJob, CopyOptions};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about)]
+#[command(group(ArgGroup::new("transform").args(["compress", "decompress"])))]
 pub struct Args {
-    /// the copy sources
-    #[arg(required(true))]
+    /// the copy sources, - for stdin, [USER@]HOST:PATH or s3://BUCKET/KEY for a single remote one
+    #[arg(required_unless_present = "print_config")]
     srcs: Vec<String>,
-    /// the copy destination
-    #[arg(last(true), required(true))]
-    des: String,
+    /// the copy destination, - for stdout, [USER@]HOST:PATH to copy over SSH, s3://BUCKET/KEY to upload
+    #[arg(last(true), required_unless_present_any = ["to", "print_config"])]
+    des: Option<String>,
     /// recursive copy
     #[arg(short, long)]
     recursive: bool,
     /// copy only when the source file is newer than the destination file or when the destination file is missing
     #[arg(short, long)]
     update: bool,
+    /// skip existing destination files by the given criterion: mtime (same as -u), size, size+mtime, checksum
+    #[arg(long, value_name = "CRITERION", value_parser = ["mtime", "size", "size+mtime", "checksum"])]
+    skip_if: Option<String>,
+    /// with --skip-if=checksum, cache the checksums in extended attributes of the destination files
+    #[arg(long)]
+    checksum_cache: bool,
     /// preserve the specified attributes (default: mode,ownership,timestamps), if possible additional attributes: context, links, xattr, all
     #[arg(short, long, value_name = "ATTR_LIST")]
     preserve: Option<String>,
     /// make the progress bar invisible
     #[arg(short, long)]
     mute: bool,
+    /// explain what is being done
+    #[arg(short, long)]
+    verbose: bool,
     /// same as -r --preserve=all
     #[arg(short, long)]
     archive: bool,
+    /// skip FIFOs, sockets and device nodes instead of recreating them in recursive mode
+    #[arg(long)]
+    skip_special: bool,
+    /// make a backup of each existing destination file, CONTROL: none, numbered, existing (default), simple
+    #[arg(
+        long,
+        value_name = "CONTROL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "existing"
+    )]
+    backup: Option<String>,
+    /// override the usual backup suffix (default: ~), implies --backup
+    #[arg(short = 'S', long)]
+    suffix: Option<String>,
+    /// do not overwrite an existing file (overrides a previous -i option)
+    #[arg(short, long, overrides_with = "interactive")]
+    no_clobber: bool,
+    /// prompt before overwrite (overrides a previous -n option)
+    #[arg(short, long, overrides_with = "no_clobber")]
+    interactive: bool,
+    /// if an existing destination file cannot be opened, remove it and try again
+    #[arg(short, long)]
+    force: bool,
+    /// move the sources instead of copying them: rename when possible, otherwise copy with --archive, verify and remove the sources
+    #[arg(long = "move")]
+    move_sources: bool,
+    /// exclude the entries whose name matches PATTERN, can be given multiple times
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+    /// delete the destination entries missing from the sources, implies -r
+    #[arg(long)]
+    mirror: bool,
+    /// with --mirror, also delete the excluded entries from the destination
+    #[arg(long, requires = "mirror")]
+    delete_excluded: bool,
+    /// print the planned operations without changing anything, FORMAT: table (default), json
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "table",
+        value_parser = ["table", "json"]
+    )]
+    dry_run: Option<String>,
+    /// copy through io_uring with several reads and writes in flight, the next file read while the current one is written, falls back when the kernel lacks it
+    #[arg(long)]
+    io_uring: bool,
+    /// bypass the page cache with O_DIRECT, or drop the copied pages where it is unsupported
+    #[arg(long, conflicts_with = "io_uring")]
+    direct: bool,
+    /// the copy buffer size, e.g. 64K or 4M, picked from the files and the throughput when not given
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    buffer_size: Option<usize>,
+    /// the expected input size for the bar when it is unknown, e.g. when reading stdin
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    size: Option<usize>,
+    /// compress the copies, CODEC: zstd, gzip or xz, with an optional :LEVEL
+    #[arg(long, value_name = "CODEC[:LEVEL]", conflicts_with_all = ["decompress", "direct", "io_uring", "move_sources"])]
+    compress: Option<String>,
+    /// decompress zstd, gzip or xz sources, the codec is told from the data
+    #[arg(long, conflicts_with_all = ["direct", "io_uring", "move_sources"])]
+    decompress: bool,
+    /// with --compress or --decompress, add or strip the codec extension of the destination names
+    #[arg(long, requires = "transform")]
+    extension: bool,
+    /// copy the files into parts of SIZE, e.g. 4G, named NAME.000, NAME.001...
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, conflicts_with_all = ["join", "transform", "move_sources", "mirror", "verbose", "no_clobber", "interactive", "update", "skip_if", "backup", "suffix", "preserve", "archive"])]
+    split: Option<usize>,
+    /// join the parts NAME.000, NAME.001... given by their first one into NAME
+    #[arg(long, conflicts_with_all = ["transform", "move_sources", "mirror"])]
+    join: bool,
+    /// another destination, can be given multiple times, each source is read once for all of them
+    #[arg(long, value_name = "DES")]
+    to: Vec<String>,
+    /// the port of the hosts given as [USER@]HOST:PATH, 22 by default
+    #[arg(long, value_name = "PORT")]
+    ssh_port: Option<u16>,
+    /// log in to the hosts with this private key instead of ssh-agent and ~/.ssh/id_*
+    #[arg(long, value_name = "FILE")]
+    ssh_key: Option<String>,
+    /// the part size of the multipart uploads to s3://BUCKET/KEY, at least 5M (default: 8M)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    s3_part_size: Option<usize>,
+    /// the parts uploaded to S3 at once (default: 4)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    s3_concurrency: Option<u16>,
+    /// read the defaults from FILE instead of ~/.config/pbcp/config.toml, --no-OPTION turns one off
+    #[arg(long, value_name = "FILE")]
+    config: Option<String>,
+    /// also take the defaults of the [profiles.NAME] table of the configuration file
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+    /// print the settings in effect, with where they come from, and exit
+    #[arg(long)]
+    print_config: bool,
 }
 
-impl Args {
-    pub fn zip_src2des_pairs(&self) -> anyhow::Result<(Vec<String>, Vec<String>)> {
-        let len = self.srcs.len() + 1;
-        let src_paths = &self.srcs[..];
-        let des = &self.des;
-
-        // Check if recursive mode is enabled (either directly or via archive)
-        let is_recursive = self.recursive || self.archive;
-        let mut is_des_exists: bool = true;
-
-        let is_des_dir = match std::fs::metadata(des) {
-            Ok(metadata) => metadata.is_dir(),
-            Err(e) => {
-                if e.kind() == std::io::ErrorKind::NotFound {
-                    is_des_exists = false;
-                    false
-                } else {
-                    return Err(e.into());
-                }
-            }
-        };
-
-        match len {
-            2 => {
-                let is_src_dir = std::fs::metadata(&src_paths[0])
-                    .with_context(|| format!("Failed to get metadata of {}", &src_paths[0]))?
-                    .is_dir();
-                let is_src_link = std::fs::symlink_metadata(&src_paths[0])
-                    .with_context(|| {
-                        format!("Failed to get symlink metadata of {}", &src_paths[0])
-                    })?
-                    .file_type()
-                    .is_symlink();
-
-                match (is_src_dir, is_des_dir, is_src_link) {
-                    (false, true, _) => Ok((
-                        vec![src_paths[0].clone()],
-                        vec![des.clone() + "/" + src_paths[0].rsplit('/').next().unwrap()],
-                    )),
-                    (false, false, _) => Ok((vec![src_paths[0].clone()], vec![des.clone()])),
-                    (true, true, _) => {
-                        if is_recursive {
-                            let scanner = scanner::scanners::basescanner::BaseScanner::new(des);
-                            let (src_paths, des_paths) = scanner.scan(src_paths, false)?;
-
-                            Ok((src_paths, des_paths))
-                        } else {
-                            Err(anyhow::anyhow!(
-                                "{} is a directory, should specify -r",
-                                src_paths[0]
-                            ))
-                        }
-                    }
-                    (true, false, false) => {
-                        if is_recursive || !is_des_exists {
-                            let scanner = scanner::scanners::basescanner::BaseScanner::new(des);
-                            let (src_paths, des_paths) = scanner.scan(src_paths, true)?;
-
-                            Ok((src_paths, des_paths))
-                        } else {
-                            Err(anyhow::anyhow!(
-                                "\'{}\' is a directory, should specify a directory as the last argument",
-                                src_paths[0]
-                            ))
-                        }
-                    }
-                    (true, false, true) => Ok((vec![src_paths[0].clone()], vec![des.clone()])),
-                }
-            }
-            _ => {
-                if is_des_dir {
-                    if is_recursive {
-                        let scanner = scanner::scanners::basescanner::BaseScanner::new(des);
-                        let (src_paths, des_paths) = scanner.scan(src_paths, false)?;
-
-                        Ok((src_paths, des_paths))
-                    } else {
-                        let mut des_paths = Vec::new();
-                        for src in src_paths {
-                            let is_src_dir = std::fs::metadata(src)
-                                .with_context(|| format!("Failed to get metadata of {}", src))?
-                                .is_dir();
-                            if is_src_dir {
-                                return Err(anyhow::anyhow!(
-                                    "\'{}\' is a directory, should specify -r",
-                                    src
-                                ));
-                            } else {
-                                des_paths.push(des.clone() + "/" + src.rsplit('/').next().unwrap());
-                            }
-                        }
-                        Ok((src_paths.to_vec(), des_paths))
-                    }
-                } else {
-                    if is_recursive || !is_des_exists {
-                        let scanner = scanner::scanners::basescanner::BaseScanner::new(des);
-                        let (src_paths, des_paths) = scanner.scan(src_paths, false)?;
+// Parses a size with an optional K, M or G (binary) suffix
+fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        _ => return Err(format!("invalid size unit: {}", unit)),
+    };
+    let size = digits
+        .parse::<usize>()
+        .map_err(|e| format!("invalid size {}: {}", s, e))?
+        .checked_mul(1 << shift)
+        .filter(|size| *size > 0)
+        .ok_or_else(|| format!("invalid size: {}", s))?;
+    Ok(size)
+}
 
-                        Ok((src_paths, des_paths))
-                    } else {
-                        Err(anyhow::anyhow!(
-                            "\'{}\' is not a directory, should specify a directory as the last argument when having multiple srcs",
-                            des
-                        ))
-                    }
-                }
-            }
-        }
+impl Args {
+    pub fn print_config(&self) -> bool {
+        self.print_config
     }
 
-    pub fn build_in_progress_actions(
-        &mut self,
-    ) -> anyhow::Result<(
-        Rc<dyn actions::Preparation>,
-        Vec<Rc<dyn actions::PreAction>>,
-        Rc<dyn copier::InCopyAction>,
-        Vec<Rc<dyn actions::PostAction>>,
-        Rc<dyn actions::Ending>,
-    )> {
-        let mut precopy_actions = Vec::<Rc<dyn actions::PreAction>>::new();
-        let mut postcopy_actions = Vec::<Rc<dyn actions::PostAction>>::new();
-        let preparation: Rc<dyn actions::Preparation>;
-        let in_copy_action: Rc<dyn copier::InCopyAction>;
-        let ending: Rc<dyn actions::Ending>;
-
-        if self.archive {
-            self.recursive = true;
-            self.preserve = Some("all".to_string());
-        }
-
-        if self.recursive {
-            precopy_actions.push(Rc::new(actions::recursive::RecursiveAction));
-        }
-
-        if self.update {
-            precopy_actions.push(Rc::new(actions::update::UpdateAction));
-        }
+    pub fn dry_run_format(&self) -> Option<&str> {
+        self.dry_run.as_deref()
+    }
 
-        if let Some(preserve) = self.preserve.clone() {
-            let pact_rc = Rc::new(actions::preserve::PreserveAction::new(preserve));
-            precopy_actions.push(pact_rc.clone());
-            postcopy_actions.push(pact_rc);
+    pub fn transform(&self) -> Option<&'static str> {
+        if self.decompress {
+            Some("decompressed")
+        } else if self.compress.is_some() {
+            Some("compressed")
+        } else {
+            None
         }
+    }
 
-        if self.mute {
-            let no_bar = Rc::new(actions::showbar::NoBar);
-            preparation = no_bar.clone();
-            in_copy_action = no_bar.clone();
-            ending = no_bar.clone();
-        } else {
-            let show_bar = Rc::new(actions::showbar::ShowBar::new()?);
-            preparation = show_bar.clone();
-            in_copy_action = show_bar.clone();
-            ending = show_bar.clone();
-            postcopy_actions.push(show_bar);
+    pub fn into_job(self) -> CopyJob {
+        // the first --to stands in for a missing destination
+        let mut to = self.to;
+        let des = self.des.unwrap_or_else(|| to.remove(0));
+        let options = CopyOptions {
+            recursive: self.recursive,
+            update: self.update,
+            skip_if: self.skip_if,
+            checksum_cache: self.checksum_cache,
+            preserve: self.preserve,
+            mute: self.mute,
+            verbose: self.verbose,
+            archive: self.archive,
+            skip_special: self.skip_special,
+            backup: self.backup,
+            suffix: self.suffix,
+            no_clobber: self.no_clobber,
+            interactive: self.interactive,
+            force: self.force,
+            move_sources: self.move_sources,
+            exclude: self.exclude,
+            mirror: self.mirror,
+            delete_excluded: self.delete_excluded,
+            dry_run: self.dry_run.is_some(),
+            io_uring: self.io_uring,
+            direct: self.direct,
+            buffer_size: self.buffer_size,
+            size: self.size.map(|size| size as u64),
+            compress: self.compress,
+            decompress: self.decompress,
+            extension: self.extension,
+            split: self.split.map(|size| size as u64),
+            join: self.join,
+            to,
+            ssh_port: self.ssh_port,
+            ssh_key: self.ssh_key,
+            s3_part_size: self.s3_part_size.map(|size| size as u64),
+            s3_concurrency: self.s3_concurrency.map(usize::from),
         };
 
-        Ok((
-            preparation,
-            precopy_actions,
-            in_copy_action,
-            postcopy_actions,
-            ending,
-        ))
+        CopyJob::new(self.srcs, des).options(options)
     }
 }
@@ -0,0 +1,351 @@
+use crate::arg::Args;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command, CommandFactory, Parser};
+use progressbar_cp::error::Result;
+use progressbar_cp::{CopyError, Stage};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::path::PathBuf;
+use toml::{Table, Value};
+
+// The options choosing the defaults, they cannot be defaults themselves
+const META: [&str; 5] = ["config", "profile", "print-config", "help", "version"];
+
+#[derive(Debug, Clone)]
+enum Setting {
+    On,
+    Off,
+    Values(Vec<String>),
+}
+
+#[derive(Debug, Clone)]
+enum Source {
+    File,
+    Profile(String),
+    Env(String),
+    CommandLine,
+}
+
+impl Source {
+    // The later layers override the earlier ones
+    fn rank(&self) -> u8 {
+        match self {
+            Source::File => 0,
+            Source::Profile(_) => 1,
+            Source::Env(_) => 2,
+            Source::CommandLine => 3,
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::File => write!(f, "config file"),
+            Source::Profile(name) => write!(f, "profile {}", name),
+            Source::Env(var) => write!(f, "{}", var),
+            Source::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// The settings in effect, with where each one comes from.
+pub struct Config {
+    file: Option<PathBuf>,
+    profile: Option<String>,
+    settings: Vec<(String, Value, Source)>,
+}
+
+impl Config {
+    /// Prints the settings as a configuration file.
+    pub fn print(&self) {
+        match &self.file {
+            Some(file) => println!("# {}", file.display()),
+            None => println!("# no configuration file"),
+        }
+        if let Some(profile) = &self.profile {
+            println!("# profile {}", profile);
+        }
+        for (long, value, source) in self.settings.iter() {
+            println!("{} = {} # {}", long, value, source);
+        }
+    }
+}
+
+fn invalid(msg: String) -> CopyError {
+    CopyError::InvalidArgument(msg)
+}
+
+// ~/.config/pbcp/config.toml, or under $XDG_CONFIG_HOME when it is set
+fn default_path() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(dir.join("pbcp/config.toml"))
+}
+
+fn read_file(path: Option<String>) -> Result<Option<(PathBuf, Table)>> {
+    let (path, explicit) = match path {
+        Some(path) => (PathBuf::from(path), true),
+        None => match default_path() {
+            Some(path) => (path, false),
+            None => return Ok(None),
+        },
+    };
+
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => return Ok(None),
+        Err(e) => return Err(CopyError::io(Stage::Setup, path.to_string_lossy(), e)),
+    };
+    let table = content
+        .parse::<Table>()
+        .map_err(|e| invalid(format!("{}: {}", path.display(), e.message())))?;
+    Ok(Some((path, table)))
+}
+
+fn takes_many(arg: &Arg) -> bool {
+    matches!(arg.get_action(), ArgAction::Append)
+}
+
+// Whether the option may be given alone, like --backup
+fn takes_flag(arg: &Arg) -> bool {
+    !arg.get_action().takes_values()
+        || arg
+            .get_num_args()
+            .is_some_and(|range| range.min_values() == 0)
+}
+
+fn from_toml(arg: &Arg, long: &str, value: &Value) -> Result<Setting> {
+    let scalar = |value: &Value| match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Integer(i) => Some(i.to_string()),
+        _ => None,
+    };
+
+    let setting = match value {
+        Value::Boolean(true) if takes_flag(arg) => Some(Setting::On),
+        Value::Boolean(false) if takes_flag(arg) => Some(Setting::Off),
+        Value::Array(items) if takes_many(arg) => items
+            .iter()
+            .map(scalar)
+            .collect::<Option<_>>()
+            .map(Setting::Values),
+        value if arg.get_action().takes_values() => scalar(value).map(|s| Setting::Values(vec![s])),
+        _ => None,
+    };
+    setting.ok_or_else(|| invalid(format!("invalid value for {}: {}", long, value)))
+}
+
+fn from_env(arg: &Arg, var: &str, value: String) -> Result<Setting> {
+    let flag = match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(Setting::On),
+        "" | "0" | "false" | "no" | "off" => Some(Setting::Off),
+        _ => None,
+    };
+
+    if !arg.get_action().takes_values() {
+        return flag.ok_or_else(|| invalid(format!("{} is not a boolean: {}", var, value)));
+    }
+    match flag {
+        Some(flag) if takes_flag(arg) => Ok(flag),
+        _ if value.is_empty() => Ok(Setting::Off),
+        _ => Ok(Setting::Values(vec![value])),
+    }
+}
+
+fn from_matches(arg: &Arg, matches: &ArgMatches) -> Setting {
+    if !arg.get_action().takes_values() {
+        return Setting::On;
+    }
+    let values = matches
+        .get_raw(arg.get_id().as_str())
+        .into_iter()
+        .flatten()
+        .map(|value| value.to_string_lossy().into_owned())
+        .collect();
+    Setting::Values(values)
+}
+
+fn to_toml(arg: &Arg, setting: &Setting) -> Value {
+    match setting {
+        Setting::On => Value::Boolean(true),
+        Setting::Off => Value::Boolean(false),
+        Setting::Values(values) if takes_many(arg) => {
+            Value::Array(values.iter().cloned().map(Value::String).collect())
+        }
+        Setting::Values(values) => Value::String(values.last().cloned().unwrap_or_default()),
+    }
+}
+
+// The settings of a table, keyed by their long option
+fn from_table(
+    options: &[&Arg],
+    table: Table,
+    source: Source,
+    settings: &mut HashMap<String, (Setting, Source)>,
+) -> Result<()> {
+    for (key, value) in table.iter() {
+        let long = key.replace('_', "-");
+        let arg = options
+            .iter()
+            .find(|arg| arg.get_long() == Some(long.as_str()))
+            .ok_or_else(|| invalid(format!("unknown option in the configuration: {}", key)))?;
+        let setting = from_toml(arg, &long, value)?;
+        settings.insert(long, (setting, source.clone()));
+    }
+    Ok(())
+}
+
+// Takes the --no-<option> turning a default off out of the command line
+fn negations(options: &[&Arg], argv: Vec<OsString>) -> (Vec<OsString>, Vec<String>) {
+    let mut rest = Vec::with_capacity(argv.len());
+    let mut negated = Vec::new();
+    let mut tokens = argv.into_iter();
+    while let Some(token) = tokens.next() {
+        // the paths after -- are left alone
+        if token == "--" {
+            rest.push(token);
+            rest.extend(tokens);
+            break;
+        }
+        let long = token
+            .to_str()
+            .and_then(|token| token.strip_prefix("--no-"))
+            .filter(|long| options.iter().any(|arg| arg.get_long() == Some(*long)));
+        match long {
+            Some(long) => negated.push(long.to_string()),
+            None => rest.push(token),
+        }
+    }
+    (rest, negated)
+}
+
+// Whether clap refuses both options together, the conflicts go either way
+fn conflicting(command: &Command, a: &Arg, b: &Arg) -> bool {
+    let refuses = |a: &Arg, b: &Arg| {
+        command
+            .get_arg_conflicts_with(a)
+            .iter()
+            .any(|arg| arg.get_id() == b.get_id())
+    };
+    refuses(a, b) || refuses(b, a)
+}
+
+/// Parses the command line on top of the defaults of the configuration file,
+/// of its profile and of the PBCP_* variables, each overriding the previous.
+pub fn parse() -> Result<(Args, Config)> {
+    let command = Args::command();
+    let options: Vec<&Arg> = command
+        .get_arguments()
+        .filter(|arg| arg.get_long().is_some_and(|long| !META.contains(&long)))
+        .collect();
+    let (argv, negated) = negations(&options, std::env::args_os().collect());
+    // only the options given are wanted, the rest is checked below
+    let matches = command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&argv)
+        .unwrap_or_else(|e| e.exit());
+
+    let given = |id: &str, var: &str| {
+        matches
+            .get_one::<String>(id)
+            .cloned()
+            .or_else(|| std::env::var(var).ok().filter(|s| !s.is_empty()))
+    };
+    let file = read_file(given("config", "PBCP_CONFIG"))?;
+    let profile = given("profile", "PBCP_PROFILE");
+
+    let mut settings = HashMap::new();
+    let mut file_path = None;
+    let mut profile_table = None;
+    if let Some((path, mut table)) = file {
+        let profiles = table.remove("profiles");
+        if let (Some(name), Some(profiles)) = (&profile, profiles) {
+            let Value::Table(mut profiles) = profiles else {
+                return Err(invalid(format!(
+                    "{}: profiles is not a table",
+                    path.display()
+                )));
+            };
+            profile_table = match profiles.remove(name) {
+                Some(Value::Table(table)) => Some(table),
+                _ => None,
+            };
+        }
+        from_table(&options, table, Source::File, &mut settings)?;
+        file_path = Some(path);
+    }
+    if let Some(name) = &profile {
+        let table = profile_table.ok_or_else(|| invalid(format!("no profile {}", name)))?;
+        from_table(
+            &options,
+            table,
+            Source::Profile(name.clone()),
+            &mut settings,
+        )?;
+    }
+
+    for arg in options.iter() {
+        let long = arg.get_long().unwrap();
+        let var = format!("PBCP_{}", long.replace('-', "_").to_ascii_uppercase());
+        if let Ok(value) = std::env::var(&var) {
+            let setting = from_env(arg, &var, value)?;
+            settings.insert(long.to_string(), (setting, Source::Env(var)));
+        }
+        if negated.iter().any(|negated| negated == long) {
+            settings.insert(long.to_string(), (Setting::Off, Source::CommandLine));
+        }
+        if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            let setting = from_matches(arg, &matches);
+            settings.insert(long.to_string(), (setting, Source::CommandLine));
+        }
+    }
+
+    // a default gives way to the options it conflicts with from a later layer
+    let active: Vec<(&Arg, u8)> = options
+        .iter()
+        .filter_map(|arg| match settings.get(arg.get_long()?)? {
+            (Setting::Off, _) => None,
+            (_, source) => Some((*arg, source.rank())),
+        })
+        .collect();
+    for (arg, rank) in active.iter() {
+        if active
+            .iter()
+            .any(|(other, other_rank)| other_rank > rank && conflicting(&command, arg, other))
+        {
+            settings.remove(arg.get_long().unwrap());
+        }
+    }
+
+    // the defaults go first, the options given on the command line override them
+    let mut args = vec![argv[0].clone()];
+    let mut config = Config {
+        file: file_path,
+        profile,
+        settings: Vec::new(),
+    };
+    for arg in options.iter() {
+        let long = arg.get_long().unwrap();
+        let Some((setting, source)) = settings.remove(long) else {
+            continue;
+        };
+        match (&setting, &source) {
+            (_, Source::CommandLine) | (Setting::Off, _) => {}
+            (Setting::On, _) => args.push(format!("--{}", long).into()),
+            (Setting::Values(values), _) => {
+                args.extend(values.iter().map(|v| format!("--{}={}", long, v).into()))
+            }
+        }
+        config
+            .settings
+            .push((long.to_string(), to_toml(arg, &setting), source));
+    }
+    args.extend(argv.into_iter().skip(1));
+
+    Ok((Args::parse_from(args), config))
+}
@@ -0,0 +1,113 @@
+use std::fmt;
+use std::io;
+
+/// The stage of the copy pipeline an error comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Setup,
+    Scan,
+    Mirror,
+    PreCopy,
+    Copy,
+    PostCopy,
+    Ending,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Setup => write!(f, "setup"),
+            Stage::Scan => write!(f, "scan"),
+            Stage::Mirror => write!(f, "mirror"),
+            Stage::PreCopy => write!(f, "pre copy"),
+            Stage::Copy => write!(f, "copy"),
+            Stage::PostCopy => write!(f, "post copy"),
+            Stage::Ending => write!(f, "ending"),
+        }
+    }
+}
+
+/// The errors of a copy job, `path` is the file the operation failed on.
+#[derive(Debug, thiserror::Error)]
+pub enum CopyError {
+    #[error("{0}")]
+    InvalidArgument(String),
+    #[error("{stage}: '{path}' does not exist")]
+    NotFound { stage: Stage, path: String },
+    #[error("{stage}: permission denied on '{path}'")]
+    PermissionDenied { stage: Stage, path: String },
+    #[error("{stage}: '{path}' is a directory")]
+    IsADirectory { stage: Stage, path: String },
+    #[error("{stage}: '{path}' is not a directory")]
+    NotADirectory { stage: Stage, path: String },
+    #[error("{stage}: '{path}' already exists")]
+    AlreadyExists { stage: Stage, path: String },
+    #[error("{stage}: no space left for '{path}'")]
+    NoSpace { stage: Stage, path: String },
+    #[error("{stage}: '{des}' does not match '{src}'")]
+    Mismatch {
+        stage: Stage,
+        src: String,
+        des: String,
+    },
+    #[error("{stage}: '{path}': {source}")]
+    Io {
+        stage: Stage,
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("{stage}: {source}")]
+    Other {
+        stage: Stage,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, CopyError>;
+
+impl CopyError {
+    /// Classifies an I/O error on `path` by its kind.
+    pub fn io(stage: Stage, path: impl Into<String>, source: io::Error) -> Self {
+        let path = path.into();
+        match source.kind() {
+            io::ErrorKind::NotFound => CopyError::NotFound { stage, path },
+            io::ErrorKind::PermissionDenied => CopyError::PermissionDenied { stage, path },
+            io::ErrorKind::IsADirectory => CopyError::IsADirectory { stage, path },
+            io::ErrorKind::NotADirectory => CopyError::NotADirectory { stage, path },
+            io::ErrorKind::AlreadyExists => CopyError::AlreadyExists { stage, path },
+            io::ErrorKind::StorageFull => CopyError::NoSpace { stage, path },
+            _ => CopyError::Io {
+                stage,
+                path,
+                source,
+            },
+        }
+    }
+
+    pub fn other(
+        stage: Stage,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Self {
+        CopyError::Other {
+            stage,
+            source: source.into(),
+        }
+    }
+
+    pub fn stage(&self) -> Option<Stage> {
+        match self {
+            CopyError::InvalidArgument(_) => None,
+            CopyError::NotFound { stage, .. }
+            | CopyError::PermissionDenied { stage, .. }
+            | CopyError::IsADirectory { stage, .. }
+            | CopyError::NotADirectory { stage, .. }
+            | CopyError::AlreadyExists { stage, .. }
+            | CopyError::NoSpace { stage, .. }
+            | CopyError::Mismatch { stage, .. }
+            | CopyError::Io { stage, .. }
+            | CopyError::Other { stage, .. } => Some(*stage),
+        }
+    }
+}
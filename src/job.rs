@@ -0,0 +1,1364 @@
+use super::actions::{self, ActRet};
+use super::archive::{archive_codec, Archiver};
+use super::error::{CopyError, Result, Stage};
+use super::plan::Plan;
+use super::remote::{self, RemotePath, Transfer};
+use super::s3::{self, ObjectTransfer, S3Path};
+use super::split;
+use copier::copiers::compresscopier::{self, Codec, Transform};
+use copier::copiers::{directcopier, teecopier, uringcopier};
+use copier::{FileCopy, InCopyAction};
+use log::{debug, trace, warn};
+use scanner::scanners::basescanner::BaseScanner;
+use scanner::DirScan;
+use std::fs::File;
+use std::os::fd::AsFd;
+use std::path::Path;
+use std::rc::Rc;
+use storage::storages::localstorage::LocalStorage;
+use storage::{Handle, Storage};
+
+#[cfg(feature = "basecopier")]
+use copier::copiers::basecopier::Copier;
+#[cfg(feature = "zerocopier")]
+use copier::copiers::zerocopier::Copier;
+
+const BUFFER_SIZE: usize = 4096 * 1024;
+
+/// The path standing for stdin as a source and stdout as the destination.
+pub const STDIO: &str = "-";
+
+#[cfg(feature = "basecopier")]
+fn default_copier(buffer_size: Option<usize>) -> Copier {
+    match buffer_size {
+        Some(size) => Copier::new(size),
+        None => Copier::adaptive(),
+    }
+}
+
+#[cfg(feature = "zerocopier")]
+fn default_copier(buffer_size: Option<usize>) -> Copier {
+    Copier::new(buffer_size.unwrap_or(BUFFER_SIZE))
+}
+// The options of the copies between files, refused by the other modes
+const COPY_ONLY: [&str; 11] = [
+    "--move",
+    "--mirror",
+    "--update",
+    "--skip-if",
+    "--backup",
+    "--no-clobber",
+    "--interactive",
+    "--dry-run",
+    "--split",
+    "--join",
+    "--to",
+];
+
+// chunks in flight per file with --io-uring
+const URING_DEPTH: usize = 8;
+
+// The direction of a copy through a tar archive
+enum ArchiveMode {
+    Pack(Option<Codec>),
+    Unpack,
+}
+
+// A host over SSH or a bucket
+enum Remote {
+    Ssh(RemotePath),
+    S3(S3Path),
+}
+
+// The direction of a copy to or from another host
+enum RemoteMode {
+    Upload(Remote),
+    Download(Remote),
+}
+
+type InProgressActions = (
+    Rc<dyn actions::Preparation>,
+    Vec<Rc<dyn actions::PreAction>>,
+    Rc<dyn InCopyAction>,
+    Vec<Rc<dyn actions::PostAction>>,
+    Vec<Rc<dyn actions::SkipAction>>,
+    Vec<Rc<dyn actions::Ending>>,
+);
+
+/// The options of a copy job, named after the `pbcp` flags.
+#[derive(Debug, Clone, Default)]
+pub struct CopyOptions {
+    pub recursive: bool,
+    pub update: bool,
+    pub skip_if: Option<String>,
+    pub checksum_cache: bool,
+    pub preserve: Option<String>,
+    pub mute: bool,
+    pub verbose: bool,
+    pub archive: bool,
+    pub skip_special: bool,
+    pub backup: Option<String>,
+    pub suffix: Option<String>,
+    pub no_clobber: bool,
+    pub interactive: bool,
+    pub force: bool,
+    pub move_sources: bool,
+    pub exclude: Vec<String>,
+    pub mirror: bool,
+    pub delete_excluded: bool,
+    pub dry_run: bool,
+    pub io_uring: bool,
+    pub direct: bool,
+    /// a fixed buffer size, adapted to the files and the devices when unset
+    pub buffer_size: Option<usize>,
+    /// the length shown by the bar when the input does not tell it, e.g. stdin
+    pub size: Option<u64>,
+    /// compress the copies, `CODEC[:LEVEL]` with zstd, gzip or xz
+    pub compress: Option<String>,
+    pub decompress: bool,
+    /// add or strip the codec extension of the destination names
+    pub extension: bool,
+    /// copy the files into parts of this size, named `NAME.000`, `NAME.001`...
+    pub split: Option<u64>,
+    /// join the parts of the sources named `NAME.000` into `NAME`
+    pub join: bool,
+    /// more destinations, each source is read once and written to all of them
+    pub to: Vec<String>,
+    /// the port of the hosts named by `[user@]host:path`, 22 when unset
+    pub ssh_port: Option<u16>,
+    /// the private key to log in with instead of the agent and `~/.ssh/id_*`
+    pub ssh_key: Option<String>,
+    /// the part size of the multipart uploads to S3, 8 MiB when unset
+    pub s3_part_size: Option<u64>,
+    /// the parts uploaded to S3 at once, 4 when unset
+    pub s3_concurrency: Option<usize>,
+}
+
+/// What a finished job has done.
+#[derive(Default)]
+pub struct CopyReport {
+    /// files copied through the copier
+    pub copied: u64,
+    /// bytes copied through the copier
+    pub bytes: u64,
+    /// entries handled without copying, such as directories, symlinks and special files
+    pub created: u64,
+    /// entries skipped by the pre actions
+    pub skipped: u64,
+    /// entries renamed to the destination by the move mode
+    pub moved: u64,
+    /// extraneous destination entries deleted by the mirror mode
+    pub deleted: u64,
+    /// bytes written to the destinations, they differ from `bytes` when compressing
+    pub written: u64,
+    /// the planned operations of a dry run, nothing else is done then
+    pub plan: Option<Plan>,
+    /// the errors of the destinations given up on, the others have been copied to
+    pub failed: Vec<CopyError>,
+}
+
+/// The bars and callbacks of the copies made outside of the copy loop, such
+/// as into archives or over SSH.
+pub(crate) struct Progress {
+    pub preparation: Rc<dyn actions::Preparation>,
+    pub in_copy: Rc<dyn InCopyAction>,
+    pub post: Vec<Rc<dyn actions::PostAction>>,
+    pub endings: Vec<Rc<dyn actions::Ending>>,
+}
+
+pub struct CopyJob {
+    srcs: Vec<String>,
+    des: String,
+    options: CopyOptions,
+    pre_actions: Vec<Rc<dyn actions::PreAction>>,
+    post_actions: Vec<Rc<dyn actions::PostAction>>,
+    in_copy_actions: Vec<Rc<dyn InCopyAction>>,
+    storage: Rc<dyn Storage>,
+}
+
+// Stands in for the length of inputs without one, such as pipes
+struct SizeHint {
+    inner: Rc<dyn InCopyAction>,
+    size: u64,
+}
+
+impl InCopyAction for SizeHint {
+    fn set_length(&self, length: u64) {
+        self.inner
+            .set_length(if length == 0 { self.size } else { length });
+    }
+
+    fn in_copy_run(&self, copied: u64) {
+        self.inner.in_copy_run(copied);
+    }
+
+    fn in_copy_run_to(&self, index: usize, written: u64) {
+        self.inner.in_copy_run_to(index, written);
+    }
+}
+
+// Forwards the progress to every in copy action
+struct InCopyActions(Vec<Rc<dyn InCopyAction>>);
+
+impl InCopyAction for InCopyActions {
+    fn set_length(&self, length: u64) {
+        self.0.iter().for_each(|act| act.set_length(length));
+    }
+
+    fn in_copy_run(&self, copied: u64) {
+        self.0.iter().for_each(|act| act.in_copy_run(copied));
+    }
+
+    fn in_copy_run_to(&self, index: usize, written: u64) {
+        self.0
+            .iter()
+            .for_each(|act| act.in_copy_run_to(index, written));
+    }
+}
+
+impl CopyJob {
+    pub fn new(srcs: Vec<String>, des: String) -> Self {
+        CopyJob {
+            srcs,
+            des,
+            options: CopyOptions::default(),
+            pre_actions: Vec::new(),
+            post_actions: Vec::new(),
+            in_copy_actions: Vec::new(),
+            storage: Rc::new(LocalStorage),
+        }
+    }
+
+    pub fn options(mut self, options: CopyOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Adds a pre action running after the built-in ones.
+    pub fn pre_action(mut self, action: Rc<dyn actions::PreAction>) -> Self {
+        self.pre_actions.push(action);
+        self
+    }
+
+    /// Adds a post action running after the built-in ones.
+    pub fn post_action(mut self, action: Rc<dyn actions::PostAction>) -> Self {
+        self.post_actions.push(action);
+        self
+    }
+
+    /// Adds a progress callback next to the progress bar.
+    pub fn in_copy_action(mut self, action: Rc<dyn InCopyAction>) -> Self {
+        self.in_copy_actions.push(action);
+        self
+    }
+
+    /// Copies within `storage` instead of the local file systems.
+    pub fn storage(mut self, storage: Rc<dyn Storage>) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    pub fn run(mut self) -> Result<CopyReport> {
+        self.check_streams()?;
+        if let Some(mode) = self.remote_mode()? {
+            return self.run_remote(mode);
+        }
+        if let Some(mode) = self.archive_mode() {
+            return self.run_archive(mode);
+        }
+        if !self.options.to.is_empty() {
+            return self.run_to_all();
+        }
+        // the cache is kept in the extended attributes of the local files
+        if self.options.checksum_cache {
+            self.require_local("with --checksum-cache")?;
+        }
+        // the actions look at the destination, not at its parts
+        if self.options.split.is_some() {
+            self.refuse(
+                "with --split",
+                &[
+                    "--verbose",
+                    "--no-clobber",
+                    "--interactive",
+                    "--update",
+                    "--skip-if",
+                    "--backup",
+                    "--preserve",
+                    "--archive",
+                ],
+            )?;
+        }
+        let transform = self.transform()?;
+        let (mut src_paths, mut des_paths) = self.zip_src2des_pairs()?;
+        if self.options.join {
+            (src_paths, des_paths) = split::join_pairs(&*self.storage, src_paths, des_paths);
+        }
+        if self.options.extension {
+            if let Some(transform) = transform {
+                rename_extensions(&*self.storage, &src_paths, &mut des_paths, transform);
+            }
+        }
+
+        debug!("src_paths: {:?}", src_paths);
+        debug!("des_paths: {:?}", des_paths);
+
+        let mirror = self.build_mirror()?;
+        let extraneous = match &mirror {
+            Some(mirror) => mirror.extraneous(&src_paths, &des_paths)?,
+            None => Vec::new(),
+        };
+        debug!("extraneous: {:?}", extraneous);
+
+        let (preparation, precopy_acts, in_copy_action, postcopy_acts, skip_acts, endings) =
+            self.build_in_progress_actions()?;
+        let mut report = CopyReport::default();
+
+        if self.options.dry_run {
+            report.plan = Some(Plan::build(
+                &src_paths,
+                &des_paths,
+                &extraneous,
+                &precopy_acts,
+                &*self.storage,
+            )?);
+            return Ok(report);
+        }
+
+        if self.des != STDIO {
+            self.check_free_space(&src_paths, &des_paths)?;
+        }
+
+        if let Some(mirror) = mirror {
+            mirror.delete(&extraneous)?;
+            report.deleted = extraneous.len() as u64;
+        }
+
+        let mut copier = self.build_copier(transform);
+        preparation.get_ready(src_paths.len() as u64)?;
+
+        for (i, (src, des)) in src_paths.iter().zip(des_paths.iter()).enumerate() {
+            trace!("Copy from {} to {}", src, des);
+
+            match pre_run(&precopy_acts, src, des)? {
+                ActRet::GoOn => {
+                    let storage = &*self.storage;
+                    let bytes = if let Some(size) = self.options.split {
+                        split::split(&mut *copier, storage, src, des, size, &*in_copy_action)?
+                    } else if self.options.join && split::joined_name(src).is_some() {
+                        split::join(&mut *copier, storage, src, des, &*in_copy_action)?
+                    } else {
+                        let src_file = open_src(storage, src)
+                            .map_err(|e| CopyError::io(Stage::Copy, src.as_str(), e))?;
+                        let des_file = create_des(storage, des)
+                            .map_err(|e| CopyError::io(Stage::Copy, des.as_str(), e))?;
+                        if self.options.io_uring {
+                            self.prefetch_next(&mut *copier, src_paths.get(i + 1));
+                        }
+
+                        copier
+                            .copy(src_file, des_file, &*in_copy_action)
+                            .map_err(|e| CopyError::io(Stage::Copy, des.as_str(), e))?
+                    };
+                    report.bytes += bytes;
+                    report.written += copier.output_len().unwrap_or(bytes);
+                    report.copied += 1;
+                }
+                ActRet::SkipRest => {
+                    for act in skip_acts.iter() {
+                        act.skip_run(src, des)?;
+                    }
+                    report.skipped += 1;
+                    continue;
+                }
+                ActRet::SkipCopy => report.created += 1,
+                ActRet::Moved => {
+                    for act in postcopy_acts.iter() {
+                        act.moved_run(src, des)?;
+                    }
+                    report.moved += 1;
+                    continue;
+                }
+            };
+
+            for act in postcopy_acts.iter() {
+                act.post_run(src, des)?;
+            }
+        }
+
+        for ending in endings.iter() {
+            ending.done()?;
+        }
+
+        Ok(report)
+    }
+
+    // Offers the next source to the copier when it is a regular file, so that
+    // io_uring reads it while the current one is written
+    fn prefetch_next(&self, copier: &mut dyn FileCopy, next: Option<&String>) {
+        let Some(next) = next else {
+            return;
+        };
+        if !self.storage.lstat(next).is_ok_and(|stat| stat.is_file()) {
+            return;
+        }
+        match self.storage.open_read(next) {
+            Ok(file) => copier.prefetch(file),
+            Err(e) => debug!("Failed to open {} ahead: {}", next, e),
+        }
+    }
+
+    // Reads each source once and writes it to every destination, a failing
+    // destination is given up on while the others go on
+    fn run_to_all(mut self) -> Result<CopyReport> {
+        self.refuse(
+            "with several destinations",
+            &[
+                "--move",
+                "--mirror",
+                "--dry-run",
+                "--compress",
+                "--decompress",
+                "--split",
+                "--join",
+                "--io-uring",
+                "--direct",
+            ],
+        )?;
+        let options = &self.options;
+
+        let destinations: Vec<String> = std::iter::once(self.des.clone())
+            .chain(options.to.iter().cloned())
+            .collect();
+        let mut src_paths: Option<Vec<String>> = None;
+        let mut targets = Vec::with_capacity(destinations.len());
+        let mut failed: Vec<Option<CopyError>> = Vec::with_capacity(destinations.len());
+        for des in destinations.iter() {
+            self.des = des.clone();
+            let mut slot = None;
+            match self.zip_src2des_pairs() {
+                Ok((srcs, des_paths)) => {
+                    let first = src_paths.get_or_insert_with(|| srcs.clone());
+                    if *first != srcs {
+                        give_up(
+                            &mut slot,
+                            CopyError::other(
+                                Stage::Scan,
+                                format!("the sources changed while scanning them for '{}'", des),
+                            ),
+                        );
+                    } else if let Err(e) = self.check_free_space(&srcs, &des_paths) {
+                        give_up(&mut slot, e);
+                    }
+                    targets.push(des_paths);
+                }
+                Err(e) => {
+                    give_up(&mut slot, e);
+                    targets.push(Vec::new());
+                }
+            }
+            failed.push(slot);
+        }
+        self.des = destinations[0].clone();
+        let src_paths = src_paths.unwrap_or_default();
+        debug!("src_paths: {:?}", src_paths);
+        debug!("des_paths: {:?}", targets);
+
+        let (preparation, precopy_acts, in_copy_action, postcopy_acts, skip_acts, endings) =
+            self.build_in_progress_actions()?;
+        let mut report = CopyReport::default();
+        let mut copier = teecopier::Copier::new(self.options.buffer_size.unwrap_or(BUFFER_SIZE));
+        preparation.get_ready((src_paths.len() * destinations.len()) as u64)?;
+
+        for (i, src) in src_paths.iter().enumerate() {
+            let mut copies = Vec::new();
+            // the destinations to run the post actions on
+            let mut done = Vec::new();
+
+            for (index, des_paths) in targets.iter().enumerate() {
+                if failed[index].is_some() {
+                    continue;
+                }
+                let des = &des_paths[i];
+                trace!("Copy from {} to {}", src, des);
+
+                match pre_run(&precopy_acts, src, des) {
+                    Ok(ActRet::GoOn) => copies.push(index),
+                    Ok(ActRet::SkipRest) => {
+                        for act in skip_acts.iter() {
+                            act.skip_run(src, des)?;
+                        }
+                        report.skipped += 1;
+                    }
+                    // --move is refused with several destinations
+                    Ok(ActRet::SkipCopy) | Ok(ActRet::Moved) => {
+                        report.created += 1;
+                        done.push(index);
+                    }
+                    Err(e) => give_up(&mut failed[index], e),
+                }
+            }
+
+            if !copies.is_empty() {
+                let src_file = open_src(&*self.storage, src)
+                    .map_err(|e| CopyError::io(Stage::Copy, src.as_str(), e))?;
+                let mut des_files = Vec::with_capacity(copies.len());
+                for index in copies {
+                    let des = &targets[index][i];
+                    match create_des(&*self.storage, des) {
+                        Ok(file) => des_files.push((index, file)),
+                        Err(e) => give_up(
+                            &mut failed[index],
+                            CopyError::io(Stage::Copy, des.as_str(), e),
+                        ),
+                    }
+                }
+
+                let indexes: Vec<usize> = des_files.iter().map(|(index, _)| *index).collect();
+                let results = copier
+                    .copy_to_all(src_file, des_files, &*in_copy_action)
+                    .map_err(|e| CopyError::io(Stage::Copy, src.as_str(), e))?;
+
+                let mut read = None;
+                for (index, result) in indexes.into_iter().zip(results) {
+                    match result {
+                        Ok(bytes) => {
+                            read = Some(bytes);
+                            report.written += bytes;
+                            done.push(index);
+                        }
+                        Err(e) => give_up(
+                            &mut failed[index],
+                            CopyError::io(Stage::Copy, targets[index][i].as_str(), e),
+                        ),
+                    }
+                }
+                if let Some(bytes) = read {
+                    report.bytes += bytes;
+                    report.copied += 1;
+                }
+            }
+
+            for index in done {
+                let des = &targets[index][i];
+                for act in postcopy_acts.iter() {
+                    if let Err(e) = act.post_run(src, des) {
+                        give_up(&mut failed[index], e);
+                        break;
+                    }
+                }
+            }
+        }
+
+        for ending in endings.iter() {
+            ending.done()?;
+        }
+
+        report.failed = failed.into_iter().flatten().collect();
+        Ok(report)
+    }
+
+    // Only plain copies make sense from stdin or to stdout
+    fn check_streams(&self) -> Result<()> {
+        let from_stdin = self.srcs.iter().any(|src| src == STDIO);
+        let to_stdout = self.des == STDIO;
+        if !from_stdin && !to_stdout {
+            return Ok(());
+        }
+
+        let mut refused = [
+            &["--recursive", "--archive", "--preserve", "--io-uring"],
+            &COPY_ONLY[..],
+        ]
+        .concat();
+        // the lines would get mixed into the copied data
+        if to_stdout {
+            refused.push("--verbose");
+        }
+        self.refuse("when copying from stdin or to stdout", &refused)?;
+
+        if from_stdin && self.srcs.len() > 1 {
+            return Err(CopyError::InvalidArgument(format!(
+                "\'{}\' should be the only source",
+                STDIO
+            )));
+        }
+
+        Ok(())
+    }
+
+    // Refuses the first of the given `flags` that is set, they cannot be used `mode`
+    fn refuse(&self, mode: &str, flags: &[&str]) -> Result<()> {
+        let options = &self.options;
+        let set = |flag: &str| match flag {
+            "--recursive" => options.recursive,
+            "--archive" => options.archive,
+            "--preserve" => options.preserve.is_some(),
+            "--move" => options.move_sources,
+            "--mirror" => options.mirror,
+            "--update" => options.update,
+            "--skip-if" => options.skip_if.is_some(),
+            "--backup" => options.backup.is_some() || options.suffix.is_some(),
+            "--no-clobber" => options.no_clobber,
+            "--interactive" => options.interactive,
+            "--dry-run" => options.dry_run,
+            "--compress" => options.compress.is_some(),
+            "--decompress" => options.decompress,
+            "--io-uring" => options.io_uring,
+            "--direct" => options.direct,
+            "--verbose" => options.verbose,
+            "--split" => options.split.is_some(),
+            "--join" => options.join,
+            "--to" => !options.to.is_empty(),
+            "-" => self.srcs.iter().any(|src| src == STDIO),
+            _ => unreachable!("unknown flag {}", flag),
+        };
+
+        match flags.iter().find(|flag| set(flag)) {
+            Some(flag) => Err(CopyError::InvalidArgument(format!(
+                "{} cannot be used {}",
+                flag, mode
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    // The archive and remote copies go to the local files directly
+    fn require_local(&self, mode: &str) -> Result<()> {
+        if self.storage.is_local() {
+            return Ok(());
+        }
+        Err(CopyError::InvalidArgument(format!(
+            "only the local storage can be used {}",
+            mode
+        )))
+    }
+
+    // A remote destination uploads the sources, a single remote source is downloaded
+    fn remote_mode(&self) -> Result<Option<RemoteMode>> {
+        let des = parse_remote(&self.des)?;
+        let mut srcs = Vec::new();
+        for src in self.srcs.iter() {
+            srcs.extend(parse_remote(src)?);
+        }
+
+        match (srcs.pop(), des) {
+            (None, None) => Ok(None),
+            (None, Some(des)) => Ok(Some(RemoteMode::Upload(des))),
+            (Some(src), None) if self.srcs.len() == 1 && self.des != STDIO => {
+                Ok(Some(RemoteMode::Download(src)))
+            }
+            (Some(_), None) => Err(CopyError::InvalidArgument(
+                "a remote source should be the only source and have a local destination"
+                    .to_string(),
+            )),
+            (Some(_), Some(_)) => Err(CopyError::InvalidArgument(
+                "cannot copy from a remote host to another, one side should be local".to_string(),
+            )),
+        }
+    }
+
+    fn run_remote(mut self, mode: RemoteMode) -> Result<CopyReport> {
+        let (remote, upload) = match mode {
+            RemoteMode::Upload(remote) => (remote, true),
+            RemoteMode::Download(remote) => (remote, false),
+        };
+        let over = match remote {
+            Remote::Ssh(_) => "when copying over SSH",
+            Remote::S3(_) => "when copying to or from S3",
+        };
+        let refused = [
+            &COPY_ONLY[..],
+            &[
+                "--compress",
+                "--decompress",
+                "--io-uring",
+                "--direct",
+                "--verbose",
+                "-",
+            ],
+        ]
+        .concat();
+        self.refuse(over, &refused)?;
+        self.require_local(over)?;
+        let options = &self.options;
+        let recursive = options.recursive || options.archive;
+
+        match remote {
+            Remote::Ssh(target) => {
+                let sftp = remote::connect(
+                    &target,
+                    options.ssh_port.unwrap_or(remote::SSH_PORT),
+                    options.ssh_key.as_deref(),
+                )?;
+                let preserve = if options.archive {
+                    Some("all".to_string())
+                } else {
+                    options.preserve.clone()
+                };
+                let transfer = Transfer {
+                    sftp,
+                    preserve: preserve.map(|attrs| {
+                        actions::preserve::PreserveAction::new(attrs, self.storage.clone())
+                    }),
+                    excludes: self.exclude_patterns()?,
+                    recursive,
+                    buffer_size: options.buffer_size.unwrap_or(remote::BUFFER_SIZE),
+                    progress: self.build_progress()?,
+                };
+
+                match upload {
+                    true => transfer.upload(&self.srcs, &target.path),
+                    false => transfer.download(&target.path, &self.des),
+                }
+            }
+            Remote::S3(path) => {
+                let part_size = options.s3_part_size.unwrap_or(s3::PART_SIZE);
+                if part_size < s3::MIN_PART_SIZE {
+                    return Err(CopyError::InvalidArgument(format!(
+                        "the S3 part size should be at least {} bytes",
+                        s3::MIN_PART_SIZE
+                    )));
+                }
+                let transfer = ObjectTransfer {
+                    client: s3::Client::from_env()?,
+                    part_size,
+                    concurrency: options.s3_concurrency.unwrap_or(s3::CONCURRENCY).max(1),
+                    excludes: self.exclude_patterns()?,
+                    recursive,
+                    progress: self.build_progress()?,
+                };
+
+                match upload {
+                    true => transfer.upload(&self.srcs, &path),
+                    false => transfer.download(&path, &self.des),
+                }
+            }
+        }
+    }
+
+    // -r into a tar name packs the sources, -r from a single tar extracts it
+    fn archive_mode(&self) -> Option<ArchiveMode> {
+        let options = &self.options;
+        if !(options.recursive || options.archive) || self.des == STDIO {
+            return None;
+        }
+
+        let src_is_archive = self.srcs.len() == 1
+            && archive_codec(&self.srcs[0]).is_some()
+            && self
+                .storage
+                .stat(&self.srcs[0])
+                .is_ok_and(|stat| stat.is_file());
+        let des_is_dir = self.storage.stat(&self.des).is_ok_and(|stat| stat.is_dir());
+
+        match archive_codec(&self.des) {
+            Some(codec) if !src_is_archive && !des_is_dir => Some(ArchiveMode::Pack(codec)),
+            None if src_is_archive => Some(ArchiveMode::Unpack),
+            _ => None,
+        }
+    }
+
+    fn run_archive(mut self, mode: ArchiveMode) -> Result<CopyReport> {
+        let refused = [
+            &COPY_ONLY[..],
+            &[
+                "--compress",
+                "--decompress",
+                "--io-uring",
+                "--direct",
+                "--verbose",
+            ],
+        ]
+        .concat();
+        let into = "when copying into or out of a tar archive";
+        self.refuse(into, &refused)?;
+        self.require_local(into)?;
+        let options = &self.options;
+
+        let preserve = if options.archive {
+            Some("all".to_string())
+        } else {
+            options.preserve.clone()
+        };
+        let archiver = Archiver {
+            preserve: preserve
+                .map(|attrs| actions::preserve::PreserveAction::new(attrs, self.storage.clone())),
+            excludes: self.exclude_patterns()?,
+            skip_special: options.skip_special,
+            progress: self.build_progress()?,
+        };
+
+        match mode {
+            ArchiveMode::Pack(codec) => archiver.pack(&self.srcs, &self.des, codec),
+            ArchiveMode::Unpack => archiver.unpack(&self.srcs[0], &self.des),
+        }
+    }
+
+    fn zip_src2des_pairs(&self) -> Result<(Vec<String>, Vec<String>)> {
+        if self.srcs[0] == STDIO {
+            if self.storage.stat(&self.des).is_ok_and(|stat| stat.is_dir()) {
+                return Err(CopyError::InvalidArgument(format!(
+                    "\'{}\' is a directory, should name the file to copy stdin to",
+                    self.des
+                )));
+            }
+            return Ok((self.srcs.clone(), vec![self.des.clone()]));
+        }
+
+        if self.des == STDIO {
+            for src in self.srcs.iter() {
+                let stat = self
+                    .storage
+                    .stat(src)
+                    .map_err(|e| CopyError::io(Stage::Setup, src.as_str(), e))?;
+                if stat.is_dir() {
+                    return Err(CopyError::InvalidArgument(format!(
+                        "\'{}\' is a directory, cannot copy it to stdout",
+                        src
+                    )));
+                }
+            }
+            return Ok((self.srcs.clone(), vec![self.des.clone(); self.srcs.len()]));
+        }
+
+        let len = self.srcs.len() + 1;
+        let src_paths = &self.srcs[..];
+        let des = &self.des;
+        let options = &self.options;
+        let scanner = BaseScanner::with_storage(des, &*self.storage);
+
+        // Check if recursive mode is enabled (either directly or via archive, move or mirror)
+        let is_recursive =
+            options.recursive || options.archive || options.move_sources || options.mirror;
+        let mut is_des_exists: bool = true;
+
+        let is_des_dir = match self.storage.stat(des) {
+            Ok(stat) => stat.is_dir(),
+            Err(e) => {
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    is_des_exists = false;
+                    false
+                } else {
+                    return Err(CopyError::io(Stage::Setup, des.as_str(), e));
+                }
+            }
+        };
+
+        match len {
+            2 => {
+                let is_src_dir = self
+                    .storage
+                    .stat(&src_paths[0])
+                    .map_err(|e| CopyError::io(Stage::Setup, src_paths[0].as_str(), e))?
+                    .is_dir();
+                let is_src_link = self
+                    .storage
+                    .lstat(&src_paths[0])
+                    .map_err(|e| CopyError::io(Stage::Setup, src_paths[0].as_str(), e))?
+                    .is_symlink();
+
+                match (is_src_dir, is_des_dir, is_src_link) {
+                    (false, true, _) => Ok((
+                        vec![src_paths[0].clone()],
+                        vec![des.clone() + "/" + src_paths[0].rsplit('/').next().unwrap()],
+                    )),
+                    (false, false, _) => Ok((vec![src_paths[0].clone()], vec![des.clone()])),
+                    (true, true, _) => {
+                        if is_recursive {
+                            let (src_paths, des_paths) =
+                                scanner.scan(src_paths, false).map_err(scan_error)?;
+
+                            Ok((src_paths, des_paths))
+                        } else {
+                            Err(CopyError::InvalidArgument(format!(
+                                "{} is a directory, should specify -r",
+                                src_paths[0]
+                            )))
+                        }
+                    }
+                    (true, false, false) => {
+                        if is_recursive || !is_des_exists {
+                            let (src_paths, des_paths) =
+                                scanner.scan(src_paths, true).map_err(scan_error)?;
+
+                            Ok((src_paths, des_paths))
+                        } else {
+                            Err(CopyError::InvalidArgument(format!(
+                                "\'{}\' is a directory, should specify a directory as the last argument",
+                                src_paths[0]
+                            )))
+                        }
+                    }
+                    (true, false, true) => Ok((vec![src_paths[0].clone()], vec![des.clone()])),
+                }
+            }
+            _ => {
+                if is_des_dir {
+                    if is_recursive {
+                        let (src_paths, des_paths) =
+                            scanner.scan(src_paths, false).map_err(scan_error)?;
+
+                        Ok((src_paths, des_paths))
+                    } else {
+                        let mut des_paths = Vec::new();
+                        for src in src_paths {
+                            let is_src_dir = self
+                                .storage
+                                .stat(src)
+                                .map_err(|e| CopyError::io(Stage::Setup, src.as_str(), e))?
+                                .is_dir();
+                            if is_src_dir {
+                                return Err(CopyError::InvalidArgument(format!(
+                                    "\'{}\' is a directory, should specify -r",
+                                    src
+                                )));
+                            } else {
+                                des_paths.push(des.clone() + "/" + src.rsplit('/').next().unwrap());
+                            }
+                        }
+                        Ok((src_paths.to_vec(), des_paths))
+                    }
+                } else {
+                    if is_recursive || !is_des_exists {
+                        let (src_paths, des_paths) =
+                            scanner.scan(src_paths, false).map_err(scan_error)?;
+
+                        Ok((src_paths, des_paths))
+                    } else {
+                        Err(CopyError::InvalidArgument(format!(
+                            "\'{}\' is not a directory, should specify a directory as the last argument when having multiple srcs",
+                            des
+                        )))
+                    }
+                }
+            }
+        }
+    }
+
+    // Refuses to start when the files won't fit the destination file system
+    fn check_free_space(&self, src_paths: &[String], des_paths: &[String]) -> Result<()> {
+        let options = &self.options;
+        // the size of the compressed or decompressed output is unknown
+        if options.compress.is_some() || options.decompress {
+            return Ok(());
+        }
+        // existing destinations are kept by -n, and may be by -u and --skip-if
+        let keeps_existing = options.no_clobber || options.update || options.skip_if.is_some();
+
+        let storage = &*self.storage;
+        let mut root = Path::new(&self.des);
+        loop {
+            match storage.lstat(&root.to_string_lossy()) {
+                Ok(_) => break,
+                Err(e) if root == Path::new(".") => {
+                    return Err(CopyError::io(Stage::Setup, ".", e));
+                }
+                Err(_) => {}
+            }
+            root = match root.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+        }
+        let root = root.to_string_lossy();
+        let des_dev = storage
+            .stat(&root)
+            .map_err(|e| CopyError::io(Stage::Setup, root.clone(), e))?
+            .dev;
+
+        let mut needed = 0u64;
+        for (src, des) in src_paths.iter().zip(des_paths.iter()) {
+            let Ok(stat) = storage.lstat(src) else {
+                continue;
+            };
+            // a move within the file system is a rename
+            if !stat.is_file() || (self.options.move_sources && stat.dev == des_dev) {
+                continue;
+            }
+            // a backup keeps the old file around, the parts go beside it
+            let existing = match storage.lstat(des) {
+                Ok(_) if keeps_existing => continue,
+                Ok(stat)
+                    if stat.is_file() && options.backup.is_none() && options.split.is_none() =>
+                {
+                    stat.len
+                }
+                _ => 0,
+            };
+            needed += stat.len.saturating_sub(existing);
+        }
+        if needed == 0 {
+            return Ok(());
+        }
+
+        let Some(available) = storage
+            .free_space(&root)
+            .map_err(|e| CopyError::io(Stage::Setup, root.clone(), e))?
+        else {
+            // the storage cannot tell, the copy fails once it runs out then
+            return Ok(());
+        };
+        debug!("{} bytes to copy, {} bytes available", needed, available);
+
+        if needed > available {
+            warn!(
+                "{} bytes to copy but only {} bytes available on {}",
+                needed, available, root
+            );
+            return Err(CopyError::NoSpace {
+                stage: Stage::Setup,
+                path: self.des.clone(),
+            });
+        }
+
+        Ok(())
+    }
+
+    fn transform(&self) -> Result<Option<Transform>> {
+        if self.options.decompress {
+            return Ok(Some(Transform::Decompress));
+        }
+
+        self.options
+            .compress
+            .as_deref()
+            .map(|s| Transform::parse_compress(s).map_err(CopyError::InvalidArgument))
+            .transpose()
+    }
+
+    fn build_copier(&self, transform: Option<Transform>) -> Box<dyn FileCopy> {
+        let mut buffer_size = self.options.buffer_size;
+        // a chunk running past the end of a part is copied again into the next one
+        if let Some(size) = self.options.split {
+            buffer_size = Some(buffer_size.unwrap_or(BUFFER_SIZE).min(size as usize));
+        }
+
+        if let Some(transform) = transform {
+            return Box::new(compresscopier::Copier::new(
+                transform,
+                buffer_size.unwrap_or(BUFFER_SIZE),
+            ));
+        }
+
+        if self.options.direct {
+            return Box::new(directcopier::Copier::new(
+                buffer_size.unwrap_or(BUFFER_SIZE),
+            ));
+        }
+
+        if self.options.io_uring {
+            let chunk_size = buffer_size.unwrap_or(BUFFER_SIZE / URING_DEPTH);
+            match uringcopier::Copier::new(chunk_size, URING_DEPTH) {
+                Ok(copier) => return Box::new(copier),
+                Err(e) => warn!(
+                    "io_uring is not available ({}), using the default copier",
+                    e
+                ),
+            }
+        }
+
+        Box::new(default_copier(buffer_size))
+    }
+
+    fn exclude_patterns(&self) -> Result<Vec<glob::Pattern>> {
+        self.options
+            .exclude
+            .iter()
+            .map(|p| {
+                glob::Pattern::new(p).map_err(|e| {
+                    CopyError::InvalidArgument(format!("invalid exclude pattern {}: {}", p, e))
+                })
+            })
+            .collect()
+    }
+
+    fn build_mirror(&self) -> Result<Option<actions::mirror::Mirror>> {
+        if !self.options.mirror {
+            return Ok(None);
+        }
+
+        Ok(Some(actions::mirror::Mirror::new(
+            self.exclude_patterns()?,
+            self.options.delete_excluded,
+            self.storage.clone(),
+        )))
+    }
+
+    fn build_in_progress_actions(&mut self) -> Result<InProgressActions> {
+        let mut precopy_actions = Vec::<Rc<dyn actions::PreAction>>::new();
+        let mut postcopy_actions = Vec::<Rc<dyn actions::PostAction>>::new();
+        let mut in_copy_actions = std::mem::take(&mut self.in_copy_actions);
+        let preparation: Rc<dyn actions::Preparation>;
+        let mut skip_actions = Vec::<Rc<dyn actions::SkipAction>>::new();
+        let mut endings = Vec::<Rc<dyn actions::Ending>>::new();
+        let mut move_action = None;
+        let options = &mut self.options;
+
+        if options.move_sources {
+            options.archive = true;
+        }
+
+        if options.mirror {
+            options.recursive = true;
+        }
+
+        if options.archive {
+            options.recursive = true;
+            options.preserve = Some("all".to_string());
+        }
+
+        let show_bar = if options.mute || options.dry_run {
+            None
+        } else {
+            let mut show_bar = actions::showbar::ShowBar::new()?;
+            if !options.to.is_empty() {
+                let names: Vec<String> = std::iter::once(self.des.clone())
+                    .chain(options.to.iter().cloned())
+                    .collect();
+                show_bar.add_destinations(&names)?;
+            }
+            Some(Rc::new(show_bar))
+        };
+
+        let verbose_action = if options.verbose {
+            let verbose = Rc::new(actions::verbose::VerboseAction::new(
+                show_bar.as_ref().map(|bar| bar.multi_progress()),
+                self.storage.clone(),
+            ));
+            precopy_actions.push(verbose.clone());
+            Some(verbose)
+        } else {
+            None
+        };
+
+        if !options.exclude.is_empty() {
+            precopy_actions.push(Rc::new(actions::exclude::ExcludeAction::new(
+                self.exclude_patterns()?,
+            )));
+        }
+        let options = &self.options;
+
+        if options.move_sources {
+            let mv = Rc::new(actions::mv::MoveAction::new(
+                options.exclude.is_empty(),
+                self.storage.clone(),
+            ));
+            precopy_actions.push(mv.clone());
+            move_action = Some(mv);
+        }
+
+        if options.recursive {
+            precopy_actions.push(Rc::new(actions::recursive::RecursiveAction::new(
+                self.storage.clone(),
+            )));
+        }
+
+        if options.update || options.skip_if.is_some() {
+            let skip_if =
+                actions::update::SkipIf::parse(options.skip_if.as_deref().unwrap_or("mtime"))?;
+            precopy_actions.push(Rc::new(actions::update::UpdateAction::new(
+                skip_if,
+                options.checksum_cache,
+                self.storage.clone(),
+            )));
+        }
+
+        if options.no_clobber || options.interactive || options.force {
+            let policy = if options.no_clobber {
+                actions::overwrite::OverwritePolicy::NoClobber
+            } else if options.interactive {
+                actions::overwrite::OverwritePolicy::Interactive
+            } else {
+                actions::overwrite::OverwritePolicy::Overwrite
+            };
+            precopy_actions.push(Rc::new(actions::overwrite::OverwriteAction::new(
+                policy,
+                options.force,
+                show_bar.as_ref().map(|bar| bar.multi_progress()),
+                self.storage.clone(),
+            )));
+        }
+
+        if options.backup.is_some() || options.suffix.is_some() {
+            let control = options.backup.as_deref().unwrap_or("existing");
+            let suffix = options.suffix.clone().unwrap_or_else(|| "~".to_string());
+            if let Some(backup) =
+                actions::backup::BackupAction::new(control, suffix, self.storage.clone())?
+            {
+                precopy_actions.push(Rc::new(backup));
+            }
+        }
+
+        if options.recursive {
+            precopy_actions.push(Rc::new(actions::special::SpecialAction::new(
+                options.skip_special,
+                self.storage.clone(),
+            )));
+        }
+
+        if let Some(preserve) = options.preserve.clone() {
+            let pact_rc = Rc::new(actions::preserve::PreserveAction::new(
+                preserve,
+                self.storage.clone(),
+            ));
+            precopy_actions.push(pact_rc.clone());
+            postcopy_actions.push(pact_rc);
+        }
+
+        precopy_actions.append(&mut self.pre_actions);
+
+        if let Some(mv) = move_action {
+            postcopy_actions.push(mv.clone());
+            endings.push(mv);
+        }
+
+        postcopy_actions.append(&mut self.post_actions);
+
+        if let Some(verbose) = verbose_action {
+            postcopy_actions.push(verbose.clone());
+            skip_actions.push(verbose);
+        }
+
+        if let Some(show_bar) = show_bar {
+            preparation = show_bar.clone();
+            in_copy_actions.push(show_bar.clone());
+            endings.push(show_bar.clone());
+            postcopy_actions.push(show_bar);
+        } else {
+            let no_bar = Rc::new(actions::showbar::NoBar);
+            preparation = no_bar.clone();
+            endings.push(no_bar);
+        };
+
+        let mut in_copy_action = combine(in_copy_actions);
+        if let Some(size) = options.size {
+            in_copy_action = Rc::new(SizeHint {
+                inner: in_copy_action,
+                size,
+            });
+        }
+
+        Ok((
+            preparation,
+            precopy_actions,
+            in_copy_action,
+            postcopy_actions,
+            skip_actions,
+            endings,
+        ))
+    }
+
+    // The bar follows the entries copied, the pre and post actions do not apply
+    fn build_progress(&mut self) -> Result<Progress> {
+        let mut in_copy_actions = std::mem::take(&mut self.in_copy_actions);
+        let mut post = Vec::<Rc<dyn actions::PostAction>>::new();
+        let mut endings = Vec::<Rc<dyn actions::Ending>>::new();
+
+        let preparation: Rc<dyn actions::Preparation> = if self.options.mute {
+            Rc::new(actions::showbar::NoBar)
+        } else {
+            let show_bar = Rc::new(actions::showbar::ShowBar::new()?);
+            in_copy_actions.push(show_bar.clone());
+            post.push(show_bar.clone());
+            endings.push(show_bar.clone());
+            show_bar
+        };
+
+        Ok(Progress {
+            preparation,
+            in_copy: combine(in_copy_actions),
+            post,
+            endings,
+        })
+    }
+}
+
+// The host or the bucket a path names, if any
+fn parse_remote(path: &str) -> Result<Option<Remote>> {
+    if path.starts_with("s3://") {
+        return match S3Path::parse(path) {
+            Some(path) => Ok(Some(Remote::S3(path))),
+            None => Err(CopyError::InvalidArgument(format!(
+                "'{}' names no bucket",
+                path
+            ))),
+        };
+    }
+    Ok(RemotePath::parse(path).map(Remote::Ssh))
+}
+
+fn combine(mut in_copy_actions: Vec<Rc<dyn InCopyAction>>) -> Rc<dyn InCopyAction> {
+    match in_copy_actions.len() {
+        0 => Rc::new(actions::showbar::NoBar),
+        1 => in_copy_actions.remove(0),
+        _ => Rc::new(InCopyActions(in_copy_actions)),
+    }
+}
+
+// Runs the pre actions until one of them asks to skip the rest
+fn pre_run(precopy_acts: &[Rc<dyn actions::PreAction>], src: &str, des: &str) -> Result<ActRet> {
+    let mut ret = ActRet::GoOn;
+    for act in precopy_acts.iter() {
+        // once an action asks to skip the rest, later actions must not touch the destination
+        if ret.is_final() {
+            break;
+        }
+        ret = ret.merge(act.pre_run(src, des)?);
+    }
+    Ok(ret)
+}
+
+// Gives up on a destination after its first error, the others go on
+fn give_up(failed: &mut Option<CopyError>, e: CopyError) {
+    warn!("Giving up on the destination: {}", e);
+    *failed = Some(e);
+}
+
+pub(crate) fn scan_error(e: scanner::ScanError) -> CopyError {
+    CopyError::io(Stage::Scan, e.path, e.source)
+}
+
+fn open_src(storage: &dyn Storage, src: &str) -> std::io::Result<Handle> {
+    if src == STDIO {
+        return Ok(Box::new(File::from(
+            std::io::stdin().as_fd().try_clone_to_owned()?,
+        )));
+    }
+    storage.open_read(src)
+}
+
+fn create_des(storage: &dyn Storage, des: &str) -> std::io::Result<Handle> {
+    if des == STDIO {
+        return Ok(Box::new(File::from(
+            std::io::stdout().as_fd().try_clone_to_owned()?,
+        )));
+    }
+    storage.open_write(des)
+}
+
+// Adds the codec extension to the compressed files, or strips it from the decompressed ones
+fn rename_extensions(
+    storage: &dyn Storage,
+    src_paths: &[String],
+    des_paths: &mut [String],
+    transform: Transform,
+) {
+    for (src, des) in src_paths.iter().zip(des_paths.iter_mut()) {
+        if des == STDIO || !(src == STDIO || storage.stat(src).is_ok_and(|stat| stat.is_file())) {
+            continue;
+        }
+
+        match transform {
+            Transform::Compress(codec, _) => {
+                des.push('.');
+                des.push_str(codec.extension());
+            }
+            Transform::Decompress => {
+                if let Some(codec) = Codec::from_path(des) {
+                    des.truncate(des.len() - codec.extension().len() - 1);
+                }
+            }
+        }
+    }
+}
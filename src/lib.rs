@@ -0,0 +1,15 @@
+pub mod actions;
+mod archive;
+pub mod error;
+mod job;
+pub mod plan;
+mod remote;
+mod s3;
+mod split;
+
+pub use copier::InCopyAction;
+pub use error::{CopyError, Stage};
+pub use job::{CopyJob, CopyOptions, CopyReport, STDIO};
+pub use storage::storages::localstorage::LocalStorage;
+pub use storage::storages::memstorage::{Faults, MemStorage};
+pub use storage::Storage;
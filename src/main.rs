@@ -1,70 +1,86 @@
-mod actions;
 mod arg;
+mod config;
 
-use actions::ActRet;
-use anyhow::Context;
 use arg::Args;
-use clap::Parser;
-use copier::FileCopy;
-use log::{debug, trace};
-use std::fs::File;
+use log::debug;
+use progressbar_cp::CopyError;
+use std::process::ExitCode;
 
-#[cfg(feature = "basecopier")]
-use copier::copiers::basecopier::Copier;
-#[cfg(feature = "zerocopier")]
-use copier::copiers::zerocopier::Copier;
-
-fn main() -> anyhow::Result<()> {
-    let mut args = Args::parse();
-    env_logger::init();
-
-    debug!("{:?}", args);
+fn exit_code(err: &CopyError) -> u8 {
+    match err {
+        CopyError::InvalidArgument(_) => 2,
+        CopyError::NotFound { .. } => 3,
+        CopyError::PermissionDenied { .. } => 4,
+        CopyError::IsADirectory { .. } | CopyError::NotADirectory { .. } => 5,
+        CopyError::AlreadyExists { .. } => 6,
+        CopyError::NoSpace { .. } => 7,
+        CopyError::Mismatch { .. } => 8,
+        CopyError::Io { .. } | CopyError::Other { .. } => 1,
+    }
+}
 
-    let (src_paths, des_paths) = args.zip_src2des_pairs()?;
+fn run(args: Args) -> progressbar_cp::error::Result<()> {
+    let format = args.dry_run_format().map(|f| f.to_string());
+    let transform = args.transform();
+    let mut report = args.into_job().run()?;
 
-    debug!("src_paths: {:?}", src_paths);
-    debug!("des_paths: {:?}", des_paths);
+    if let Some(transform) = transform {
+        // the uncompressed size over the compressed one
+        let (plain, packed) = match transform {
+            "compressed" => (report.bytes, report.written),
+            _ => (report.written, report.bytes),
+        };
+        if packed > 0 {
+            eprintln!(
+                "{} {} bytes to {} bytes, ratio {:.2}",
+                transform,
+                report.bytes,
+                report.written,
+                plain as f64 / packed as f64
+            );
+        }
+    }
 
-    let (preparation, precopy_acts, in_copy_action, postcopy_acts, ending) =
-        args.build_in_progress_actions()?;
+    if let Some(plan) = report.plan {
+        match format.as_deref() {
+            Some("json") => plan.print_json()?,
+            _ => plan.print_table()?,
+        }
+    }
 
-    let mut copier = Copier::new(4096 * 1024);
-    preparation.get_ready(src_paths.len() as u64)?;
+    // the other destinations have been copied to
+    if let Some(last) = report.failed.pop() {
+        for e in report.failed.iter() {
+            eprintln!("pbcp: {}", e);
+        }
+        return Err(last);
+    }
 
-    for (src, des) in src_paths.iter().zip(des_paths.iter()) {
-        trace!("Copy from {} to {}", src, des);
+    Ok(())
+}
 
-        match precopy_acts.iter().fold(ActRet::GoOn, |pre, act| {
-            match (
-                act.pre_run(src, des)
-                    .expect(&format!("pre actions failed({} to {})", src, des)),
-                pre,
-            ) {
-                (ActRet::GoOn, pre) => pre,
-                (ActRet::SkipRest, ActRet::SkipCopy) => ActRet::SkipCopy,
-                (ActRet::SkipRest, _) => ActRet::SkipRest,
-                (ActRet::SkipCopy, _) => ActRet::SkipCopy,
-            }
-        }) {
-            ActRet::GoOn => {
-                let src_file = File::open(src)?;
-                let des_file = File::create(des)?;
+fn main() -> ExitCode {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    let (args, config) = match config::parse() {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("pbcp: {}", e);
+            return ExitCode::from(exit_code(&e));
+        }
+    };
 
-                copier
-                    .copy(src_file, des_file, &*in_copy_action)
-                    .with_context(|| format!("copy failed({} to {})", src, des))?;
-            }
-            ActRet::SkipRest => continue,
-            ActRet::SkipCopy => {}
-        };
+    debug!("{:?}", args);
 
-        postcopy_acts.iter().for_each(|act| {
-            act.post_run(src, des)
-                .expect(&format!("post actions failed({} to {})", src, des))
-        });
+    if args.print_config() {
+        config.print();
+        return ExitCode::SUCCESS;
     }
 
-    ending.done()?;
-
-    Ok(())
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("pbcp: {}", e);
+            ExitCode::from(exit_code(&e))
+        }
+    }
 }
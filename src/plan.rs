@@ -0,0 +1,150 @@
+use super::actions::{ActRet, Op, PreAction};
+use super::error::{CopyError, Result, Stage};
+use serde::Serialize;
+use std::io::{self, Write};
+use std::rc::Rc;
+use storage::Storage;
+
+#[derive(Serialize)]
+pub struct PlanEntry {
+    action: String,
+    src: Option<String>,
+    des: String,
+    bytes: u64,
+}
+
+#[derive(Serialize)]
+pub struct Plan {
+    entries: Vec<PlanEntry>,
+    total_bytes: u64,
+}
+
+impl Plan {
+    /// Runs the pre actions in plan mode over every pair, nothing gets changed.
+    pub fn build(
+        src_paths: &[String],
+        des_paths: &[String],
+        extraneous: &[String],
+        precopy_acts: &[Rc<dyn PreAction>],
+        storage: &dyn Storage,
+    ) -> Result<Self> {
+        let mut entries = Vec::with_capacity(src_paths.len() + extraneous.len());
+
+        for des in extraneous {
+            entries.push(PlanEntry {
+                action: Op::Delete.to_string(),
+                src: None,
+                des: des.clone(),
+                bytes: 0,
+            });
+        }
+
+        for (src, des) in src_paths.iter().zip(des_paths.iter()) {
+            let mut ret = ActRet::GoOn;
+            let mut ops = Vec::new();
+            let mut bytes = 0;
+
+            for act in precopy_acts.iter() {
+                if ret.is_final() {
+                    break;
+                }
+                let (next, op) = act.plan(src, des)?;
+                ops.extend(op);
+                ret = ret.merge(next);
+            }
+
+            if let ActRet::GoOn = ret {
+                bytes = storage
+                    .stat(src)
+                    .map_err(|e| CopyError::io(Stage::PreCopy, src.as_str(), e))?
+                    .len;
+                if storage.lstat(des).is_ok() {
+                    ops.push(Op::Overwrite);
+                } else {
+                    ops.push(Op::Copy);
+                }
+            }
+
+            if ops.is_empty() {
+                continue;
+            }
+
+            entries.push(PlanEntry {
+                action: ops
+                    .iter()
+                    .map(|op| op.to_string())
+                    .collect::<Vec<_>>()
+                    .join("+"),
+                src: Some(src.clone()),
+                des: des.clone(),
+                bytes,
+            });
+        }
+
+        let total_bytes = entries.iter().map(|e| e.bytes).sum();
+        Ok(Plan {
+            entries,
+            total_bytes,
+        })
+    }
+
+    pub fn print_table(&self) -> Result<()> {
+        ignore_broken_pipe(self.write_table(&mut std::io::stdout().lock()))
+    }
+
+    fn write_table(&self, out: &mut dyn Write) -> io::Result<()> {
+        let width = self
+            .entries
+            .iter()
+            .map(|e| e.action.len())
+            .chain(std::iter::once("ACTION".len()))
+            .max()
+            .unwrap_or(0);
+
+        writeln!(
+            out,
+            "{:<width$}  {:>12}  PATH",
+            "ACTION",
+            "BYTES",
+            width = width
+        )?;
+        for entry in self.entries.iter() {
+            let bytes = match entry.bytes {
+                0 => "-".to_string(),
+                n => n.to_string(),
+            };
+            let path = match &entry.src {
+                Some(src) => format!("{} -> {}", src, entry.des),
+                None => entry.des.clone(),
+            };
+            writeln!(
+                out,
+                "{:<width$}  {:>12}  {}",
+                entry.action,
+                bytes,
+                path,
+                width = width
+            )?;
+        }
+        writeln!(
+            out,
+            "{} operations, {} bytes to copy",
+            self.entries.len(),
+            self.total_bytes
+        )
+    }
+
+    pub fn print_json(&self) -> Result<()> {
+        let json =
+            serde_json::to_string_pretty(self).map_err(|e| CopyError::other(Stage::Ending, e))?;
+        ignore_broken_pipe(writeln!(std::io::stdout().lock(), "{}", json))
+    }
+}
+
+// A reader like `head` may close the pipe early, the rest of the plan is not wanted then
+fn ignore_broken_pipe(ret: io::Result<()>) -> Result<()> {
+    match ret {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        ret => ret.map_err(|e| CopyError::io(Stage::Ending, "stdout", e)),
+    }
+}
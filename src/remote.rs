@@ -0,0 +1,658 @@
+use super::actions::{exclude, preserve::PreserveAction};
+use super::error::{CopyError, Result, Stage};
+use super::job::{scan_error, CopyReport, Progress};
+use copier::InCopyAction;
+use glob::Pattern;
+use log::{debug, trace, warn};
+use scanner::DirScan;
+use ssh2::{
+    CheckResult, ErrorCode, FileStat, KnownHostFileKind, OpenFlags, OpenType, Session, Sftp,
+};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+/// The port SSH listens on unless told otherwise.
+pub const SSH_PORT: u16 = 22;
+
+/// The transfer buffer size unless told otherwise, SFTP sends it in packets anyway.
+pub const BUFFER_SIZE: usize = 256 * 1024;
+
+// the keys tried after the agent, like ssh does
+const KEYS: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+// SFTP status codes the I/O errors of ssh2 do not tell apart
+const FX_PERMISSION_DENIED: i32 = 3;
+const FX_FILE_ALREADY_EXISTS: i32 = 11;
+const FX_NO_SPACE_ON_FILESYSTEM: i32 = 14;
+const FX_QUOTA_EXCEEDED: i32 = 15;
+const FX_NOT_A_DIRECTORY: i32 = 19;
+
+/// A path on another host, written `[user@]host:path` like scp does.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemotePath {
+    pub user: Option<String>,
+    pub host: String,
+    pub path: String,
+}
+
+impl RemotePath {
+    /// Reads `[user@]host:path`, IPv6 hosts go in brackets. A slash before
+    /// the colon keeps the path local, e.g. `./notes:draft`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (user, rest) = match s.split_once('@') {
+            Some((user, rest)) if !user.is_empty() && !user.contains([':', '/']) => {
+                (Some(user.to_string()), rest)
+            }
+            _ => (None, s),
+        };
+        let (host, path) = match rest.strip_prefix('[') {
+            Some(bracketed) => bracketed.split_once("]:")?,
+            None => rest.split_once(':')?,
+        };
+        // URLs such as s3://bucket are no hosts either
+        if host.is_empty() || host.contains('/') || path.starts_with("//") {
+            return None;
+        }
+
+        Some(RemotePath {
+            user,
+            host: host.to_string(),
+            // the home directory, like scp
+            path: if path.is_empty() { "." } else { path }.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for RemotePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(user) = &self.user {
+            write!(f, "{}@", user)?;
+        }
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.path)
+        } else {
+            write!(f, "{}:{}", self.host, self.path)
+        }
+    }
+}
+
+/// Opens an SFTP session on the host of `target`, checking its key against
+/// `~/.ssh/known_hosts` and logging in with the agent or the usual keys.
+pub fn connect(target: &RemotePath, port: u16, key: Option<&str>) -> Result<Sftp> {
+    let name = format!("{}:{}", target.host, port);
+    let tcp = TcpStream::connect((target.host.as_str(), port))
+        .map_err(|e| CopyError::io(Stage::Setup, name.as_str(), e))?;
+    let mut session = Session::new().map_err(|e| CopyError::other(Stage::Setup, e))?;
+    session.set_tcp_stream(tcp);
+    session
+        .handshake()
+        .map_err(|e| CopyError::other(Stage::Setup, e))?;
+
+    check_host_key(&session, &target.host, port)?;
+
+    let user = match &target.user {
+        Some(user) => user.clone(),
+        None => local_user(),
+    };
+    authenticate(&session, &user, key)?;
+    if !session.authenticated() {
+        return Err(CopyError::PermissionDenied {
+            stage: Stage::Setup,
+            path: format!("{}@{}", user, target.host),
+        });
+    }
+
+    session
+        .sftp()
+        .map_err(|e| CopyError::other(Stage::Setup, e))
+}
+
+// Unknown hosts are refused rather than trusted on first use
+fn check_host_key(session: &Session, host: &str, port: u16) -> Result<()> {
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| CopyError::other(Stage::Setup, format!("{} sent no host key", host)))?;
+    let mut known_hosts = session
+        .known_hosts()
+        .map_err(|e| CopyError::other(Stage::Setup, e))?;
+    let file = ssh_dir().join("known_hosts");
+    if let Err(e) = known_hosts.read_file(&file, KnownHostFileKind::OpenSSH) {
+        debug!("Failed to read {}: {}", file.display(), e);
+    }
+
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(CopyError::other(
+            Stage::Setup,
+            format!(
+                "the host key of {} does not match the one in {}",
+                host,
+                file.display()
+            ),
+        )),
+        CheckResult::NotFound => Err(CopyError::other(
+            Stage::Setup,
+            format!(
+                "{} is not in {}, connect to it with ssh once to add its key",
+                host,
+                file.display()
+            ),
+        )),
+        CheckResult::Failure => Err(CopyError::other(
+            Stage::Setup,
+            format!("failed to check the host key of {}", host),
+        )),
+    }
+}
+
+fn authenticate(session: &Session, user: &str, key: Option<&str>) -> Result<()> {
+    if let Some(key) = key {
+        if let Err(e) = session.userauth_pubkey_file(user, None, Path::new(key), None) {
+            debug!("Key {} refused: {}", key, e);
+        }
+        return Ok(());
+    }
+
+    if let Err(e) = session.userauth_agent(user) {
+        debug!("ssh-agent login failed: {}", e);
+    }
+    for name in KEYS {
+        if session.authenticated() {
+            break;
+        }
+        let key = ssh_dir().join(name);
+        if key.exists() {
+            if let Err(e) = session.userauth_pubkey_file(user, None, &key, None) {
+                debug!("Key {} refused: {}", key.display(), e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn ssh_dir() -> PathBuf {
+    std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .unwrap_or_default()
+        .join(".ssh")
+}
+
+fn local_user() -> String {
+    std::env::var("USER").unwrap_or_else(|_| {
+        nix::unistd::User::from_uid(nix::unistd::getuid())
+            .ok()
+            .flatten()
+            .map(|user| user.name)
+            .unwrap_or_else(|| "root".to_string())
+    })
+}
+
+// Tells the SFTP failures apart like the local ones
+fn sftp_error(stage: Stage, path: &str, e: ssh2::Error) -> CopyError {
+    let kind = match e.code() {
+        ErrorCode::SFTP(FX_PERMISSION_DENIED) => Some(io::ErrorKind::PermissionDenied),
+        ErrorCode::SFTP(FX_FILE_ALREADY_EXISTS) => Some(io::ErrorKind::AlreadyExists),
+        ErrorCode::SFTP(FX_NO_SPACE_ON_FILESYSTEM) | ErrorCode::SFTP(FX_QUOTA_EXCEEDED) => {
+            Some(io::ErrorKind::StorageFull)
+        }
+        ErrorCode::SFTP(FX_NOT_A_DIRECTORY) => Some(io::ErrorKind::NotADirectory),
+        _ => None,
+    };
+    let source = match kind {
+        Some(kind) => io::Error::new(kind, e),
+        None => e.into(),
+    };
+    CopyError::io(stage, path, source)
+}
+
+/// Copies local trees to a host or a remote tree from one over SFTP.
+pub struct Transfer {
+    pub sftp: Sftp,
+    pub preserve: Option<PreserveAction>,
+    pub excludes: Vec<Pattern>,
+    pub recursive: bool,
+    pub buffer_size: usize,
+    pub progress: Progress,
+}
+
+impl Transfer {
+    fn preserves(&self, attr: &str) -> bool {
+        self.preserve
+            .as_ref()
+            .is_some_and(|preserve| preserve.preserves(attr))
+    }
+
+    // The remote entry, `None` when it does not exist
+    fn remote_stat(&self, path: &str) -> Result<Option<FileStat>> {
+        match self.sftp.stat(Path::new(path)) {
+            Ok(stat) => Ok(Some(stat)),
+            Err(e) => match sftp_error(Stage::Setup, path, e) {
+                CopyError::NotFound { .. } => Ok(None),
+                e => Err(e),
+            },
+        }
+    }
+
+    /// Copies the sources into `des` on the host, the local side is scanned
+    /// like for the local copies.
+    pub fn upload(&self, srcs: &[String], des: &str) -> Result<CopyReport> {
+        for src in srcs {
+            let is_dir = fs::metadata(src)
+                .map_err(|e| CopyError::io(Stage::Setup, src.as_str(), e))?
+                .is_dir();
+            if is_dir && !self.recursive {
+                return Err(CopyError::InvalidArgument(format!(
+                    "\'{}\' is a directory, should specify -r",
+                    src
+                )));
+            }
+        }
+
+        let des_stat = self.remote_stat(des)?;
+        let des_is_dir = des_stat.as_ref().is_some_and(|stat| stat.is_dir());
+        let scanner = scanner::scanners::basescanner::BaseScanner::new(des);
+        let (src_paths, des_paths) = if des_is_dir {
+            scanner.scan(srcs, false).map_err(scan_error)?
+        } else if srcs.len() > 1 {
+            if des_stat.is_some() {
+                return Err(CopyError::InvalidArgument(format!(
+                    "\'{}\' is not a directory, should specify a directory as the last argument when having multiple srcs",
+                    des
+                )));
+            }
+            self.sftp
+                .mkdir(Path::new(des), 0o755)
+                .map_err(|e| sftp_error(Stage::Setup, des, e))?;
+            scanner.scan(srcs, false).map_err(scan_error)?
+        } else if fs::metadata(&srcs[0]).is_ok_and(|m| m.is_dir()) {
+            if des_stat.is_some() {
+                return Err(CopyError::InvalidArgument(format!(
+                    "\'{}\' is a directory, should specify a directory as the last argument",
+                    srcs[0]
+                )));
+            }
+            scanner.scan(srcs, true).map_err(scan_error)?
+        } else {
+            (srcs.to_vec(), vec![des.to_string()])
+        };
+        debug!("uploading {:?}", src_paths);
+
+        let mut report = CopyReport::default();
+        let mut excluded = Vec::new();
+        self.progress
+            .preparation
+            .get_ready(src_paths.len() as u64)?;
+
+        for (src, des) in src_paths.iter().zip(des_paths.iter()) {
+            trace!("Upload {} to {}", src, des);
+
+            if exclude::is_under(&excluded, src) {
+                report.skipped += 1;
+                continue;
+            }
+            if exclude::is_excluded(&self.excludes, src) {
+                excluded.push(src.clone());
+                report.skipped += 1;
+                continue;
+            }
+
+            // symlinks are followed unless they are preserved
+            let metadata = if self.preserves("links") {
+                fs::symlink_metadata(src)
+            } else {
+                fs::metadata(src)
+            }
+            .map_err(|e| CopyError::io(Stage::Copy, src.as_str(), e))?;
+            let file_type = metadata.file_type();
+
+            if file_type.is_file() {
+                report.bytes += self.upload_file(src, des, &metadata)?;
+                report.copied += 1;
+            } else if file_type.is_dir() {
+                if let Err(e) = self.sftp.mkdir(Path::new(des), 0o755) {
+                    // it may exist already
+                    if !self.remote_stat(des)?.is_some_and(|stat| stat.is_dir()) {
+                        return Err(sftp_error(Stage::Copy, des, e));
+                    }
+                }
+                report.created += 1;
+            } else if file_type.is_symlink() {
+                let target =
+                    fs::read_link(src).map_err(|e| CopyError::io(Stage::Copy, src.as_str(), e))?;
+                // OpenSSH takes the target first, ssh2 sends it so
+                self.sftp
+                    .symlink(&target, Path::new(des))
+                    .map_err(|e| sftp_error(Stage::Copy, des, e))?;
+                report.created += 1;
+            } else {
+                warn!("Skipping special file, SFTP cannot create it: {}", src);
+                report.skipped += 1;
+                continue;
+            }
+
+            if !file_type.is_symlink() {
+                self.set_remote_attrs(des, &metadata);
+            }
+            for act in self.progress.post.iter() {
+                act.post_run(src, des)?;
+            }
+        }
+        report.written = report.bytes;
+
+        for ending in self.progress.endings.iter() {
+            ending.done()?;
+        }
+
+        Ok(report)
+    }
+
+    fn upload_file(&self, src: &str, des: &str, metadata: &fs::Metadata) -> Result<u64> {
+        let mut src_file = File::open(src).map_err(|e| CopyError::io(Stage::Copy, src, e))?;
+        let mut des_file = self
+            .sftp
+            .open_mode(
+                Path::new(des),
+                OpenFlags::WRITE | OpenFlags::TRUNCATE,
+                (metadata.mode() & 0o777) as i32,
+                OpenType::File,
+            )
+            .map_err(|e| sftp_error(Stage::Copy, des, e))?;
+
+        self.progress.in_copy.set_length(metadata.len());
+        transfer(
+            &mut src_file,
+            &mut des_file,
+            self.buffer_size,
+            &*self.progress.in_copy,
+        )
+        .map_err(|e| CopyError::io(Stage::Copy, des, e))
+    }
+
+    // Failing to set an attribute is not fatal, like for the local copies
+    fn set_remote_attrs(&self, des: &str, metadata: &fs::Metadata) {
+        let mut stat = FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: None,
+            atime: None,
+            mtime: None,
+        };
+        if self.preserves("mode") {
+            stat.perm = Some(metadata.mode() & 0o7777);
+        }
+        if self.preserves("ownership") {
+            stat.uid = Some(metadata.uid());
+            stat.gid = Some(metadata.gid());
+        }
+        if self.preserves("timestamps") {
+            stat.atime = Some(metadata.atime() as u64);
+            stat.mtime = Some(metadata.mtime() as u64);
+        }
+        if stat.perm.is_none() && stat.uid.is_none() && stat.atime.is_none() {
+            return;
+        }
+
+        if let Err(e) = self.sftp.setstat(Path::new(des), stat) {
+            debug!("Failed to set the attributes of: {}", des);
+            debug!("Error: {}", e);
+        }
+    }
+
+    /// Copies `src` from the host into the local `des`, the remote tree is
+    /// listed before anything is copied.
+    pub fn download(&self, src: &str, des: &str) -> Result<CopyReport> {
+        let stat = self.remote_stat(src)?.ok_or_else(|| CopyError::NotFound {
+            stage: Stage::Setup,
+            path: src.to_string(),
+        })?;
+        if stat.is_dir() && !self.recursive {
+            return Err(CopyError::InvalidArgument(format!(
+                "\'{}\' is a directory, should specify -r",
+                src
+            )));
+        }
+
+        let des_is_dir = fs::metadata(des).is_ok_and(|m| m.is_dir());
+        let base = match Path::new(src).file_name() {
+            Some(name) if des_is_dir => Path::new(des).join(name),
+            _ if des_is_dir && !stat.is_dir() => {
+                return Err(CopyError::InvalidArgument(format!(
+                    "\'{}\' names no file to copy into \'{}\'",
+                    src, des
+                )))
+            }
+            _ => PathBuf::from(des),
+        };
+
+        let mut entries = Vec::new();
+        self.walk(Path::new(src), &mut entries)?;
+        debug!("downloading {} entries", entries.len());
+
+        let mut report = CopyReport::default();
+        self.progress.preparation.get_ready(entries.len() as u64)?;
+
+        let root = Path::new(src);
+        // directory times change with every entry copied into them
+        let mut dir_times = Vec::new();
+
+        for (path, stat) in entries.iter() {
+            let name = path.to_string_lossy().into_owned();
+            let target = match path.strip_prefix(root) {
+                Ok(rest) if !rest.as_os_str().is_empty() => base.join(rest),
+                _ => base.clone(),
+            };
+            let target_str = target.to_string_lossy().into_owned();
+            trace!("Download {} to {}", name, target_str);
+
+            if !is_inside(&base, &target)
+                .map_err(|e| CopyError::io(Stage::Copy, target_str.as_str(), e))?
+            {
+                warn!("Skipping {}, it points outside of {}", name, base.display());
+                report.skipped += 1;
+                continue;
+            }
+
+            let is_link = stat.file_type().is_symlink();
+            if is_link && self.preserves("links") {
+                let link = self
+                    .sftp
+                    .readlink(path)
+                    .map_err(|e| sftp_error(Stage::Copy, &name, e))?;
+                match std::os::unix::fs::symlink(&link, &target) {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                    Err(e) => return Err(CopyError::io(Stage::Copy, target_str.as_str(), e)),
+                }
+                report.created += 1;
+                for act in self.progress.post.iter() {
+                    act.post_run(&name, &target_str)?;
+                }
+                continue;
+            }
+
+            // symlinks are followed unless they are preserved
+            let stat = if is_link {
+                self.sftp
+                    .stat(path)
+                    .map_err(|e| sftp_error(Stage::Copy, &name, e))?
+            } else {
+                stat.clone()
+            };
+
+            if stat.is_file() {
+                report.bytes += self.download_file(path, &target, &stat)?;
+                report.copied += 1;
+            } else if stat.is_dir() && !is_link {
+                match fs::create_dir(&target) {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists && target.is_dir() => {}
+                    Err(e) => return Err(CopyError::io(Stage::Copy, target_str.as_str(), e)),
+                }
+                if let Some(mtime) = stat.mtime.filter(|_| self.preserves("timestamps")) {
+                    dir_times.push((target.clone(), stat.atime.unwrap_or(mtime), mtime));
+                }
+                report.created += 1;
+            } else {
+                warn!("Skipping {}, it is no regular file or directory", name);
+                report.skipped += 1;
+                continue;
+            }
+
+            self.set_local_attrs(&target, &stat);
+            for act in self.progress.post.iter() {
+                act.post_run(&name, &target_str)?;
+            }
+        }
+        report.written = report.bytes;
+
+        for (dir, atime, mtime) in dir_times.iter().rev() {
+            let atime = filetime::FileTime::from_unix_time(*atime as i64, 0);
+            let mtime = filetime::FileTime::from_unix_time(*mtime as i64, 0);
+            if let Err(e) = filetime::set_file_times(dir, atime, mtime) {
+                debug!("Failed to set timestamps for: {}", dir.display());
+                debug!("Error: {}", e);
+            }
+        }
+
+        for ending in self.progress.endings.iter() {
+            ending.done()?;
+        }
+
+        Ok(report)
+    }
+
+    fn download_file(&self, src: &Path, des: &Path, stat: &FileStat) -> Result<u64> {
+        let name = src.to_string_lossy();
+        let mut src_file = self
+            .sftp
+            .open(src)
+            .map_err(|e| sftp_error(Stage::Copy, &name, e))?;
+        let des_name = des.to_string_lossy();
+        let mut des_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(stat.perm.unwrap_or(0o644) & 0o777)
+            .open(des)
+            .map_err(|e| CopyError::io(Stage::Copy, des_name.as_ref(), e))?;
+
+        self.progress.in_copy.set_length(stat.size.unwrap_or(0));
+        transfer(
+            &mut src_file,
+            &mut des_file,
+            self.buffer_size,
+            &*self.progress.in_copy,
+        )
+        .map_err(|e| CopyError::io(Stage::Copy, des_name.as_ref(), e))
+    }
+
+    // Failing to set an attribute is not fatal, like for the local copies
+    fn set_local_attrs(&self, des: &Path, stat: &FileStat) {
+        if let Some(perm) = stat.perm.filter(|_| self.preserves("mode")) {
+            if let Err(e) = fs::set_permissions(des, fs::Permissions::from_mode(perm & 0o7777)) {
+                debug!("Failed to set permissions for: {}", des.display());
+                debug!("Error: {}", e);
+            }
+        }
+        if let (Some(uid), Some(gid)) = (stat.uid, stat.gid) {
+            if self.preserves("ownership") {
+                let uid = nix::unistd::Uid::from_raw(uid);
+                let gid = nix::unistd::Gid::from_raw(gid);
+                if let Err(e) = nix::unistd::chown(des, Some(uid), Some(gid)) {
+                    debug!("Failed to set ownership for: {}", des.display());
+                    debug!("Error: {}", e);
+                }
+            }
+        }
+        if let Some(mtime) = stat.mtime.filter(|_| self.preserves("timestamps")) {
+            let atime = filetime::FileTime::from_unix_time(stat.atime.unwrap_or(mtime) as i64, 0);
+            let mtime = filetime::FileTime::from_unix_time(mtime as i64, 0);
+            if let Err(e) = filetime::set_file_times(des, atime, mtime) {
+                debug!("Failed to set timestamps for: {}", des.display());
+                debug!("Error: {}", e);
+            }
+        }
+    }
+
+    // Lists the remote tree under `path`, the directories before their entries
+    fn walk(&self, path: &Path, entries: &mut Vec<(PathBuf, FileStat)>) -> Result<()> {
+        let name = path.to_string_lossy();
+        if exclude::is_excluded(&self.excludes, &name) {
+            return Ok(());
+        }
+        let stat = self
+            .sftp
+            .lstat(path)
+            .map_err(|e| sftp_error(Stage::Scan, &name, e))?;
+        let is_dir = stat.is_dir();
+        entries.push((path.to_path_buf(), stat));
+
+        if is_dir {
+            let mut children = self
+                .sftp
+                .readdir(path)
+                .map_err(|e| sftp_error(Stage::Scan, &name, e))?;
+            children.sort_by(|a, b| a.0.cmp(&b.0));
+            for (child, _) in children {
+                // the names come from the server, they may try to leave the tree
+                let is_entry = child.strip_prefix(path).is_ok_and(|rest| {
+                    let mut components = rest.components();
+                    matches!(
+                        (components.next(), components.next()),
+                        (Some(Component::Normal(_)), None)
+                    )
+                });
+                if is_entry {
+                    self.walk(&child, entries)?;
+                } else {
+                    warn!("Skipping {}, it is no entry of {}", child.display(), name);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Whether the parent of `target` still is under `base`, a symlinked directory
+// may lead out of it
+fn is_inside(base: &Path, target: &Path) -> io::Result<bool> {
+    match target.parent() {
+        Some(parent) if target != base => {
+            Ok(parent.canonicalize()?.starts_with(base.canonicalize()?))
+        }
+        _ => Ok(true),
+    }
+}
+
+// Copies with the bar following, SFTP files are no local files for the copiers
+fn transfer(
+    src: &mut dyn Read,
+    des: &mut dyn Write,
+    buffer_size: usize,
+    progress_callback: &dyn InCopyAction,
+) -> io::Result<u64> {
+    let mut buffer = vec![0u8; buffer_size];
+    let mut copied = 0;
+
+    loop {
+        let n = match src.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        des.write_all(&buffer[..n])?;
+        copied += n as u64;
+        progress_callback.in_copy_run(copied);
+    }
+    des.flush()?;
+
+    Ok(copied)
+}
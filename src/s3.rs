@@ -0,0 +1,988 @@
+use super::actions::exclude;
+use super::error::{CopyError, Result, Stage};
+use super::job::{scan_error, CopyReport, Progress};
+use base64::Engine;
+use glob::Pattern;
+use hmac::{Hmac, Mac};
+use log::{debug, trace, warn};
+use md5::{Digest, Md5};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use scanner::DirScan;
+use sha2::Sha256;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The part size of the multipart uploads unless told otherwise.
+pub const PART_SIZE: u64 = 8 << 20;
+/// The smallest part S3 takes, the last one aside.
+pub const MIN_PART_SIZE: u64 = 5 << 20;
+/// The parts uploaded at once unless told otherwise.
+pub const CONCURRENCY: usize = 4;
+
+// S3 takes no more parts per upload, larger files get larger parts
+const MAX_PARTS: u64 = 10_000;
+const BUFFER_SIZE: usize = 256 * 1024;
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+// everything but the unreserved characters, SigV4 wants them encoded so
+const ENCODED: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+const ENCODED_PATH: &AsciiSet = &ENCODED.remove(b'/');
+
+/// An object or a prefix in a bucket, written `s3://bucket/key`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct S3Path {
+    pub bucket: String,
+    pub key: String,
+}
+
+impl S3Path {
+    pub fn parse(s: &str) -> Option<Self> {
+        let rest = s.strip_prefix("s3://")?;
+        let (bucket, key) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            return None;
+        }
+
+        Some(S3Path {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+        })
+    }
+
+    fn object(bucket: &str, key: &str) -> String {
+        format!("s3://{}/{}", bucket, key)
+    }
+}
+
+impl fmt::Display for S3Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", S3Path::object(&self.bucket, &self.key))
+    }
+}
+
+/// An object listed or looked up in a bucket.
+#[derive(Debug, Clone)]
+pub struct Object {
+    pub key: String,
+    pub size: u64,
+    pub etag: String,
+}
+
+/// Signs and sends the S3 requests, set up from the usual AWS variables.
+pub struct Client {
+    agent: ureq::Agent,
+    // scheme and authority, the buckets go in the path
+    endpoint: String,
+    host: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    token: Option<String>,
+}
+
+impl Client {
+    /// Reads the credentials and the region from `AWS_ACCESS_KEY_ID`,
+    /// `AWS_SECRET_ACCESS_KEY`, `AWS_SESSION_TOKEN` and `AWS_REGION`.
+    /// `AWS_ENDPOINT_URL` points at an S3-compatible store, e.g. MinIO.
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+
+        let region = var("AWS_REGION")
+            .or_else(|| var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|| "us-east-1".to_string());
+        let endpoint = var("AWS_ENDPOINT_URL")
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region));
+        let (scheme, authority) = endpoint
+            .split_once("://")
+            .filter(|(scheme, _)| *scheme == "http" || *scheme == "https")
+            .ok_or_else(|| {
+                CopyError::InvalidArgument(format!("invalid S3 endpoint: {}", endpoint))
+            })?;
+        let authority = authority.split('/').next().unwrap_or_default();
+        // ureq leaves the default ports out of the Host header, so does the signature
+        let host = match (scheme, authority.rsplit_once(':')) {
+            ("http", Some((host, "80"))) | ("https", Some((host, "443"))) => host,
+            _ => authority,
+        };
+
+        let (access_key, secret_key) = match (
+            var("AWS_ACCESS_KEY_ID"),
+            var("AWS_SECRET_ACCESS_KEY"),
+        ) {
+            (Some(access_key), Some(secret_key)) => (access_key, secret_key),
+            _ => return Err(CopyError::InvalidArgument(
+                "AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY should be set to copy to or from S3"
+                    .to_string(),
+            )),
+        };
+
+        Ok(Client {
+            agent: ureq::AgentBuilder::new().build(),
+            endpoint: format!("{}://{}", scheme, host),
+            host: host.to_string(),
+            region,
+            access_key,
+            secret_key,
+            token: var("AWS_SESSION_TOKEN"),
+        })
+    }
+
+    // The SigV4 authorization of a request, `headers` are the signed ones
+    fn authorization(
+        &self,
+        method: &str,
+        path: &str,
+        query: &str,
+        headers: &[(String, String)],
+        payload_hash: &str,
+        amz_date: &str,
+    ) -> String {
+        let mut headers: Vec<(String, &str)> = headers
+            .iter()
+            .map(|(name, value)| (name.to_ascii_lowercase(), value.trim()))
+            .collect();
+        headers.sort();
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect();
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(";");
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method, path, query, canonical_headers, signed_headers, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", &amz_date[..8], self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let mut key = format!("AWS4{}", self.secret_key).into_bytes();
+        for part in [&amz_date[..8], self.region.as_str(), "s3", "aws4_request"] {
+            key = hmac(&key, part.as_bytes());
+        }
+        let signature = hex::encode(hmac(&key, string_to_sign.as_bytes()));
+
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature
+        )
+    }
+
+    fn send(
+        &self,
+        method: &str,
+        bucket: &str,
+        key: &str,
+        query: &[(&str, &str)],
+        extra: &[(&str, String)],
+        body: &[u8],
+    ) -> Result<ureq::Response> {
+        let name = S3Path::object(bucket, key);
+        let path = format!(
+            "/{}/{}",
+            utf8_percent_encode(bucket, ENCODED),
+            utf8_percent_encode(key, ENCODED_PATH)
+        );
+        let mut query: Vec<String> = query
+            .iter()
+            .map(|(name, value)| {
+                format!(
+                    "{}={}",
+                    utf8_percent_encode(name, ENCODED),
+                    utf8_percent_encode(value, ENCODED)
+                )
+            })
+            .collect();
+        query.sort();
+        let query = query.join("&");
+
+        let payload_hash = if body.is_empty() {
+            EMPTY_SHA256.to_string()
+        } else {
+            hex::encode(Sha256::digest(body))
+        };
+        let amz_date = amz_date(SystemTime::now());
+        let mut headers = vec![
+            ("host".to_string(), self.host.clone()),
+            ("x-amz-content-sha256".to_string(), payload_hash.clone()),
+            ("x-amz-date".to_string(), amz_date.clone()),
+        ];
+        if let Some(token) = &self.token {
+            headers.push(("x-amz-security-token".to_string(), token.clone()));
+        }
+        headers.extend(
+            extra
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.clone())),
+        );
+        let authorization =
+            self.authorization(method, &path, &query, &headers, &payload_hash, &amz_date);
+
+        let url = if query.is_empty() {
+            format!("{}{}", self.endpoint, path)
+        } else {
+            format!("{}{}?{}", self.endpoint, path, query)
+        };
+        trace!("{} {}", method, url);
+        let mut request = self
+            .agent
+            .request(method, &url)
+            .set("Authorization", &authorization);
+        for (name, value) in headers.iter() {
+            request = request.set(name, value);
+        }
+
+        match request.send_bytes(body) {
+            Ok(response) => Ok(response),
+            Err(ureq::Error::Status(status, response)) => {
+                Err(status_error(status, response.into_string().ok(), name))
+            }
+            Err(ureq::Error::Transport(e)) => {
+                Err(CopyError::io(Stage::Copy, name, io::Error::other(e)))
+            }
+        }
+    }
+
+    /// The object under `key`, `None` when there is none.
+    pub fn head(&self, bucket: &str, key: &str) -> Result<Option<Object>> {
+        match self.send("HEAD", bucket, key, &[], &[], &[]) {
+            Ok(response) => Ok(Some(Object {
+                key: key.to_string(),
+                size: response
+                    .header("Content-Length")
+                    .and_then(|len| len.parse().ok())
+                    .unwrap_or(0),
+                etag: etag(&response),
+            })),
+            Err(CopyError::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The objects whose keys start with `prefix`, page after page.
+    pub fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<Object>> {
+        let mut objects = Vec::new();
+        let mut token: Option<String> = None;
+
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+            if let Some(token) = &token {
+                query.push(("continuation-token", token));
+            }
+            let body = self.send("GET", bucket, "", &query, &[], &[])?;
+            let body = read_body(body, bucket, prefix)?;
+            let doc = parse_xml(&body, bucket, prefix)?;
+
+            for contents in doc
+                .descendants()
+                .filter(|node| node.has_tag_name("Contents"))
+            {
+                objects.push(Object {
+                    key: child_text(contents, "Key").unwrap_or_default(),
+                    size: child_text(contents, "Size")
+                        .and_then(|size| size.parse().ok())
+                        .unwrap_or(0),
+                    etag: child_text(contents, "ETag")
+                        .unwrap_or_default()
+                        .trim_matches('"')
+                        .to_string(),
+                });
+            }
+
+            let root = doc.root_element();
+            if child_text(root, "IsTruncated").as_deref() != Some("true") {
+                break;
+            }
+            token = child_text(root, "NextContinuationToken");
+            if token.is_none() {
+                break;
+            }
+        }
+
+        Ok(objects)
+    }
+
+    /// Uploads `body` at once, S3 checks it against its MD5 and so does the ETag.
+    pub fn put(&self, bucket: &str, key: &str, body: &[u8], src: &str) -> Result<()> {
+        let digest = Md5::digest(body);
+        let response = self.send(
+            "PUT",
+            bucket,
+            key,
+            &[],
+            &[("content-md5", content_md5(&digest))],
+            body,
+        )?;
+        check_etag(&etag(&response), &hex::encode(digest), src, bucket, key)
+    }
+
+    pub fn create_upload(&self, bucket: &str, key: &str) -> Result<String> {
+        let response = self.send("POST", bucket, key, &[("uploads", "")], &[], &[])?;
+        let body = read_body(response, bucket, key)?;
+        let doc = parse_xml(&body, bucket, key)?;
+        child_text(doc.root_element(), "UploadId").ok_or_else(|| {
+            CopyError::other(
+                Stage::Copy,
+                format!("no upload id for {}", S3Path::object(bucket, key)),
+            )
+        })
+    }
+
+    /// Uploads the part `number`, returning its ETag and MD5.
+    pub fn upload_part(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        number: u32,
+        body: &[u8],
+        src: &str,
+    ) -> Result<(String, Vec<u8>)> {
+        let digest = Md5::digest(body);
+        let number = number.to_string();
+        let response = self.send(
+            "PUT",
+            bucket,
+            key,
+            &[("partNumber", &number), ("uploadId", upload_id)],
+            &[("content-md5", content_md5(&digest))],
+            body,
+        )?;
+        let etag = etag(&response);
+        check_etag(&etag, &hex::encode(digest), src, bucket, key)?;
+        Ok((etag, digest.to_vec()))
+    }
+
+    /// Puts the parts together, returning the ETag of the object.
+    pub fn complete_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: &[(u32, String)],
+    ) -> Result<String> {
+        let mut xml = String::from("<CompleteMultipartUpload>");
+        for (number, etag) in parts {
+            xml.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>\"{}\"</ETag></Part>",
+                number, etag
+            ));
+        }
+        xml.push_str("</CompleteMultipartUpload>");
+
+        let response = self.send(
+            "POST",
+            bucket,
+            key,
+            &[("uploadId", upload_id)],
+            &[],
+            xml.as_bytes(),
+        )?;
+        let body = read_body(response, bucket, key)?;
+        let doc = parse_xml(&body, bucket, key)?;
+        // the upload may fail after the status has been sent
+        let root = doc.root_element();
+        if root.has_tag_name("Error") {
+            return Err(xml_error(root, S3Path::object(bucket, key)));
+        }
+        Ok(child_text(root, "ETag")
+            .unwrap_or_default()
+            .trim_matches('"')
+            .to_string())
+    }
+
+    pub fn abort_upload(&self, bucket: &str, key: &str, upload_id: &str) -> Result<()> {
+        self.send("DELETE", bucket, key, &[("uploadId", upload_id)], &[], &[])?;
+        Ok(())
+    }
+
+    pub fn get(&self, bucket: &str, key: &str) -> Result<Box<dyn Read + Send + Sync>> {
+        Ok(self.send("GET", bucket, key, &[], &[], &[])?.into_reader())
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn content_md5(digest: &[u8]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(digest)
+}
+
+fn etag(response: &ureq::Response) -> String {
+    response
+        .header("ETag")
+        .unwrap_or_default()
+        .trim_matches('"')
+        .to_string()
+}
+
+fn check_etag(etag: &str, expected: &str, src: &str, bucket: &str, key: &str) -> Result<()> {
+    if etag.eq_ignore_ascii_case(expected) {
+        return Ok(());
+    }
+    debug!("ETag {} of {} should be {}", etag, key, expected);
+    Err(CopyError::Mismatch {
+        stage: Stage::Copy,
+        src: src.to_string(),
+        des: S3Path::object(bucket, key),
+    })
+}
+
+// The time of a request, e.g. 20130524T000000Z
+fn amz_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, rest) = ((secs / 86_400) as i64, secs % 86_400);
+
+    // the civil date of a day count since 1970, after Howard Hinnant
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        rest / 3_600,
+        rest % 3_600 / 60,
+        rest % 60
+    )
+}
+
+fn read_body(response: ureq::Response, bucket: &str, key: &str) -> Result<String> {
+    response
+        .into_string()
+        .map_err(|e| CopyError::io(Stage::Copy, S3Path::object(bucket, key), e))
+}
+
+fn parse_xml<'a>(body: &'a str, bucket: &str, key: &str) -> Result<roxmltree::Document<'a>> {
+    roxmltree::Document::parse(body).map_err(|e| {
+        CopyError::other(
+            Stage::Copy,
+            format!("bad response for {}: {}", S3Path::object(bucket, key), e),
+        )
+    })
+}
+
+fn child_text(node: roxmltree::Node, tag: &str) -> Option<String> {
+    node.children()
+        .find(|child| child.has_tag_name(tag))
+        .and_then(|child| child.text())
+        .map(str::to_string)
+}
+
+fn xml_error(root: roxmltree::Node, name: String) -> CopyError {
+    CopyError::other(
+        Stage::Copy,
+        format!(
+            "{}: {} {}",
+            name,
+            child_text(root, "Code").unwrap_or_default(),
+            child_text(root, "Message").unwrap_or_default()
+        ),
+    )
+}
+
+// Tells the S3 failures apart like the local ones
+fn status_error(status: u16, body: Option<String>, name: String) -> CopyError {
+    match status {
+        404 => CopyError::NotFound {
+            stage: Stage::Copy,
+            path: name,
+        },
+        403 => CopyError::PermissionDenied {
+            stage: Stage::Copy,
+            path: name,
+        },
+        _ => match body
+            .as_deref()
+            .and_then(|body| roxmltree::Document::parse(body).ok())
+        {
+            Some(doc) => xml_error(doc.root_element(), name),
+            None => CopyError::other(Stage::Copy, format!("{}: HTTP {}", name, status)),
+        },
+    }
+}
+
+// The part size of an upload, the one given unless the file needs more parts
+fn part_size_for(part_size: u64, len: u64) -> u64 {
+    part_size.max(len.div_ceil(MAX_PARTS))
+}
+
+// Recomputes the ETag while reading an object, the MD5 of the data or, for
+// a multipart upload, the MD5 of the MD5s of its parts
+struct EtagCheck {
+    whole: Md5,
+    part: Md5,
+    part_len: u64,
+    part_size: u64,
+    digests: Vec<u8>,
+    parts: u64,
+}
+
+impl EtagCheck {
+    fn new(part_size: u64) -> Self {
+        EtagCheck {
+            whole: Md5::new(),
+            part: Md5::new(),
+            part_len: 0,
+            part_size,
+            digests: Vec::new(),
+            parts: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.whole.update(data);
+        while !data.is_empty() {
+            let n = data.len().min((self.part_size - self.part_len) as usize);
+            self.part.update(&data[..n]);
+            self.part_len += n as u64;
+            data = &data[n..];
+            if self.part_len == self.part_size {
+                self.end_part();
+            }
+        }
+    }
+
+    fn end_part(&mut self) {
+        let part = std::mem::replace(&mut self.part, Md5::new());
+        self.digests.extend(part.finalize());
+        self.part_len = 0;
+        self.parts += 1;
+    }
+
+    // `None` when the ETag is no MD5, or the parts were of another size
+    fn matches(mut self, etag: &str) -> Option<bool> {
+        match etag.split_once('-') {
+            None if etag.len() == 32 => {
+                Some(hex::encode(self.whole.finalize()).eq_ignore_ascii_case(etag))
+            }
+            None => None,
+            Some((digest, parts)) => {
+                if self.part_len > 0 {
+                    self.end_part();
+                }
+                if parts.parse() != Ok(self.parts) {
+                    return None;
+                }
+                Some(hex::encode(Md5::digest(&self.digests)).eq_ignore_ascii_case(digest))
+            }
+        }
+    }
+}
+
+// An uploaded part of a file
+struct Part {
+    number: u32,
+    etag: String,
+    digest: Vec<u8>,
+    len: u64,
+}
+
+// A key is excluded when any of its names is
+fn is_key_excluded(patterns: &[Pattern], rel: &str) -> bool {
+    rel.split('/')
+        .any(|name| patterns.iter().any(|p| p.matches(name)))
+}
+
+/// Copies local trees into a bucket or objects out of one.
+pub struct ObjectTransfer {
+    pub client: Client,
+    pub part_size: u64,
+    pub concurrency: usize,
+    pub excludes: Vec<Pattern>,
+    pub recursive: bool,
+    pub progress: Progress,
+}
+
+impl ObjectTransfer {
+    /// Uploads the sources under `des`, a key ending with `/` or several
+    /// sources name a prefix to upload into. S3 has no directories, only the
+    /// files are uploaded.
+    pub fn upload(&self, srcs: &[String], des: &S3Path) -> Result<CopyReport> {
+        let mut is_dir = false;
+        for src in srcs {
+            if fs::metadata(src)
+                .map_err(|e| CopyError::io(Stage::Setup, src.as_str(), e))?
+                .is_dir()
+            {
+                if !self.recursive {
+                    return Err(CopyError::InvalidArgument(format!(
+                        "\'{}\' is a directory, should specify -r",
+                        src
+                    )));
+                }
+                is_dir = true;
+            }
+        }
+
+        let into_prefix = des.key.is_empty() || des.key.ends_with('/') || srcs.len() > 1;
+        let base = des.key.trim_end_matches('/');
+        let scanner = scanner::scanners::basescanner::BaseScanner::new(base);
+        let (src_paths, keys) = if into_prefix {
+            scanner.scan(srcs, false).map_err(scan_error)?
+        } else if is_dir {
+            scanner.scan(srcs, true).map_err(scan_error)?
+        } else {
+            (srcs.to_vec(), vec![des.key.clone()])
+        };
+        debug!("uploading {:?}", src_paths);
+
+        let mut report = CopyReport::default();
+        let mut excluded = Vec::new();
+        self.progress
+            .preparation
+            .get_ready(src_paths.len() as u64)?;
+
+        for (src, key) in src_paths.iter().zip(keys.iter()) {
+            let key = key.trim_start_matches('/');
+            trace!("Upload {} to {}", src, key);
+
+            if exclude::is_under(&excluded, src) {
+                report.skipped += 1;
+                continue;
+            }
+            if exclude::is_excluded(&self.excludes, src) {
+                excluded.push(src.clone());
+                report.skipped += 1;
+                continue;
+            }
+
+            let metadata =
+                fs::metadata(src).map_err(|e| CopyError::io(Stage::Copy, src.as_str(), e))?;
+            if metadata.is_file() {
+                report.bytes += self.upload_file(src, &des.bucket, key, metadata.len())?;
+                report.copied += 1;
+            } else if metadata.is_dir() {
+                report.created += 1;
+            } else {
+                warn!("Skipping special file, S3 cannot hold it: {}", src);
+                report.skipped += 1;
+                continue;
+            }
+
+            for act in self.progress.post.iter() {
+                act.post_run(src, &S3Path::object(&des.bucket, key))?;
+            }
+        }
+        report.written = report.bytes;
+
+        for ending in self.progress.endings.iter() {
+            ending.done()?;
+        }
+
+        Ok(report)
+    }
+
+    fn upload_file(&self, src: &str, bucket: &str, key: &str, len: u64) -> Result<u64> {
+        let mut file = File::open(src).map_err(|e| CopyError::io(Stage::Copy, src, e))?;
+        self.progress.in_copy.set_length(len);
+
+        let part_size = part_size_for(self.part_size, len);
+        if len <= part_size {
+            let mut body = Vec::with_capacity(len as usize);
+            file.read_to_end(&mut body)
+                .map_err(|e| CopyError::io(Stage::Copy, src, e))?;
+            self.client.put(bucket, key, &body, src)?;
+            self.progress.in_copy.in_copy_run(body.len() as u64);
+            return Ok(body.len() as u64);
+        }
+
+        let upload_id = self.client.create_upload(bucket, key)?;
+        let parts = match self.upload_parts(&mut file, src, bucket, key, &upload_id, part_size) {
+            Ok(parts) => parts,
+            Err(e) => {
+                // the uploaded parts are kept, and billed, until aborted
+                if let Err(e) = self.client.abort_upload(bucket, key, &upload_id) {
+                    warn!("Failed to abort the upload of {}: {}", key, e);
+                }
+                return Err(e);
+            }
+        };
+
+        let mut digests = Vec::new();
+        let mut etags = Vec::new();
+        let mut uploaded = 0;
+        for part in parts {
+            digests.extend(part.digest);
+            etags.push((part.number, part.etag));
+            uploaded += part.len;
+        }
+        let etag = self
+            .client
+            .complete_upload(bucket, key, &upload_id, &etags)?;
+        let expected = format!("{}-{}", hex::encode(Md5::digest(&digests)), etags.len());
+        check_etag(&etag, &expected, src, bucket, key)?;
+
+        Ok(uploaded)
+    }
+
+    // Reads the parts in order and uploads them from several threads, the bar
+    // moves as they complete
+    fn upload_parts(
+        &self,
+        file: &mut File,
+        src: &str,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        part_size: u64,
+    ) -> Result<Vec<Part>> {
+        let (job_tx, job_rx) = mpsc::sync_channel::<(u32, Vec<u8>)>(self.concurrency);
+        let job_rx = Mutex::new(job_rx);
+        let (done_tx, done_rx) = mpsc::channel();
+        let client = &self.client;
+
+        std::thread::scope(|s| {
+            for _ in 0..self.concurrency {
+                let job_rx = &job_rx;
+                let done_tx = done_tx.clone();
+                s.spawn(move || loop {
+                    let job = job_rx
+                        .lock()
+                        .map_err(|_| ())
+                        .and_then(|rx| rx.recv().map_err(|_| ()));
+                    let Ok((number, body)) = job else {
+                        break;
+                    };
+                    let len = body.len() as u64;
+                    let result = client
+                        .upload_part(bucket, key, upload_id, number, &body, src)
+                        .map(|(etag, digest)| Part {
+                            number,
+                            etag,
+                            digest,
+                            len,
+                        });
+                    if done_tx.send(result).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(done_tx);
+
+            let mut parts = Vec::new();
+            let mut failure = None;
+            let mut uploaded = 0;
+            let mut record = |result: Result<Part>| match result {
+                Ok(part) => {
+                    uploaded += part.len;
+                    self.progress.in_copy.in_copy_run(uploaded);
+                    parts.push(part);
+                }
+                Err(e) => {
+                    failure.get_or_insert(e);
+                }
+            };
+
+            let mut number = 0;
+            loop {
+                let mut body = Vec::with_capacity(part_size as usize);
+                if let Err(e) = (&mut *file).take(part_size).read_to_end(&mut body) {
+                    record(Err(CopyError::io(Stage::Copy, src, e)));
+                    break;
+                }
+                if body.is_empty() {
+                    break;
+                }
+                number += 1;
+                if job_tx.send((number, body)).is_err() {
+                    break;
+                }
+                let mut failed = false;
+                while let Ok(result) = done_rx.try_recv() {
+                    failed |= result.is_err();
+                    record(result);
+                }
+                if failed {
+                    break;
+                }
+            }
+            drop(job_tx);
+            for result in done_rx {
+                record(result);
+            }
+
+            match failure {
+                Some(e) => Err(e),
+                None => {
+                    parts.sort_by_key(|part| part.number);
+                    Ok(parts)
+                }
+            }
+        })
+    }
+
+    /// Downloads the object `src` into `des`, or with -r the objects under
+    /// the prefix `src`, named after their keys.
+    pub fn download(&self, src: &S3Path, des: &str) -> Result<CopyReport> {
+        let object = match src.key.is_empty() || src.key.ends_with('/') {
+            true => None,
+            false => self.client.head(&src.bucket, &src.key)?,
+        };
+        let des_is_dir = fs::metadata(des).is_ok_and(|m| m.is_dir());
+
+        let (objects, prefix, base) = match object {
+            Some(object) => {
+                let name = src.key.rsplit('/').next().unwrap_or_default();
+                let base = if des_is_dir {
+                    Path::new(des).join(name)
+                } else {
+                    PathBuf::from(des)
+                };
+                (vec![object], src.key.clone(), base)
+            }
+            None => {
+                if !self.recursive {
+                    return Err(CopyError::InvalidArgument(format!(
+                        "\'{}\' is no object, should specify -r to copy the objects under it",
+                        src
+                    )));
+                }
+                let prefix = match src.key.is_empty() || src.key.ends_with('/') {
+                    true => src.key.clone(),
+                    false => format!("{}/", src.key),
+                };
+                let objects = self.client.list(&src.bucket, &prefix)?;
+                if objects.is_empty() {
+                    return Err(CopyError::NotFound {
+                        stage: Stage::Setup,
+                        path: src.to_string(),
+                    });
+                }
+                let name = prefix
+                    .trim_end_matches('/')
+                    .rsplit('/')
+                    .next()
+                    .filter(|name| !name.is_empty())
+                    .unwrap_or(&src.bucket);
+                let base = if des_is_dir {
+                    Path::new(des).join(name)
+                } else {
+                    PathBuf::from(des)
+                };
+                (objects, prefix, base)
+            }
+        };
+        debug!("downloading {} objects", objects.len());
+
+        let mut report = CopyReport::default();
+        self.progress.preparation.get_ready(objects.len() as u64)?;
+
+        for object in objects.iter() {
+            let name = S3Path::object(&src.bucket, &object.key);
+            let rel = object.key.strip_prefix(&prefix).unwrap_or(&object.key);
+            trace!("Download {} as {}", name, rel);
+
+            if is_key_excluded(&self.excludes, rel) {
+                report.skipped += 1;
+                continue;
+            }
+            // keys are no paths, they may lead anywhere
+            if !Path::new(rel)
+                .components()
+                .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+            {
+                warn!("Skipping {}, it points outside of {}", name, des);
+                report.skipped += 1;
+                continue;
+            }
+            let target = match rel.trim_end_matches('/') {
+                "" => base.clone(),
+                rel => base.join(rel),
+            };
+            let target_str = target.to_string_lossy().into_owned();
+
+            // the folders made by the consoles are empty objects ending with /
+            if object.key.ends_with('/') {
+                fs::create_dir_all(&target)
+                    .map_err(|e| CopyError::io(Stage::Copy, target_str.as_str(), e))?;
+                report.created += 1;
+            } else {
+                if let Some(parent) = target.parent().filter(|p| !p.as_os_str().is_empty()) {
+                    fs::create_dir_all(parent)
+                        .map_err(|e| CopyError::io(Stage::Copy, target_str.as_str(), e))?;
+                }
+                report.bytes += self.download_object(&src.bucket, object, &target_str)?;
+                report.copied += 1;
+            }
+
+            for act in self.progress.post.iter() {
+                act.post_run(&name, &target_str)?;
+            }
+        }
+        report.written = report.bytes;
+
+        for ending in self.progress.endings.iter() {
+            ending.done()?;
+        }
+
+        Ok(report)
+    }
+
+    fn download_object(&self, bucket: &str, object: &Object, des: &str) -> Result<u64> {
+        let name = S3Path::object(bucket, &object.key);
+        let mut reader = self.client.get(bucket, &object.key)?;
+        let mut file = File::create(des).map_err(|e| CopyError::io(Stage::Copy, des, e))?;
+        self.progress.in_copy.set_length(object.size);
+
+        let mut check = EtagCheck::new(part_size_for(self.part_size, object.size));
+        let mut buffer = vec![0u8; BUFFER_SIZE];
+        let mut copied = 0;
+        loop {
+            let n = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(CopyError::io(Stage::Copy, name, e)),
+            };
+            check.update(&buffer[..n]);
+            file.write_all(&buffer[..n])
+                .map_err(|e| CopyError::io(Stage::Copy, des, e))?;
+            copied += n as u64;
+            self.progress.in_copy.in_copy_run(copied);
+        }
+
+        match check.matches(&object.etag) {
+            Some(true) => Ok(copied),
+            Some(false) => Err(CopyError::Mismatch {
+                stage: Stage::Copy,
+                src: name,
+                des: des.to_string(),
+            }),
+            None => {
+                debug!("Cannot check {} against its ETag {}", name, object.etag);
+                Ok(copied)
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use super::error::{CopyError, Result, Stage};
+use copier::{preallocate, FileCopy, InCopyAction};
+use log::debug;
+use storage::Storage;
+
+/// The name of the part `index` of a file split into `path`.
+pub fn part_name(path: &str, index: usize) -> String {
+    format!("{}.{:03}", path, index)
+}
+
+/// The file the first part, e.g. `image.iso.000`, is joined into.
+pub fn joined_name(path: &str) -> Option<&str> {
+    path.strip_suffix(".000")
+}
+
+// A part after the first one, it is joined together with the first
+fn is_later_part(storage: &dyn Storage, path: &str) -> bool {
+    match path.rsplit_once('.') {
+        Some((name, index)) => {
+            index.len() >= 3
+                && index.bytes().all(|b| b.is_ascii_digit())
+                && index != "000"
+                && storage.stat(&part_name(name, 0)).is_ok()
+        }
+        None => false,
+    }
+}
+
+/// Drops the parts after the first ones from the pairs, and names the
+/// destinations of the first ones after the joined files.
+pub fn join_pairs(
+    storage: &dyn Storage,
+    src_paths: Vec<String>,
+    des_paths: Vec<String>,
+) -> (Vec<String>, Vec<String>) {
+    src_paths
+        .into_iter()
+        .zip(des_paths)
+        .filter(|(src, _)| !is_later_part(storage, src))
+        .map(|(src, des)| {
+            let des = joined_name(&des).map(str::to_string).unwrap_or(des);
+            (src, des)
+        })
+        .unzip()
+}
+
+/// Copies `src` into parts of `size` bytes named after `des`, the progress
+/// spans all of them.
+pub fn split(
+    copier: &mut dyn FileCopy,
+    storage: &dyn Storage,
+    src: &str,
+    des: &str,
+    size: u64,
+    progress_callback: &dyn InCopyAction,
+) -> Result<u64> {
+    let mut src_file = storage
+        .open_read(src)
+        .map_err(|e| CopyError::io(Stage::Copy, src, e))?;
+    let len = src_file
+        .stat()
+        .map_err(|e| CopyError::io(Stage::Copy, src, e))?
+        .len;
+    progress_callback.set_length(len);
+
+    let mut copied = 0;
+    let mut index = 0;
+    loop {
+        let part = part_name(des, index);
+        let mut part_file = storage
+            .open_write(&part)
+            .map_err(|e| CopyError::io(Stage::Copy, part.as_str(), e))?;
+        let n = preallocate(&*part_file, size.min(len.saturating_sub(copied)))
+            .and_then(|_| {
+                copier.copy_span(
+                    &mut *src_file,
+                    &mut *part_file,
+                    Some(size),
+                    copied,
+                    progress_callback,
+                )
+            })
+            .map_err(|e| CopyError::io(Stage::Copy, part.as_str(), e))?;
+        copied += n;
+        index += 1;
+
+        if n < size || copied >= len {
+            break;
+        }
+    }
+
+    // the parts left from an earlier split of a larger file would be joined too
+    loop {
+        let stale = part_name(des, index);
+        if storage.lstat(&stale).is_err() {
+            break;
+        }
+        debug!("Removing the stale part: {}", stale);
+        storage
+            .remove_file(&stale)
+            .map_err(|e| CopyError::io(Stage::Copy, stale.as_str(), e))?;
+        index += 1;
+    }
+
+    Ok(copied)
+}
+
+/// Concatenates the parts `NAME.000`, `NAME.001`... starting at `first` into `des`.
+pub fn join(
+    copier: &mut dyn FileCopy,
+    storage: &dyn Storage,
+    first: &str,
+    des: &str,
+    progress_callback: &dyn InCopyAction,
+) -> Result<u64> {
+    let name = joined_name(first).unwrap_or(first);
+    let parts: Vec<String> = (0..)
+        .map(|index| part_name(name, index))
+        .take_while(|part| storage.stat(part).is_ok_and(|stat| stat.is_file()))
+        .collect();
+    debug!("joining {:?}", parts);
+
+    let mut len = 0;
+    for part in parts.iter() {
+        len += storage
+            .stat(part)
+            .map_err(|e| CopyError::io(Stage::Copy, part.as_str(), e))?
+            .len;
+    }
+    progress_callback.set_length(len);
+
+    let mut des_file = storage
+        .open_write(des)
+        .map_err(|e| CopyError::io(Stage::Copy, des, e))?;
+    preallocate(&*des_file, len).map_err(|e| CopyError::io(Stage::Copy, des, e))?;
+
+    let mut copied = 0;
+    for part in parts.iter() {
+        let mut part_file = storage
+            .open_read(part)
+            .map_err(|e| CopyError::io(Stage::Copy, part.as_str(), e))?;
+        copied += copier
+            .copy_span(
+                &mut *part_file,
+                &mut *des_file,
+                None,
+                copied,
+                progress_callback,
+            )
+            .map_err(|e| CopyError::io(Stage::Copy, des, e))?;
+    }
+
+    Ok(copied)
+}
@@ -0,0 +1,594 @@
+use progressbar_cp::actions::{ActRet, Op, PostAction, PreAction};
+use progressbar_cp::{
+    CopyError, CopyJob, CopyOptions, CopyReport, Faults, InCopyAction, MemStorage, Stage, Storage,
+};
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::rc::Rc;
+use storage::{Attrs, FileHandle, Handle, Stat};
+use tempfile::tempdir;
+
+struct SkipLogs;
+
+impl PreAction for SkipLogs {
+    fn pre_run(&self, src: &str, _: &str) -> progressbar_cp::error::Result<ActRet> {
+        if src.ends_with(".log") {
+            return Ok(ActRet::SkipRest);
+        }
+        Ok(ActRet::GoOn)
+    }
+
+    fn plan(&self, src: &str, des: &str) -> progressbar_cp::error::Result<(ActRet, Option<Op>)> {
+        Ok((self.pre_run(src, des)?, None))
+    }
+}
+
+#[derive(Default)]
+struct Recorder {
+    copied: RefCell<Vec<String>>,
+    length: Cell<u64>,
+}
+
+impl PostAction for Recorder {
+    fn post_run(&self, _: &str, des: &str) -> progressbar_cp::error::Result<()> {
+        self.copied.borrow_mut().push(des.to_string());
+        Ok(())
+    }
+}
+
+impl InCopyAction for Recorder {
+    fn set_length(&self, length: u64) {
+        self.length.set(self.length.get() + length);
+    }
+    fn in_copy_run(&self, _: u64) {}
+}
+
+#[test]
+fn test_copy_job_with_hooks() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let des_dir = temp_dir.path().join("des");
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::write(src_dir.join("file1.txt"), "Hello, world!").unwrap();
+    fs::write(src_dir.join("skip.log"), "Log content").unwrap();
+
+    let recorder = Rc::new(Recorder::default());
+    let report = CopyJob::new(
+        vec![src_dir.to_str().unwrap().to_string()],
+        des_dir.to_str().unwrap().to_string(),
+    )
+    .options(CopyOptions {
+        recursive: true,
+        mute: true,
+        ..Default::default()
+    })
+    .pre_action(Rc::new(SkipLogs))
+    .post_action(recorder.clone())
+    .in_copy_action(recorder.clone())
+    .run()
+    .unwrap();
+
+    assert_eq!(report.copied, 1);
+    assert_eq!(report.bytes, 13);
+    assert_eq!(report.created, 1);
+    assert_eq!(report.skipped, 1);
+    assert_eq!(recorder.length.get(), 13);
+    assert_eq!(
+        *recorder.copied.borrow(),
+        vec![
+            des_dir.to_str().unwrap().to_string(),
+            des_dir.join("file1.txt").to_str().unwrap().to_string()
+        ]
+    );
+    assert!(!des_dir.join("skip.log").exists());
+}
+
+#[test]
+fn test_copy_job_dry_run() {
+    let temp_dir = tempdir().unwrap();
+    let src_file = temp_dir.path().join("src.txt");
+    let des_file = temp_dir.path().join("des.txt");
+    fs::write(&src_file, "Hello, world!").unwrap();
+
+    let report = CopyJob::new(
+        vec![src_file.to_str().unwrap().to_string()],
+        des_file.to_str().unwrap().to_string(),
+    )
+    .options(CopyOptions {
+        dry_run: true,
+        ..Default::default()
+    })
+    .run()
+    .unwrap();
+
+    assert!(report.plan.is_some());
+    assert_eq!(report.copied, 0);
+    assert!(!des_file.exists());
+}
+
+// A pre action without a planner of its own
+struct Counter(Cell<u64>);
+
+impl PreAction for Counter {
+    fn pre_run(&self, _: &str, _: &str) -> progressbar_cp::error::Result<ActRet> {
+        self.0.set(self.0.get() + 1);
+        Ok(ActRet::GoOn)
+    }
+}
+
+#[test]
+fn test_dry_run_with_default_planner() {
+    let temp_dir = tempdir().unwrap();
+    let src_file = temp_dir.path().join("src.txt");
+    fs::write(&src_file, "Hello, world!").unwrap();
+    let counter = Rc::new(Counter(Cell::new(0)));
+
+    let report = CopyJob::new(
+        vec![src_file.to_str().unwrap().to_string()],
+        temp_dir
+            .path()
+            .join("des.txt")
+            .to_str()
+            .unwrap()
+            .to_string(),
+    )
+    .options(CopyOptions {
+        dry_run: true,
+        ..Default::default()
+    })
+    .pre_action(counter.clone())
+    .run()
+    .unwrap();
+
+    let plan = serde_json::to_value(report.plan.unwrap()).unwrap();
+    assert_eq!(plan["entries"][0]["action"], "copy");
+    assert_eq!(counter.0.get(), 0);
+    assert!(!temp_dir.path().join("des.txt").exists());
+}
+
+#[test]
+fn test_copy_job_missing_source() {
+    let temp_dir = tempdir().unwrap();
+
+    let ret = CopyJob::new(
+        vec![temp_dir
+            .path()
+            .join("missing")
+            .to_str()
+            .unwrap()
+            .to_string()],
+        temp_dir.path().join("des").to_str().unwrap().to_string(),
+    )
+    .options(CopyOptions {
+        mute: true,
+        ..Default::default()
+    })
+    .run();
+
+    assert!(matches!(
+        ret,
+        Err(CopyError::NotFound {
+            stage: Stage::Setup,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_failed_destination_is_reported() {
+    let temp_dir = tempdir().unwrap();
+    let src_file = temp_dir.path().join("file1.txt");
+    let des_file = temp_dir.path().join("copy1.txt");
+    let blocked = temp_dir.path().join("blocked");
+    fs::write(&src_file, "Hello, world!").unwrap();
+    fs::write(&blocked, "").unwrap();
+
+    let report = CopyJob::new(
+        vec![src_file.to_str().unwrap().to_string()],
+        blocked.join("copy.txt").to_str().unwrap().to_string(),
+    )
+    .options(CopyOptions {
+        mute: true,
+        to: vec![des_file.to_str().unwrap().to_string()],
+        ..Default::default()
+    })
+    .run()
+    .unwrap();
+
+    assert_eq!(report.copied, 1);
+    assert_eq!(report.written, 13);
+    assert_eq!(fs::read_to_string(&des_file).unwrap(), "Hello, world!");
+    assert!(matches!(
+        report.failed.as_slice(),
+        [CopyError::NotADirectory { .. }]
+    ));
+}
+
+// The source tree of the in-memory tests
+fn mem_tree(faults: Faults) -> MemStorage {
+    let storage = MemStorage::new();
+    storage.create_dir_all("/src/sub").unwrap();
+    storage.create_dir_all("/des").unwrap();
+    storage.write_file("/src/a.txt", b"Hello, world!").unwrap();
+    storage
+        .write_file("/src/sub/b.bin", &[7u8; 20_000])
+        .unwrap();
+    storage.write_file("/des/old.txt", b"old").unwrap();
+    storage.set_faults(faults);
+    storage
+}
+
+#[test]
+fn test_copy_job_in_memory() {
+    let storage = mem_tree(Faults {
+        short_reads: Some(1000),
+        ..Default::default()
+    });
+    let recorder = Rc::new(Recorder::default());
+
+    let report = CopyJob::new(vec!["/src".to_string()], "/des/copy".to_string())
+        .options(CopyOptions {
+            recursive: true,
+            mute: true,
+            backup: Some("numbered".to_string()),
+            ..Default::default()
+        })
+        .storage(Rc::new(storage.clone()))
+        .in_copy_action(recorder.clone())
+        .run()
+        .unwrap();
+
+    assert_eq!(report.copied, 2);
+    assert_eq!(report.bytes, 20_013);
+    assert_eq!(recorder.length.get(), 20_013);
+    assert_eq!(
+        storage.read_file("/des/copy/a.txt").unwrap(),
+        b"Hello, world!"
+    );
+    assert_eq!(
+        storage.read_file("/des/copy/sub/b.bin").unwrap(),
+        [7u8; 20_000]
+    );
+
+    // a second run backs the copies up before replacing them
+    CopyJob::new(
+        vec!["/src/a.txt".to_string()],
+        "/des/copy/a.txt".to_string(),
+    )
+    .options(CopyOptions {
+        mute: true,
+        backup: Some("numbered".to_string()),
+        ..Default::default()
+    })
+    .storage(Rc::new(storage.clone()))
+    .run()
+    .unwrap();
+    assert_eq!(
+        storage.read_file("/des/copy/a.txt.~1~").unwrap(),
+        b"Hello, world!"
+    );
+}
+
+#[test]
+fn test_mirror_in_memory() {
+    let storage = mem_tree(Faults::default());
+    storage.create_dir_all("/des/src/sub").unwrap();
+    storage.write_file("/des/src/stale.txt", b"Stale").unwrap();
+
+    let report = CopyJob::new(vec!["/src".to_string()], "/des".to_string())
+        .options(CopyOptions {
+            recursive: true,
+            mirror: true,
+            mute: true,
+            ..Default::default()
+        })
+        .storage(Rc::new(storage.clone()))
+        .run()
+        .unwrap();
+
+    assert_eq!(report.deleted, 1);
+    let mut names = storage.list("/des/src").unwrap();
+    names.sort();
+    assert_eq!(names, ["a.txt", "sub"]);
+}
+
+#[test]
+fn test_mirror_does_not_follow_symlinks() {
+    let storage = mem_tree(Faults::default());
+    storage.create_dir_all("/des/src").unwrap();
+    storage.create_dir_all("/outside").unwrap();
+    storage.write_file("/outside/keep.txt", b"Keep").unwrap();
+    storage.symlink("/outside", "/des/src/sub").unwrap();
+
+    CopyJob::new(vec!["/src".to_string()], "/des".to_string())
+        .options(CopyOptions {
+            recursive: true,
+            mirror: true,
+            mute: true,
+            ..Default::default()
+        })
+        .storage(Rc::new(storage.clone()))
+        .run()
+        .unwrap();
+
+    assert_eq!(storage.read_file("/outside/keep.txt").unwrap(), b"Keep");
+}
+
+#[test]
+fn test_archive_and_remote_need_local_storage() {
+    for des in ["/des/src.tar", "host:/des"] {
+        let storage = mem_tree(Faults::default());
+        let ret = CopyJob::new(vec!["/src".to_string()], des.to_string())
+            .options(CopyOptions {
+                recursive: true,
+                mute: true,
+                ..Default::default()
+            })
+            .storage(Rc::new(storage.clone()))
+            .run();
+
+        assert!(matches!(ret, Err(CopyError::InvalidArgument(_))));
+        assert!(storage.lstat("/des/src.tar").is_err());
+    }
+}
+
+#[test]
+fn test_checksum_cache_needs_local_storage() {
+    let storage = mem_tree(Faults::default());
+    let ret = CopyJob::new(vec!["/src".to_string()], "/des/copy".to_string())
+        .options(CopyOptions {
+            recursive: true,
+            skip_if: Some("checksum".to_string()),
+            checksum_cache: true,
+            mute: true,
+            ..Default::default()
+        })
+        .storage(Rc::new(storage.clone()))
+        .run();
+
+    assert!(matches!(ret, Err(CopyError::InvalidArgument(_))));
+    assert!(storage.lstat("/des/copy").is_err());
+}
+
+#[test]
+fn test_full_storage_is_reported() {
+    let storage = mem_tree(Faults {
+        capacity: Some(10_000),
+        ..Default::default()
+    });
+
+    let ret = CopyJob::new(vec!["/src".to_string()], "/des/copy".to_string())
+        .options(CopyOptions {
+            recursive: true,
+            mute: true,
+            ..Default::default()
+        })
+        .storage(Rc::new(storage))
+        .run();
+
+    assert!(matches!(ret, Err(CopyError::NoSpace { .. })));
+}
+
+#[test]
+fn test_free_space_skips_kept_destinations() {
+    let storage = mem_tree(Faults::default());
+    storage.create_dir_all("/des/src/sub").unwrap();
+    storage.write_file("/des/src/a.txt", b"Hello").unwrap();
+    storage.write_file("/des/src/sub/b.bin", b"b").unwrap();
+    storage.set_faults(Faults {
+        capacity: Some(20_100),
+        ..Default::default()
+    });
+
+    let job = |no_clobber| {
+        CopyJob::new(vec!["/src".to_string()], "/des".to_string())
+            .options(CopyOptions {
+                recursive: true,
+                no_clobber,
+                mute: true,
+                ..Default::default()
+            })
+            .storage(Rc::new(storage.clone()))
+    };
+    // -n keeps the short destinations, nothing has to fit
+    assert!(matches!(job(false).run(), Err(CopyError::NoSpace { .. })));
+    job(true).run().unwrap();
+    assert_eq!(storage.read_file("/des/src/sub/b.bin").unwrap(), b"b");
+}
+
+#[test]
+fn test_failed_write_is_reported() {
+    let storage = mem_tree(Faults {
+        fail_write: Some(1),
+        ..Default::default()
+    });
+
+    let ret = CopyJob::new(vec!["/src/sub/b.bin".to_string()], "/des/b.bin".to_string())
+        .options(CopyOptions {
+            mute: true,
+            ..Default::default()
+        })
+        .storage(Rc::new(storage.clone()))
+        .run();
+
+    assert!(matches!(
+        ret,
+        Err(CopyError::Io {
+            stage: Stage::Copy,
+            ..
+        })
+    ));
+    assert_eq!(storage.writes(), 1);
+}
+
+// Renames fail across "devices" and the copies may come out corrupted, to
+// drive --move through its copy and verify path
+struct CrossDevice {
+    inner: MemStorage,
+    corrupt: bool,
+}
+
+// Flips the first byte written through it
+struct Corrupting(Handle, bool);
+
+impl Read for Corrupting {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for Corrupting {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.1 || buf.is_empty() {
+            return self.0.write(buf);
+        }
+        self.1 = true;
+        let mut flipped = buf.to_vec();
+        flipped[0] ^= 0xff;
+        self.0.write(&flipped)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Seek for Corrupting {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+impl FileHandle for Corrupting {
+    fn stat(&self) -> io::Result<Stat> {
+        self.0.stat()
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.0.set_len(len)
+    }
+}
+
+impl Storage for CrossDevice {
+    fn stat(&self, path: &str) -> io::Result<Stat> {
+        self.inner.stat(path)
+    }
+    fn lstat(&self, path: &str) -> io::Result<Stat> {
+        self.inner.lstat(path)
+    }
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        self.inner.list(path)
+    }
+    fn open_read(&self, path: &str) -> io::Result<Handle> {
+        self.inner.open_read(path)
+    }
+    fn open_write(&self, path: &str) -> io::Result<Handle> {
+        let handle = self.inner.open_write(path)?;
+        Ok(Box::new(Corrupting(handle, !self.corrupt)))
+    }
+    fn open_update(&self, path: &str) -> io::Result<Handle> {
+        self.inner.open_update(path)
+    }
+    fn mkdir(&self, path: &str) -> io::Result<()> {
+        self.inner.mkdir(path)
+    }
+    fn symlink(&self, target: &str, path: &str) -> io::Result<()> {
+        self.inner.symlink(target, path)
+    }
+    fn read_link(&self, path: &str) -> io::Result<String> {
+        self.inner.read_link(path)
+    }
+    fn set_attrs(&self, path: &str, attrs: &Attrs) -> io::Result<()> {
+        self.inner.set_attrs(path, attrs)
+    }
+    fn rename(&self, _: &str, _: &str) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(nix::libc::EXDEV))
+    }
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        self.inner.remove_file(path)
+    }
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        self.inner.remove_dir(path)
+    }
+}
+
+fn move_job(
+    storage: Rc<dyn Storage>,
+    recorder: Rc<Recorder>,
+) -> progressbar_cp::error::Result<CopyReport> {
+    CopyJob::new(vec!["/src".to_string()], "/des".to_string())
+        .options(CopyOptions {
+            move_sources: true,
+            mute: true,
+            ..Default::default()
+        })
+        .storage(storage)
+        .post_action(recorder)
+        .run()
+}
+
+#[test]
+fn test_move_renames_in_memory() {
+    let storage = mem_tree(Faults::default());
+    let recorder = Rc::new(Recorder::default());
+
+    let report = move_job(Rc::new(storage.clone()), recorder.clone()).unwrap();
+
+    // the renamed entries go through the post actions like the others
+    assert_eq!(report.moved, 4);
+    assert_eq!(recorder.copied.borrow().len(), 4);
+    assert_eq!(
+        storage.read_file("/des/src/a.txt").unwrap(),
+        b"Hello, world!"
+    );
+    assert!(storage.lstat("/src").is_err());
+}
+
+#[test]
+fn test_move_keeps_excluded_entries() {
+    let storage = mem_tree(Faults::default());
+
+    let report = CopyJob::new(vec!["/src".to_string()], "/des".to_string())
+        .options(CopyOptions {
+            move_sources: true,
+            exclude: vec!["*.bin".to_string()],
+            mute: true,
+            ..Default::default()
+        })
+        .storage(Rc::new(storage.clone()))
+        .run()
+        .unwrap();
+
+    // the directories are created and the files moved one by one
+    assert_eq!(report.moved, 1);
+    assert_eq!(
+        storage.read_file("/des/src/a.txt").unwrap(),
+        b"Hello, world!"
+    );
+    assert!(storage.lstat("/des/src/sub/b.bin").is_err());
+    assert!(storage.lstat("/src/a.txt").is_err());
+    assert_eq!(storage.read_file("/src/sub/b.bin").unwrap(), [7u8; 20_000]);
+}
+
+#[test]
+fn test_move_verifies_copies() {
+    let storage = mem_tree(Faults::default());
+    let cross = CrossDevice {
+        inner: storage.clone(),
+        corrupt: false,
+    };
+    let report = move_job(Rc::new(cross), Rc::new(Recorder::default())).unwrap();
+    assert_eq!(report.copied, 2);
+    assert!(storage.lstat("/src").is_err());
+
+    // a copy with the right size but other bytes keeps the source
+    let storage = mem_tree(Faults::default());
+    let cross = CrossDevice {
+        inner: storage.clone(),
+        corrupt: true,
+    };
+    let ret = move_job(Rc::new(cross), Rc::new(Recorder::default()));
+    assert!(matches!(ret, Err(CopyError::Mismatch { .. })));
+    assert_eq!(storage.read_file("/src/a.txt").unwrap(), b"Hello, world!");
+}
@@ -0,0 +1,320 @@
+use assert_cmd::Command;
+use md5::{Digest, Md5};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::sync::{Arc, Mutex};
+use tempfile::tempdir;
+use tiny_http::{Header, Method, Response, Server};
+
+const ACCESS_KEY: &str = "test-key";
+
+// The objects and the unfinished uploads of an S3 stand-in
+#[derive(Default)]
+struct Store {
+    objects: BTreeMap<String, (Vec<u8>, String)>,
+    uploads: HashMap<String, BTreeMap<u32, Vec<u8>>>,
+    parts_uploaded: usize,
+    // answers the uploads with a wrong ETag
+    bad_etag: bool,
+}
+
+// Serves the few S3 calls pbcp makes, with path-style buckets
+fn start_stand_in(bad_etag: bool) -> (String, Arc<Mutex<Store>>) {
+    let server = Server::http("127.0.0.1:0").unwrap();
+    let port = server.server_addr().to_ip().unwrap().port();
+    let store = Arc::new(Mutex::new(Store {
+        bad_etag,
+        ..Default::default()
+    }));
+
+    let shared = store.clone();
+    std::thread::spawn(move || {
+        for mut request in server.incoming_requests() {
+            let signed = request.headers().iter().any(|h| {
+                h.field.equiv("Authorization")
+                    && h.value
+                        .as_str()
+                        .starts_with(&format!("AWS4-HMAC-SHA256 Credential={}/", ACCESS_KEY))
+            });
+            if !signed {
+                request.respond(Response::empty(403)).unwrap();
+                continue;
+            }
+
+            let url = request.url().to_string();
+            let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+            let query: HashMap<String, String> = query
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    (decode(name), decode(value))
+                })
+                .collect();
+            let path = decode(path);
+            let mut body = Vec::new();
+            request.as_reader().read_to_end(&mut body).unwrap();
+
+            let mut store = shared.lock().unwrap();
+            let response = handle(&mut store, request.method(), &path, &query, body);
+            request.respond(response).unwrap();
+        }
+    });
+
+    (format!("http://127.0.0.1:{}", port), store)
+}
+
+fn handle(
+    store: &mut Store,
+    method: &Method,
+    path: &str,
+    query: &HashMap<String, String>,
+    body: Vec<u8>,
+) -> Response<std::io::Cursor<Vec<u8>>> {
+    let with_etag = |response: Response<_>, etag: &str| {
+        response.with_header(Header::from_bytes("ETag", format!("\"{}\"", etag)).unwrap())
+    };
+    let (_, key) = path[1..].split_once('/').unwrap_or((&path[1..], ""));
+    let md5_hex = |data: &[u8]| hex::encode(Md5::digest(data));
+
+    match (method, query.get("uploadId")) {
+        (Method::Put, Some(id)) => {
+            let number: u32 = query["partNumber"].parse().unwrap();
+            let etag = if store.bad_etag {
+                "0".repeat(32)
+            } else {
+                md5_hex(&body)
+            };
+            store.uploads.get_mut(id).unwrap().insert(number, body);
+            store.parts_uploaded += 1;
+            with_etag(Response::from_data(Vec::new()), &etag)
+        }
+        (Method::Post, Some(id)) => {
+            let parts = store.uploads.remove(id).unwrap();
+            let mut digests = Vec::new();
+            let mut data = Vec::new();
+            for part in parts.values() {
+                digests.extend(Md5::digest(part));
+                data.extend(part);
+            }
+            let etag = format!("{}-{}", md5_hex(&digests), parts.len());
+            store.objects.insert(key.to_string(), (data, etag.clone()));
+            Response::from_data(format!(
+                "<CompleteMultipartUploadResult><Key>{}</Key><ETag>&quot;{}&quot;</ETag></CompleteMultipartUploadResult>",
+                key, etag
+            ).into_bytes())
+        }
+        (Method::Delete, Some(id)) => {
+            store.uploads.remove(id);
+            Response::from_data(Vec::new()).with_status_code(204)
+        }
+        (Method::Post, None) if query.contains_key("uploads") => {
+            let id = format!("upload-{}", store.uploads.len() + store.objects.len());
+            store.uploads.insert(id.clone(), BTreeMap::new());
+            Response::from_data(
+                format!(
+                    "<InitiateMultipartUploadResult><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+                    id
+                )
+                .into_bytes(),
+            )
+        }
+        (Method::Put, None) => {
+            let etag = if store.bad_etag {
+                "0".repeat(32)
+            } else {
+                md5_hex(&body)
+            };
+            store.objects.insert(key.to_string(), (body, etag.clone()));
+            with_etag(Response::from_data(Vec::new()), &etag)
+        }
+        (Method::Get, None) if query.contains_key("list-type") => {
+            // two keys a page, the token is the index to go on from
+            let prefix = query.get("prefix").cloned().unwrap_or_default();
+            let start: usize = query
+                .get("continuation-token")
+                .map(|token| token.parse().unwrap())
+                .unwrap_or(0);
+            let keys: Vec<_> = store
+                .objects
+                .iter()
+                .filter(|(key, _)| key.starts_with(&prefix))
+                .collect();
+            let mut xml = String::from("<ListBucketResult>");
+            for (key, (data, etag)) in keys.iter().skip(start).take(2) {
+                xml.push_str(&format!(
+                    "<Contents><Key>{}</Key><Size>{}</Size><ETag>&quot;{}&quot;</ETag></Contents>",
+                    key,
+                    data.len(),
+                    etag
+                ));
+            }
+            if start + 2 < keys.len() {
+                xml.push_str(&format!(
+                    "<IsTruncated>true</IsTruncated><NextContinuationToken>{}</NextContinuationToken>",
+                    start + 2
+                ));
+            } else {
+                xml.push_str("<IsTruncated>false</IsTruncated>");
+            }
+            xml.push_str("</ListBucketResult>");
+            Response::from_data(xml.into_bytes())
+        }
+        (Method::Get, None) | (Method::Head, None) => match store.objects.get(key) {
+            Some((data, etag)) => with_etag(Response::from_data(data.clone()), etag),
+            None => Response::from_data(b"<Error><Code>NoSuchKey</Code></Error>".to_vec())
+                .with_status_code(404),
+        },
+        _ => Response::from_data(Vec::new()).with_status_code(400),
+    }
+}
+
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            out.push(u8::from_str_radix(&s[i + 1..i + 3], 16).unwrap());
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).unwrap()
+}
+
+fn pbcp(endpoint: &str) -> Command {
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.env("AWS_ENDPOINT_URL", endpoint)
+        .env("AWS_ACCESS_KEY_ID", ACCESS_KEY)
+        .env("AWS_SECRET_ACCESS_KEY", "test-secret")
+        .env("AWS_REGION", "us-east-1");
+    cmd
+}
+
+#[test]
+fn test_s3_round_trip() {
+    let (endpoint, store) = start_stand_in(false);
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("tree");
+    let des_dir = temp_dir.path().join("back");
+    fs::create_dir_all(src_dir.join("sub")).unwrap();
+    // three parts of 5 MiB at most
+    let content: Vec<u8> = (0..12_000_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(src_dir.join("app.bin"), &content).unwrap();
+    fs::write(src_dir.join("sub/notes.txt"), "Hello, world!").unwrap();
+    fs::write(src_dir.join("sub/todo.txt"), "More").unwrap();
+    fs::write(src_dir.join("skip.tmp"), "Skipped").unwrap();
+
+    let mut cmd = pbcp(&endpoint);
+    cmd.arg("-r")
+        .arg("--s3-part-size")
+        .arg("5M")
+        .arg("--s3-concurrency")
+        .arg("2")
+        .arg("--exclude")
+        .arg("*.tmp")
+        .arg(&src_dir)
+        .arg("--")
+        .arg("s3://bucket/backup/");
+    cmd.assert().success();
+
+    {
+        let store = store.lock().unwrap();
+        let keys: Vec<_> = store.objects.keys().cloned().collect();
+        assert_eq!(
+            keys,
+            [
+                "backup/tree/app.bin",
+                "backup/tree/sub/notes.txt",
+                "backup/tree/sub/todo.txt"
+            ]
+        );
+        assert_eq!(store.parts_uploaded, 3);
+        assert!(store.uploads.is_empty());
+    }
+
+    // the listing takes two pages
+    let mut cmd = pbcp(&endpoint);
+    cmd.arg("-r")
+        .arg("--s3-part-size")
+        .arg("5M")
+        .arg("s3://bucket/backup/tree")
+        .arg("--")
+        .arg(&des_dir);
+    cmd.assert().success();
+
+    assert_eq!(fs::read(des_dir.join("app.bin")).unwrap(), content);
+    assert_eq!(
+        fs::read_to_string(des_dir.join("sub/notes.txt")).unwrap(),
+        "Hello, world!"
+    );
+    assert_eq!(
+        fs::read_to_string(des_dir.join("sub/todo.txt")).unwrap(),
+        "More"
+    );
+
+    // a single object
+    let mut cmd = pbcp(&endpoint);
+    cmd.arg("s3://bucket/backup/tree/sub/notes.txt")
+        .arg("--")
+        .arg(temp_dir.path());
+    cmd.assert().success();
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("notes.txt")).unwrap(),
+        "Hello, world!"
+    );
+}
+
+#[test]
+fn test_s3_etag_mismatch_fails() {
+    let (endpoint, store) = start_stand_in(true);
+    let temp_dir = tempdir().unwrap();
+    let src_file = temp_dir.path().join("file1.txt");
+    fs::write(&src_file, "Hello, world!").unwrap();
+
+    let mut cmd = pbcp(&endpoint);
+    cmd.arg(&src_file).arg("--").arg("s3://bucket/file1.txt");
+    cmd.assert().code(8);
+
+    // a failed multipart upload is aborted
+    let big_file = temp_dir.path().join("big.bin");
+    fs::write(&big_file, vec![7u8; 6 << 20]).unwrap();
+    let mut cmd = pbcp(&endpoint);
+    cmd.arg("--s3-part-size")
+        .arg("5M")
+        .arg(&big_file)
+        .arg("--")
+        .arg("s3://bucket/big.bin");
+    cmd.assert().code(8);
+    assert!(store.lock().unwrap().uploads.is_empty());
+}
+
+#[test]
+fn test_s3_options_checked() {
+    let temp_dir = tempdir().unwrap();
+    let src_file = temp_dir.path().join("file1.txt");
+    fs::write(&src_file, "Hello, world!").unwrap();
+
+    let mut cmd = pbcp("http://127.0.0.1:1");
+    cmd.arg("--s3-part-size")
+        .arg("1M")
+        .arg(&src_file)
+        .arg("--")
+        .arg("s3://bucket/file1.txt");
+    cmd.assert().code(2);
+
+    let mut cmd = pbcp("http://127.0.0.1:1");
+    cmd.arg(&src_file).arg("--").arg("s3://");
+    cmd.assert().code(2);
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.env_remove("AWS_ACCESS_KEY_ID")
+        .env_remove("AWS_SECRET_ACCESS_KEY")
+        .arg(&src_file)
+        .arg("--")
+        .arg("s3://bucket/file1.txt");
+    cmd.assert().code(2);
+}
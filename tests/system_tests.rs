@@ -1,7 +1,6 @@
 use assert_cmd::Command;
-use filetime;
 use std::fs;
-use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use tempfile::tempdir;
 
 #[test]
@@ -274,7 +273,7 @@ fn test_archive_option_with_symlink() {
 
     fs::write(&src_file, "Main content").unwrap();
     // Create a symlink to the file, using the relative path
-    std::os::unix::fs::symlink(&src_file.file_name().unwrap(), &link_file).unwrap();
+    std::os::unix::fs::symlink(src_file.file_name().unwrap(), &link_file).unwrap();
 
     // Create destination directory
     let des_dir = temp_dir.path().join("des_dir");
@@ -315,7 +314,7 @@ fn test_archive_option_with_symlink_dir() {
 
     // Create a symlink to the directory, using the relative path
     let link_dir = temp_dir.path().join("link_dir");
-    std::os::unix::fs::symlink(&src_dir.file_name().unwrap(), &link_dir).unwrap();
+    std::os::unix::fs::symlink(src_dir.file_name().unwrap(), &link_dir).unwrap();
 
     // Create destination directory
     let des_dir = temp_dir.path().join("des_dir");
@@ -343,3 +342,1408 @@ fn test_archive_option_with_symlink_dir() {
     );
     assert_eq!(fs::read_to_string(&des_link_file).unwrap(), "Main content");
 }
+
+#[test]
+fn test_recursive_copy_with_fifo() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let des_dir = temp_dir.path().join("des");
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&des_dir).unwrap();
+    fs::write(src_dir.join("file1.txt"), "Hello, world!").unwrap();
+    nix::unistd::mkfifo(
+        &src_dir.join("fifo"),
+        nix::sys::stat::Mode::from_bits_truncate(0o640),
+    )
+    .unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-a").arg(&src_dir).arg("--").arg(&des_dir);
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    let des_fifo = des_dir.join("src/fifo");
+    let des_metadata = fs::symlink_metadata(&des_fifo).unwrap();
+    assert!(des_metadata.file_type().is_fifo());
+    assert_eq!(des_metadata.mode() & 0o777, 0o640);
+    assert_eq!(
+        fs::read_to_string(des_dir.join("src/file1.txt")).unwrap(),
+        "Hello, world!"
+    );
+}
+
+#[test]
+fn test_recursive_copy_skip_special() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let des_dir = temp_dir.path().join("des");
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&des_dir).unwrap();
+    fs::write(src_dir.join("file1.txt"), "Hello, world!").unwrap();
+    nix::unistd::mkfifo(
+        &src_dir.join("fifo"),
+        nix::sys::stat::Mode::from_bits_truncate(0o644),
+    )
+    .unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-r")
+        .arg("--skip-special")
+        .arg(&src_dir)
+        .arg("--")
+        .arg(&des_dir);
+    cmd.timeout(std::time::Duration::from_secs(10));
+    cmd.assert().success();
+
+    assert!(!des_dir.join("src/fifo").exists());
+    assert!(des_dir.join("src/file1.txt").exists());
+}
+
+#[test]
+fn test_backup_simple() {
+    let temp_dir = tempdir().unwrap();
+    let src_file = temp_dir.path().join("src.txt");
+    let des_file = temp_dir.path().join("des.txt");
+    fs::write(&src_file, "New content").unwrap();
+    fs::write(&des_file, "Old content").unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--backup=simple")
+        .arg(&src_file)
+        .arg("--")
+        .arg(&des_file);
+    cmd.assert().success();
+
+    assert_eq!(fs::read_to_string(&des_file).unwrap(), "New content");
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("des.txt~")).unwrap(),
+        "Old content"
+    );
+
+    // A custom suffix implies --backup
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--suffix=.bak")
+        .arg(&src_file)
+        .arg("--")
+        .arg(&des_file);
+    cmd.assert().success();
+
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("des.txt.bak")).unwrap(),
+        "New content"
+    );
+}
+
+#[test]
+fn test_backup_numbered() {
+    let temp_dir = tempdir().unwrap();
+    let src_file = temp_dir.path().join("src.txt");
+    let des_file = temp_dir.path().join("des.txt");
+
+    for content in ["First", "Second", "Third"] {
+        fs::write(&src_file, content).unwrap();
+        let mut cmd = Command::cargo_bin("pbcp").unwrap();
+        cmd.arg("--backup=numbered")
+            .arg(&src_file)
+            .arg("--")
+            .arg(&des_file);
+        cmd.assert().success();
+    }
+
+    assert_eq!(fs::read_to_string(&des_file).unwrap(), "Third");
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("des.txt.~1~")).unwrap(),
+        "First"
+    );
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("des.txt.~2~")).unwrap(),
+        "Second"
+    );
+
+    // "existing" keeps numbering once numbered backups exist
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--backup").arg(&src_file).arg("--").arg(&des_file);
+    cmd.assert().success();
+    assert!(temp_dir.path().join("des.txt.~3~").exists());
+    assert!(!temp_dir.path().join("des.txt~").exists());
+}
+
+#[test]
+fn test_backup_skipped_by_update() {
+    let temp_dir = tempdir().unwrap();
+    let src_file = temp_dir.path().join("src.txt");
+    let des_file = temp_dir.path().join("des.txt");
+    fs::write(&src_file, "New content").unwrap();
+    fs::write(&des_file, "Old content").unwrap();
+
+    let mtime = filetime::FileTime::from_system_time(
+        std::time::SystemTime::now() - std::time::Duration::from_secs(10),
+    );
+    filetime::set_file_mtime(&src_file, mtime).unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-u")
+        .arg("--backup=simple")
+        .arg(&src_file)
+        .arg("--")
+        .arg(&des_file);
+    cmd.assert().success();
+
+    assert_eq!(fs::read_to_string(&des_file).unwrap(), "Old content");
+    assert!(!temp_dir.path().join("des.txt~").exists());
+}
+
+#[test]
+fn test_no_clobber() {
+    let temp_dir = tempdir().unwrap();
+    let src_file = temp_dir.path().join("src.txt");
+    let des_file = temp_dir.path().join("des.txt");
+    fs::write(&src_file, "New content").unwrap();
+    fs::write(&des_file, "Old content").unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-n").arg(&src_file).arg("--").arg(&des_file);
+    cmd.assert().success();
+
+    assert_eq!(fs::read_to_string(&des_file).unwrap(), "Old content");
+}
+
+#[test]
+fn test_interactive() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let des_dir = temp_dir.path().join("des");
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(des_dir.join("src")).unwrap();
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        fs::write(src_dir.join(name), "New content").unwrap();
+        fs::write(des_dir.join("src").join(name), "Old content").unwrap();
+    }
+
+    // Answer "no" once, then "yes" once
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-i")
+        .arg(src_dir.join("a.txt"))
+        .arg(src_dir.join("b.txt"))
+        .arg("--")
+        .arg(des_dir.join("src"))
+        .write_stdin("n\ny\n");
+    cmd.assert().success();
+
+    assert_eq!(
+        fs::read_to_string(des_dir.join("src/a.txt")).unwrap(),
+        "Old content"
+    );
+    assert_eq!(
+        fs::read_to_string(des_dir.join("src/b.txt")).unwrap(),
+        "New content"
+    );
+
+    // "all" answers every following prompt
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-r")
+        .arg("-i")
+        .arg(&src_dir)
+        .arg("--")
+        .arg(&des_dir)
+        .write_stdin("all\n");
+    cmd.assert().success();
+
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        assert_eq!(
+            fs::read_to_string(des_dir.join("src").join(name)).unwrap(),
+            "New content"
+        );
+    }
+}
+
+#[test]
+fn test_force_readonly_destination() {
+    let temp_dir = tempdir().unwrap();
+    let src_file = temp_dir.path().join("src.txt");
+    let des_file = temp_dir.path().join("des.txt");
+    fs::write(&src_file, "New content").unwrap();
+    fs::write(&des_file, "Old content").unwrap();
+
+    let mut perms = fs::metadata(&des_file).unwrap().permissions();
+    perms.set_readonly(true);
+    fs::set_permissions(&des_file, perms).unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-f").arg(&src_file).arg("--").arg(&des_file);
+    cmd.assert().success();
+
+    assert_eq!(fs::read_to_string(&des_file).unwrap(), "New content");
+}
+
+fn make_move_tree(root: &std::path::Path) -> std::path::PathBuf {
+    let src_dir = root.join("src");
+    fs::create_dir_all(src_dir.join("sub")).unwrap();
+    fs::write(src_dir.join("file1.txt"), "Hello, world!").unwrap();
+    fs::write(src_dir.join("sub/file2.txt"), "Sub content").unwrap();
+    std::os::unix::fs::symlink("file1.txt", src_dir.join("link.txt")).unwrap();
+    src_dir
+}
+
+fn assert_moved_tree(src_dir: &std::path::Path, des: &std::path::Path) {
+    assert!(!src_dir.exists());
+    assert_eq!(
+        fs::read_to_string(des.join("file1.txt")).unwrap(),
+        "Hello, world!"
+    );
+    assert_eq!(
+        fs::read_to_string(des.join("sub/file2.txt")).unwrap(),
+        "Sub content"
+    );
+    assert_eq!(
+        fs::read_link(des.join("link.txt")).unwrap(),
+        std::path::Path::new("file1.txt")
+    );
+}
+
+#[test]
+fn test_move_rename() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = make_move_tree(temp_dir.path());
+    let des_dir = temp_dir.path().join("des");
+    fs::create_dir_all(&des_dir).unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--move").arg(&src_dir).arg("--").arg(&des_dir);
+    cmd.assert().success();
+
+    assert_moved_tree(&src_dir, &des_dir.join("src"));
+}
+
+#[test]
+fn test_move_across_filesystems() {
+    // /dev/shm is usually a tmpfs, so rename(2) fails with EXDEV
+    if !std::path::Path::new("/dev/shm").is_dir() {
+        return;
+    }
+    let src_root = tempfile::tempdir_in("/dev/shm").unwrap();
+    let src_dir = make_move_tree(src_root.path());
+    let des_root = tempfile::tempdir_in(".").unwrap();
+    let des_dir = des_root.path().join("des");
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--move").arg(&src_dir).arg("--").arg(&des_dir);
+    cmd.assert().success();
+
+    assert_moved_tree(&src_dir, &des_dir);
+}
+
+#[test]
+fn test_move_keeps_skipped_sources() {
+    let temp_dir = tempdir().unwrap();
+    let src_file = temp_dir.path().join("src.txt");
+    let des_file = temp_dir.path().join("des.txt");
+    fs::write(&src_file, "New content").unwrap();
+    fs::write(&des_file, "Old content").unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--move")
+        .arg("-n")
+        .arg(&src_file)
+        .arg("--")
+        .arg(&des_file);
+    cmd.assert().success();
+
+    assert!(src_file.exists());
+    assert_eq!(fs::read_to_string(&des_file).unwrap(), "Old content");
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--move").arg(&src_file).arg("--").arg(&des_file);
+    cmd.assert().success();
+
+    assert!(!src_file.exists());
+    assert_eq!(fs::read_to_string(&des_file).unwrap(), "New content");
+}
+
+fn make_mirror_trees(root: &std::path::Path) -> (std::path::PathBuf, std::path::PathBuf) {
+    let src_dir = root.join("src");
+    let des_dir = root.join("des");
+    fs::create_dir_all(src_dir.join("sub")).unwrap();
+    fs::write(src_dir.join("file1.txt"), "Hello, world!").unwrap();
+    fs::write(src_dir.join("sub/file2.txt"), "Sub content").unwrap();
+    fs::write(src_dir.join("skip.log"), "Log content").unwrap();
+
+    fs::create_dir_all(des_dir.join("src/sub")).unwrap();
+    fs::create_dir_all(des_dir.join("src/stale_dir/nested")).unwrap();
+    fs::write(des_dir.join("src/stale.txt"), "Stale").unwrap();
+    fs::write(des_dir.join("src/sub/stale2.txt"), "Stale").unwrap();
+    fs::write(des_dir.join("src/stale_dir/nested/x.txt"), "Stale").unwrap();
+    fs::write(des_dir.join("src/keep.log"), "Log content").unwrap();
+    (src_dir, des_dir)
+}
+
+#[test]
+fn test_mirror() {
+    let temp_dir = tempdir().unwrap();
+    let (src_dir, des_dir) = make_mirror_trees(temp_dir.path());
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--mirror")
+        .arg("--exclude=*.log")
+        .arg(&src_dir)
+        .arg("--")
+        .arg(&des_dir);
+    cmd.assert().success();
+
+    let des_src = des_dir.join("src");
+    assert!(!des_src.join("stale.txt").exists());
+    assert!(!des_src.join("sub/stale2.txt").exists());
+    assert!(!des_src.join("stale_dir").exists());
+    assert_eq!(
+        fs::read_to_string(des_src.join("sub/file2.txt")).unwrap(),
+        "Sub content"
+    );
+    // excluded entries are neither copied nor deleted
+    assert!(!des_src.join("skip.log").exists());
+    assert!(des_src.join("keep.log").exists());
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--mirror")
+        .arg("--exclude=*.log")
+        .arg("--delete-excluded")
+        .arg(&src_dir)
+        .arg("--")
+        .arg(&des_dir);
+    cmd.assert().success();
+
+    assert!(!des_src.join("keep.log").exists());
+    assert!(des_src.join("file1.txt").exists());
+}
+
+#[test]
+fn test_mirror_dry_run() {
+    let temp_dir = tempdir().unwrap();
+    let (src_dir, des_dir) = make_mirror_trees(temp_dir.path());
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--mirror")
+        .arg("--dry-run")
+        .arg(&src_dir)
+        .arg("--")
+        .arg(&des_dir);
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let output = String::from_utf8(output).unwrap();
+
+    let des_src = des_dir.join("src");
+    for stale in ["stale.txt", "stale_dir", "sub/stale2.txt", "keep.log"] {
+        let path = des_src.join(stale).display().to_string();
+        assert!(
+            output
+                .lines()
+                .any(|l| l.starts_with("delete ") && l.ends_with(&path)),
+            "missing {}",
+            path
+        );
+    }
+    assert!(!output.contains("nested"));
+
+    // nothing is touched
+    assert!(des_src.join("stale.txt").exists());
+    assert!(!des_src.join("file1.txt").exists());
+}
+
+#[test]
+fn test_skip_if_size() {
+    let temp_dir = tempdir().unwrap();
+    let src_file = temp_dir.path().join("src.txt");
+    let des_file = temp_dir.path().join("des.txt");
+    fs::write(&src_file, "New content").unwrap();
+    fs::write(&des_file, "Old content").unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--skip-if=size")
+        .arg(&src_file)
+        .arg("--")
+        .arg(&des_file);
+    cmd.assert().success();
+    assert_eq!(fs::read_to_string(&des_file).unwrap(), "Old content");
+
+    fs::write(&src_file, "Newer content").unwrap();
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--skip-if=size")
+        .arg(&src_file)
+        .arg("--")
+        .arg(&des_file);
+    cmd.assert().success();
+    assert_eq!(fs::read_to_string(&des_file).unwrap(), "Newer content");
+}
+
+#[test]
+fn test_skip_if_size_mtime() {
+    let temp_dir = tempdir().unwrap();
+    let src_file = temp_dir.path().join("src.txt");
+    let des_file = temp_dir.path().join("des.txt");
+    fs::write(&src_file, "New content").unwrap();
+    fs::write(&des_file, "Old content").unwrap();
+
+    // An older source still differs in mtime, so it is copied
+    let mtime = filetime::FileTime::from_system_time(
+        std::time::SystemTime::now() - std::time::Duration::from_secs(10),
+    );
+    filetime::set_file_mtime(&src_file, mtime).unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--skip-if=size+mtime")
+        .arg(&src_file)
+        .arg("--")
+        .arg(&des_file);
+    cmd.assert().success();
+    assert_eq!(fs::read_to_string(&des_file).unwrap(), "New content");
+
+    fs::write(&des_file, "Old content").unwrap();
+    filetime::set_file_mtime(&des_file, mtime).unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--skip-if=size+mtime")
+        .arg(&src_file)
+        .arg("--")
+        .arg(&des_file);
+    cmd.assert().success();
+    assert_eq!(fs::read_to_string(&des_file).unwrap(), "Old content");
+}
+
+#[test]
+fn test_skip_if_checksum() {
+    let temp_dir = tempfile::tempdir_in(".").unwrap();
+    let src_file = temp_dir.path().join("src.txt");
+    let des_file = temp_dir.path().join("des.txt");
+    fs::write(&src_file, "Same content").unwrap();
+    fs::write(&des_file, "Same content").unwrap();
+
+    // The destination is older, but has the same content
+    let mtime = filetime::FileTime::from_system_time(
+        std::time::SystemTime::now() - std::time::Duration::from_secs(10),
+    );
+    filetime::set_file_mtime(&des_file, mtime).unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--skip-if=checksum")
+        .arg("--checksum-cache")
+        .arg(&src_file)
+        .arg("--")
+        .arg(&des_file);
+    cmd.assert().success();
+
+    let des_mtime =
+        filetime::FileTime::from_last_modification_time(&fs::metadata(&des_file).unwrap());
+    assert_eq!(des_mtime, mtime);
+    // The source is only read, the cache goes on the destination
+    assert!(matches!(xattr::get(&src_file, "user.pbcp.xxh3"), Ok(None)));
+    if let Ok(Some(cached)) = xattr::get(&des_file, "user.pbcp.xxh3") {
+        assert!(String::from_utf8(cached).unwrap().ends_with(&format!(
+            "{:032x}",
+            xxhash_rust::xxh3::xxh3_128(b"Same content")
+        )));
+    }
+
+    fs::write(&src_file, "Diff content").unwrap();
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--skip-if=checksum")
+        .arg("--checksum-cache")
+        .arg(&src_file)
+        .arg("--")
+        .arg(&des_file);
+    cmd.assert().success();
+    assert_eq!(fs::read_to_string(&des_file).unwrap(), "Diff content");
+}
+
+#[test]
+fn test_dry_run_into_closed_pipe() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    fs::create_dir(&src_dir).unwrap();
+    // more than a pipe buffer of plan
+    for i in 0..2_000 {
+        fs::write(src_dir.join(format!("file{:04}.txt", i)), "").unwrap();
+    }
+
+    for format in ["--dry-run", "--dry-run=json"] {
+        let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("pbcp"))
+            .arg("-r")
+            .arg(format)
+            .arg(&src_dir)
+            .arg("--")
+            .arg(temp_dir.path().join("des"))
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        // like `| head -c 1`
+        let mut first = [0u8; 1];
+        std::io::Read::read_exact(child.stdout.as_mut().unwrap(), &mut first).unwrap();
+        drop(child.stdout.take());
+
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        assert!(!String::from_utf8_lossy(&output.stderr).contains("panicked"));
+    }
+}
+
+#[test]
+fn test_dry_run_checksum_cache() {
+    let temp_dir = tempfile::tempdir_in(".").unwrap();
+    let src_file = temp_dir.path().join("src.txt");
+    let des_file = temp_dir.path().join("des.txt");
+    fs::write(&src_file, "Same content").unwrap();
+    fs::write(&des_file, "Same content").unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--dry-run")
+        .arg("--skip-if=checksum")
+        .arg("--checksum-cache")
+        .arg(&src_file)
+        .arg("--")
+        .arg(&des_file);
+    let output = cmd.assert().success().get_output().stdout.clone();
+    assert!(String::from_utf8(output).unwrap().contains("skip-same"));
+
+    assert!(matches!(xattr::get(&src_file, "user.pbcp.xxh3"), Ok(None)));
+    assert!(matches!(xattr::get(&des_file, "user.pbcp.xxh3"), Ok(None)));
+}
+
+#[test]
+fn test_dry_run() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let des_dir = temp_dir.path().join("des");
+    fs::create_dir_all(src_dir.join("sub")).unwrap();
+    fs::create_dir_all(des_dir.join("src")).unwrap();
+    fs::write(src_dir.join("new.txt"), "Hello, world!").unwrap();
+    fs::write(src_dir.join("sub/changed.txt"), "New").unwrap();
+    fs::write(src_dir.join("old.txt"), "Old").unwrap();
+    std::os::unix::fs::symlink("new.txt", src_dir.join("link.txt")).unwrap();
+    fs::write(des_dir.join("src/old.txt"), "Old").unwrap();
+
+    let mtime = filetime::FileTime::from_system_time(
+        std::time::SystemTime::now() - std::time::Duration::from_secs(10),
+    );
+    filetime::set_file_mtime(src_dir.join("old.txt"), mtime).unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-a")
+        .arg("-u")
+        .arg("--dry-run")
+        .arg(&src_dir)
+        .arg("--")
+        .arg(&des_dir);
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let output = String::from_utf8(output).unwrap();
+
+    let des_src = des_dir.join("src");
+    let action_of = |name: &str| {
+        let path = des_src.join(name).display().to_string();
+        output
+            .lines()
+            .find(|l| l.ends_with(&format!("-> {}", path)))
+            .map(|l| l.split_whitespace().next().unwrap().to_string())
+    };
+    assert_eq!(action_of("new.txt").as_deref(), Some("copy"));
+    assert_eq!(action_of("sub").as_deref(), Some("create-dir"));
+    // the existing directory is gone into, not skipped
+    assert!(!output.contains(&format!("-> {}\n", des_src.display())));
+    assert_eq!(action_of("old.txt").as_deref(), Some("skip-newer"));
+    assert_eq!(action_of("link.txt").as_deref(), Some("symlink"));
+    assert!(output.contains("16 bytes to copy"));
+
+    // nothing is touched
+    assert!(!des_src.join("new.txt").exists());
+    assert!(!des_src.join("sub").exists());
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-r")
+        .arg("--dry-run=json")
+        .arg(&src_dir)
+        .arg("--")
+        .arg(&des_dir);
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let plan: serde_json::Value = serde_json::from_slice(&output).unwrap();
+
+    let old = plan["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|e| e["des"] == des_src.join("old.txt").display().to_string())
+        .unwrap();
+    assert_eq!(old["action"], "overwrite");
+    assert_eq!(old["bytes"], 3);
+}
+
+#[test]
+fn test_verbose() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let des_dir = temp_dir.path().join("des");
+    fs::create_dir_all(src_dir.join("sub")).unwrap();
+    fs::create_dir_all(des_dir.join("src")).unwrap();
+    fs::write(src_dir.join("sub/file1.txt"), "Hello, world!").unwrap();
+    fs::write(src_dir.join("old.txt"), "Old").unwrap();
+    fs::write(des_dir.join("src/old.txt"), "Old").unwrap();
+    std::os::unix::fs::symlink("old.txt", src_dir.join("link.txt")).unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-a")
+        .arg("-n")
+        .arg("-v")
+        .arg(&src_dir)
+        .arg("--")
+        .arg(&des_dir);
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let output = String::from_utf8(output).unwrap();
+
+    let des_src = des_dir.join("src");
+    let lines: Vec<&str> = output.lines().collect();
+    assert!(
+        lines.contains(&format!("created directory '{}'", des_src.join("sub").display()).as_str())
+    );
+    assert!(lines.contains(
+        &format!(
+            "'{}' -> '{}'",
+            src_dir.join("sub/file1.txt").display(),
+            des_src.join("sub/file1.txt").display()
+        )
+        .as_str()
+    ));
+    assert!(lines.contains(
+        &format!(
+            "linked '{}' -> '{}'",
+            src_dir.join("link.txt").display(),
+            des_src.join("link.txt").display()
+        )
+        .as_str()
+    ));
+    assert!(lines.contains(&format!("skipped '{}'", src_dir.join("old.txt").display()).as_str()));
+    // the destination directory existed already
+    assert!(!output.contains(&format!("created directory '{}'", des_src.display())));
+}
+
+#[test]
+fn test_exit_codes() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let des_dir = temp_dir.path().join("des");
+    fs::create_dir(&src_dir).unwrap();
+    fs::create_dir(&des_dir).unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-m")
+        .arg(temp_dir.path().join("missing"))
+        .arg("--")
+        .arg(&des_dir);
+    let output = cmd.assert().code(3).get_output().stderr.clone();
+    assert!(String::from_utf8(output)
+        .unwrap()
+        .contains("does not exist"));
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-m").arg(&src_dir).arg("--").arg(&des_dir);
+    let output = cmd.assert().code(2).get_output().stderr.clone();
+    assert!(String::from_utf8(output)
+        .unwrap()
+        .contains("should specify -r"));
+}
+
+#[test]
+fn test_io_uring_copy() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let des_dir = temp_dir.path().join("des");
+    fs::create_dir_all(src_dir.join("sub")).unwrap();
+    fs::create_dir(&des_dir).unwrap();
+    // larger than the chunks in flight, with an unaligned tail
+    let content: Vec<u8> = (0..5 * 1024 * 1024 + 123u32)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    fs::write(src_dir.join("big.bin"), &content).unwrap();
+    fs::write(src_dir.join("sub/small.txt"), "Hello, world!").unwrap();
+    fs::write(src_dir.join("empty"), "").unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-r")
+        .arg("--io-uring")
+        .arg(&src_dir)
+        .arg("--")
+        .arg(&des_dir);
+    cmd.assert().success();
+
+    assert_eq!(fs::read(des_dir.join("src/big.bin")).unwrap(), content);
+    assert_eq!(
+        fs::read_to_string(des_dir.join("src/sub/small.txt")).unwrap(),
+        "Hello, world!"
+    );
+    assert_eq!(fs::metadata(des_dir.join("src/empty")).unwrap().len(), 0);
+}
+
+#[test]
+fn test_direct_copy() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let des_dir = temp_dir.path().join("des");
+    fs::create_dir(&src_dir).unwrap();
+    fs::create_dir(&des_dir).unwrap();
+    // an unaligned tail after the aligned blocks
+    let content: Vec<u8> = (0..5 * 1024 * 1024 + 123u32)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    fs::write(src_dir.join("big.bin"), &content).unwrap();
+    fs::write(src_dir.join("small.txt"), "Hello, world!").unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-r")
+        .arg("--direct")
+        .arg(&src_dir)
+        .arg("--")
+        .arg(&des_dir);
+    cmd.assert().success();
+
+    assert_eq!(fs::read(des_dir.join("src/big.bin")).unwrap(), content);
+    assert_eq!(
+        fs::read_to_string(des_dir.join("src/small.txt")).unwrap(),
+        "Hello, world!"
+    );
+}
+
+#[test]
+fn test_buffer_size() {
+    let temp_dir = tempdir().unwrap();
+    let src_file = temp_dir.path().join("src.bin");
+    let content: Vec<u8> = (0..1024 * 1024 + 7u32).map(|i| (i % 251) as u8).collect();
+    fs::write(&src_file, &content).unwrap();
+
+    for (size, des) in [("64K", "des_64k.bin"), ("1000", "des_1000.bin")] {
+        let mut cmd = Command::cargo_bin("pbcp").unwrap();
+        cmd.arg("--buffer-size")
+            .arg(size)
+            .arg(&src_file)
+            .arg("--")
+            .arg(temp_dir.path().join(des));
+        cmd.assert().success();
+        assert_eq!(fs::read(temp_dir.path().join(des)).unwrap(), content);
+    }
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--buffer-size")
+        .arg("4X")
+        .arg(&src_file)
+        .arg("--")
+        .arg(temp_dir.path().join("des.bin"));
+    cmd.assert().failure();
+}
+
+#[test]
+fn test_copy_from_stdin() {
+    let temp_dir = tempdir().unwrap();
+    let des_file = temp_dir.path().join("out.img");
+    let content: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--size")
+        .arg("300000")
+        .arg("-")
+        .arg("--")
+        .arg(&des_file)
+        .write_stdin(content.clone());
+    cmd.assert().success();
+
+    assert_eq!(fs::read(&des_file).unwrap(), content);
+}
+
+#[test]
+fn test_copy_to_stdout() {
+    let temp_dir = tempdir().unwrap();
+    let src_file1 = temp_dir.path().join("a.txt");
+    let src_file2 = temp_dir.path().join("b.txt");
+    fs::write(&src_file1, "Hello, ").unwrap();
+    fs::write(&src_file2, "world!").unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg(&src_file1).arg(&src_file2).arg("--").arg("-");
+    let output = cmd.assert().success().get_output().stdout.clone();
+
+    assert_eq!(String::from_utf8(output).unwrap(), "Hello, world!");
+}
+
+#[test]
+fn test_streams_reject_tree_options() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    fs::create_dir(&src_dir).unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-r").arg(&src_dir).arg("--").arg("-");
+    cmd.assert().code(2);
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-").arg("--").arg(&src_dir).write_stdin("data");
+    cmd.assert().code(2);
+}
+
+#[test]
+fn test_compress_and_decompress() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("logs");
+    let packed_dir = temp_dir.path().join("packed");
+    let unpacked_dir = temp_dir.path().join("unpacked");
+    fs::create_dir_all(src_dir.join("sub")).unwrap();
+    fs::create_dir(&packed_dir).unwrap();
+    fs::create_dir(&unpacked_dir).unwrap();
+    let content: String = (0..20_000).map(|i| format!("line {}\n", i % 100)).collect();
+    fs::write(src_dir.join("app.log"), &content).unwrap();
+    fs::write(src_dir.join("sub/db.log"), &content).unwrap();
+
+    for codec in ["zstd", "gzip:9", "xz:1"] {
+        let ext = match codec {
+            "zstd" => "zst",
+            "gzip:9" => "gz",
+            _ => "xz",
+        };
+
+        let mut cmd = Command::cargo_bin("pbcp").unwrap();
+        cmd.arg("-r")
+            .arg("--compress")
+            .arg(codec)
+            .arg("--extension")
+            .arg(&src_dir)
+            .arg("--")
+            .arg(&packed_dir);
+        let output = cmd.assert().success().get_output().stderr.clone();
+        assert!(String::from_utf8(output).unwrap().contains("compressed"));
+
+        let packed = packed_dir.join(format!("logs/app.log.{}", ext));
+        assert!(fs::metadata(&packed).unwrap().len() < content.len() as u64);
+        assert!(packed_dir.join(format!("logs/sub/db.log.{}", ext)).exists());
+
+        let mut cmd = Command::cargo_bin("pbcp").unwrap();
+        cmd.arg("-r")
+            .arg("--decompress")
+            .arg("--extension")
+            .arg(packed_dir.join("logs"))
+            .arg("--")
+            .arg(&unpacked_dir);
+        cmd.assert().success();
+
+        assert_eq!(
+            fs::read_to_string(unpacked_dir.join("logs/app.log")).unwrap(),
+            content
+        );
+        assert_eq!(
+            fs::read_to_string(unpacked_dir.join("logs/sub/db.log")).unwrap(),
+            content
+        );
+
+        fs::remove_dir_all(packed_dir.join("logs")).unwrap();
+        fs::remove_dir_all(unpacked_dir.join("logs")).unwrap();
+    }
+}
+
+#[test]
+fn test_compress_rejects_bad_level() {
+    let temp_dir = tempdir().unwrap();
+    let src_file = temp_dir.path().join("app.log");
+    fs::write(&src_file, "log").unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--compress")
+        .arg("gzip:10")
+        .arg(&src_file)
+        .arg("--")
+        .arg(temp_dir.path().join("app.log.gz"));
+    cmd.assert().code(2);
+}
+
+#[test]
+fn test_tar_round_trip() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    fs::create_dir_all(src_dir.join("sub")).unwrap();
+    fs::write(src_dir.join("file1.txt"), "Hello, world!").unwrap();
+    let content: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(src_dir.join("sub/data.bin"), &content).unwrap();
+    fs::set_permissions(
+        src_dir.join("sub/data.bin"),
+        std::os::unix::fs::PermissionsExt::from_mode(0o751),
+    )
+    .unwrap();
+    std::os::unix::fs::symlink("file1.txt", src_dir.join("link")).unwrap();
+    let mtime = filetime::FileTime::from_unix_time(1_600_000_000, 0);
+    filetime::set_file_mtime(src_dir.join("sub"), mtime).unwrap();
+
+    for name in ["out.tar", "out.tar.zst", "out.tgz"] {
+        let archive = temp_dir.path().join(name);
+        let des_dir = temp_dir.path().join(format!("{}.d", name));
+
+        let mut cmd = Command::cargo_bin("pbcp").unwrap();
+        cmd.arg("-a").arg(&src_dir).arg("--").arg(&archive);
+        cmd.assert().success();
+
+        let mut cmd = Command::cargo_bin("pbcp").unwrap();
+        cmd.arg("-a").arg(&archive).arg("--").arg(&des_dir);
+        cmd.assert().success();
+
+        assert_eq!(
+            fs::read_to_string(des_dir.join("src/file1.txt")).unwrap(),
+            "Hello, world!"
+        );
+        assert_eq!(fs::read(des_dir.join("src/sub/data.bin")).unwrap(), content);
+        assert_eq!(
+            fs::metadata(des_dir.join("src/sub/data.bin"))
+                .unwrap()
+                .mode()
+                & 0o7777,
+            0o751
+        );
+        assert_eq!(
+            fs::read_link(des_dir.join("src/link")).unwrap(),
+            std::path::Path::new("file1.txt")
+        );
+        assert_eq!(
+            fs::metadata(des_dir.join("src/sub")).unwrap().mtime(),
+            1_600_000_000
+        );
+    }
+}
+
+#[test]
+fn test_tar_needs_recursive_and_rejects_update() {
+    let temp_dir = tempdir().unwrap();
+    let src_file = temp_dir.path().join("file1.txt");
+    let des_file = temp_dir.path().join("plain.tar");
+    fs::write(&src_file, "Hello, world!").unwrap();
+
+    // without -r a tar name is an ordinary destination
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg(&src_file).arg("--").arg(&des_file);
+    cmd.assert().success();
+    assert_eq!(fs::read_to_string(&des_file).unwrap(), "Hello, world!");
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-r")
+        .arg("-u")
+        .arg(&src_file)
+        .arg("--")
+        .arg(temp_dir.path().join("out.tar"));
+    cmd.assert().code(2);
+}
+
+#[test]
+fn test_split_and_join() {
+    let temp_dir = tempdir().unwrap();
+    let src_file = temp_dir.path().join("image.iso");
+    let parts_dir = temp_dir.path().join("parts");
+    let joined_dir = temp_dir.path().join("joined");
+    fs::create_dir(&parts_dir).unwrap();
+    fs::create_dir(&joined_dir).unwrap();
+    let content: Vec<u8> = (0..2_500_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(&src_file, &content).unwrap();
+    // left from an earlier split of a larger file
+    fs::write(parts_dir.join("image.iso.003"), "stale").unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--split")
+        .arg("1M")
+        .arg(&src_file)
+        .arg("--")
+        .arg(&parts_dir);
+    cmd.assert().success();
+
+    for (part, len) in [("000", 1 << 20), ("001", 1 << 20), ("002", 402_848)] {
+        assert_eq!(
+            fs::metadata(parts_dir.join(format!("image.iso.{}", part)))
+                .unwrap()
+                .len(),
+            len
+        );
+    }
+    assert!(!parts_dir.join("image.iso.003").exists());
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-r")
+        .arg("--join")
+        .arg(&parts_dir)
+        .arg("--")
+        .arg(&joined_dir);
+    cmd.assert().success();
+
+    assert_eq!(
+        fs::read(joined_dir.join("parts/image.iso")).unwrap(),
+        content
+    );
+    assert!(!joined_dir.join("parts/image.iso.001").exists());
+}
+
+#[test]
+fn test_split_refuses_the_actions() {
+    let temp_dir = tempdir().unwrap();
+    let src_file = temp_dir.path().join("src.bin");
+    fs::write(&src_file, vec![7u8; 5_000]).unwrap();
+    fs::write(temp_dir.path().join("keep.000"), "Keep").unwrap();
+
+    for flag in ["-v", "-n"] {
+        let mut cmd = Command::cargo_bin("pbcp").unwrap();
+        cmd.arg(flag)
+            .arg("--split")
+            .arg("2K")
+            .arg(&src_file)
+            .arg("--")
+            .arg(temp_dir.path().join("keep"));
+        cmd.assert().code(2);
+    }
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("keep.000")).unwrap(),
+        "Keep"
+    );
+    assert!(!temp_dir.path().join("keep.001").exists());
+}
+
+#[test]
+fn test_join_runs_the_actions_on_the_joined_file() {
+    let temp_dir = tempdir().unwrap();
+    fs::write(temp_dir.path().join("data.000"), "Hello, ").unwrap();
+    fs::write(temp_dir.path().join("data.001"), "world!").unwrap();
+    let des_file = temp_dir.path().join("joined");
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-v")
+        .arg("--join")
+        .arg(temp_dir.path().join("data.000"))
+        .arg("--")
+        .arg(&des_file);
+    let output = cmd.assert().success().get_output().stdout.clone();
+    assert!(String::from_utf8(output)
+        .unwrap()
+        .contains(&format!("-> '{}'", des_file.display())));
+    assert_eq!(fs::read_to_string(&des_file).unwrap(), "Hello, world!");
+
+    fs::write(&des_file, "Keep").unwrap();
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-n")
+        .arg("--join")
+        .arg(temp_dir.path().join("data.000"))
+        .arg("--")
+        .arg(&des_file);
+    cmd.assert().success();
+    assert_eq!(fs::read_to_string(&des_file).unwrap(), "Keep");
+}
+
+#[test]
+fn test_copy_to_several_destinations() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("release");
+    let des_dirs: Vec<_> = (0..3)
+        .map(|i| temp_dir.path().join(format!("target{}", i)))
+        .collect();
+    fs::create_dir_all(src_dir.join("sub")).unwrap();
+    for des_dir in des_dirs.iter() {
+        fs::create_dir(des_dir).unwrap();
+    }
+    let content: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(src_dir.join("app.bin"), &content).unwrap();
+    fs::write(src_dir.join("sub/notes.txt"), "Hello, world!").unwrap();
+    // a file where a directory should be
+    let blocked = temp_dir.path().join("blocked");
+    fs::write(&blocked, "").unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-r")
+        .arg(&src_dir)
+        .arg("--to")
+        .arg(&des_dirs[0])
+        .arg("--to")
+        .arg(blocked.join("target"))
+        .arg("--to")
+        .arg(&des_dirs[1])
+        .arg("--to")
+        .arg(&des_dirs[2]);
+    let output = cmd.assert().failure().get_output().stderr.clone();
+    assert!(String::from_utf8(output).unwrap().contains("blocked"));
+
+    for des_dir in des_dirs.iter() {
+        assert_eq!(fs::read(des_dir.join("release/app.bin")).unwrap(), content);
+        assert_eq!(
+            fs::read_to_string(des_dir.join("release/sub/notes.txt")).unwrap(),
+            "Hello, world!"
+        );
+    }
+}
+
+#[test]
+fn test_remote_paths_checked_before_connecting() {
+    let temp_dir = tempdir().unwrap();
+    let src_file = temp_dir.path().join("file1.txt");
+    fs::write(&src_file, "Hello, world!").unwrap();
+
+    // one side should be local
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("alice@host1:file1.txt")
+        .arg("--")
+        .arg("bob@host2:file1.txt");
+    cmd.assert().code(2);
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-u")
+        .arg(&src_file)
+        .arg("--")
+        .arg("alice@host1:backup/");
+    cmd.assert().code(2);
+
+    // nothing listens on port 1
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--ssh-port")
+        .arg("1")
+        .arg(&src_file)
+        .arg("--")
+        .arg("alice@127.0.0.1:file1.txt");
+    let output = cmd.assert().failure().get_output().stderr.clone();
+    assert!(String::from_utf8(output).unwrap().contains("127.0.0.1:1"));
+}
+
+#[test]
+fn test_colon_after_slash_is_local() {
+    let temp_dir = tempdir().unwrap();
+    let src_file = temp_dir.path().join("file1.txt");
+    let des_file = temp_dir.path().join("notes:draft");
+    fs::write(&src_file, "Hello, world!").unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg(&src_file).arg("--").arg(&des_file);
+    cmd.assert().success();
+    assert_eq!(fs::read_to_string(&des_file).unwrap(), "Hello, world!");
+}
+
+// Needs a host taking key logins and an existing directory on it,
+// Needs an SSH server, run it with --ignored and
+// e.g. PBCP_SSH_TEST_TARGET=me@localhost:/tmp/pbcp-test
+#[test]
+#[ignore]
+fn test_remote_round_trip() {
+    let target = std::env::var("PBCP_SSH_TEST_TARGET").expect("PBCP_SSH_TEST_TARGET is unset");
+    let port = std::env::var("PBCP_SSH_TEST_PORT").unwrap_or_else(|_| "22".to_string());
+
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("tree");
+    let des_dir = temp_dir.path().join("back");
+    fs::create_dir_all(src_dir.join("sub")).unwrap();
+    let content: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(src_dir.join("app.bin"), &content).unwrap();
+    fs::write(src_dir.join("sub/notes.txt"), "Hello, world!").unwrap();
+    std::os::unix::fs::symlink("app.bin", src_dir.join("link")).unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-a")
+        .arg("--ssh-port")
+        .arg(&port)
+        .arg(&src_dir)
+        .arg("--")
+        .arg(&target);
+    cmd.assert().success();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("-a")
+        .arg("--ssh-port")
+        .arg(&port)
+        .arg(format!("{}/tree", target.trim_end_matches('/')))
+        .arg("--")
+        .arg(&des_dir);
+    cmd.assert().success();
+
+    assert_eq!(fs::read(des_dir.join("app.bin")).unwrap(), content);
+    assert_eq!(
+        fs::read_to_string(des_dir.join("sub/notes.txt")).unwrap(),
+        "Hello, world!"
+    );
+    assert_eq!(
+        fs::read_link(des_dir.join("link")).unwrap().to_str(),
+        Some("app.bin")
+    );
+}
+
+// Writes the configuration file under the XDG_CONFIG_HOME returned
+fn write_config(root: &std::path::Path, content: &str) -> std::path::PathBuf {
+    let config_home = root.join("config");
+    fs::create_dir_all(config_home.join("pbcp")).unwrap();
+    fs::write(config_home.join("pbcp/config.toml"), content).unwrap();
+    config_home
+}
+
+#[test]
+fn test_config_defaults() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let des_dir = temp_dir.path().join("des");
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::write(src_dir.join("file1.txt"), "Hello, world!").unwrap();
+    fs::write(src_dir.join("skip.log"), "Log content").unwrap();
+    let config_home = write_config(
+        temp_dir.path(),
+        "recursive = true\nexclude = [\"*.log\"]\n\n[profiles.quiet]\nmute = true\n",
+    );
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.env("XDG_CONFIG_HOME", &config_home)
+        .arg("--profile=quiet")
+        .arg(&src_dir)
+        .arg("--")
+        .arg(&des_dir);
+    cmd.assert().success();
+
+    assert!(des_dir.join("file1.txt").exists());
+    assert!(!des_dir.join("skip.log").exists());
+
+    // the environment overrides the configuration file
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.env("XDG_CONFIG_HOME", &config_home)
+        .env("PBCP_RECURSIVE", "0")
+        .arg("-m")
+        .arg(&src_dir)
+        .arg("--")
+        .arg(temp_dir.path().join("des2"));
+    cmd.assert().failure().code(5);
+
+    assert!(!temp_dir.path().join("des2/file1.txt").exists());
+
+    // and --no-OPTION overrides both
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.env("XDG_CONFIG_HOME", &config_home)
+        .arg("-m")
+        .arg("--no-recursive")
+        .arg(&src_dir)
+        .arg("--")
+        .arg(temp_dir.path().join("des3"));
+    cmd.assert().failure().code(5);
+
+    assert!(!temp_dir.path().join("des3/file1.txt").exists());
+}
+
+#[test]
+fn test_print_config() {
+    let temp_dir = tempdir().unwrap();
+    let config_home = write_config(
+        temp_dir.path(),
+        "archive = true\npreserve = \"mode\"\n\n[profiles.backup]\nbackup = \"numbered\"\n",
+    );
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.env("XDG_CONFIG_HOME", &config_home)
+        .env("PBCP_PROFILE", "backup")
+        .env("PBCP_MUTE", "true")
+        .arg("--preserve=all")
+        .arg("--print-config");
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let output = String::from_utf8(output).unwrap();
+
+    assert!(output.contains("archive = true # config file"));
+    assert!(output.contains("preserve = \"all\" # command line"));
+    assert!(output.contains("backup = \"numbered\" # profile backup"));
+    assert!(output.contains("mute = true # PBCP_MUTE"));
+}
+
+#[test]
+fn test_config_conflicts_give_way() {
+    let temp_dir = tempdir().unwrap();
+    let src_file = temp_dir.path().join("src.txt");
+    let des_file = temp_dir.path().join("des.txt");
+    fs::write(&src_file, "Hello, world!").unwrap();
+    let config_home = write_config(temp_dir.path(), "direct = true\ncompress = \"gzip\"\n");
+
+    // the options given replace the defaults they conflict with
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.env("XDG_CONFIG_HOME", &config_home)
+        .env("PBCP_DECOMPRESS", "0")
+        .arg("--io-uring")
+        .arg("--no-compress")
+        .arg(&src_file)
+        .arg("--")
+        .arg(&des_file);
+    cmd.assert().success();
+    assert_eq!(fs::read_to_string(&des_file).unwrap(), "Hello, world!");
+
+    // so do the ones of a later layer
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.env("XDG_CONFIG_HOME", &config_home)
+        .env("PBCP_DECOMPRESS", "1")
+        .arg("--print-config");
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let output = String::from_utf8(output).unwrap();
+
+    assert!(output.contains("decompress = true # PBCP_DECOMPRESS"));
+    assert!(!output.contains("direct"));
+    assert!(!output.contains("compress = \"gzip\""));
+}
+
+#[test]
+fn test_config_negated_defaults() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::write(src_dir.join("file1.txt"), "Hello, world!").unwrap();
+    let config_home = write_config(temp_dir.path(), "recursive = true\nmute = true\n");
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.env("XDG_CONFIG_HOME", &config_home)
+        .arg("--no-recursive")
+        .arg(&src_dir)
+        .arg("--")
+        .arg(temp_dir.path().join("des"));
+    cmd.assert().code(5);
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.env("XDG_CONFIG_HOME", &config_home)
+        .arg("--no-mute")
+        .arg("--print-config");
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let output = String::from_utf8(output).unwrap();
+
+    assert!(output.contains("recursive = true # config file"));
+    assert!(output.contains("mute = false # command line"));
+}
+
+#[test]
+fn test_bad_config() {
+    let temp_dir = tempdir().unwrap();
+    let config_home = write_config(temp_dir.path(), "colour = \"red\"\n");
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.env("XDG_CONFIG_HOME", &config_home)
+        .arg("--print-config");
+    cmd.assert().code(2);
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--config")
+        .arg(temp_dir.path().join("missing.toml"))
+        .arg("--print-config");
+    cmd.assert().code(3);
+}
+
+#[test]
+fn test_verbose_move() {
+    let temp_dir = tempdir().unwrap();
+    let src_dir = temp_dir.path().join("src");
+    let des_dir = temp_dir.path().join("des");
+    fs::create_dir_all(src_dir.join("sub")).unwrap();
+    fs::create_dir_all(&des_dir).unwrap();
+    fs::write(src_dir.join("sub/file1.txt"), "Hello, world!").unwrap();
+
+    let mut cmd = Command::cargo_bin("pbcp").unwrap();
+    cmd.arg("--move")
+        .arg("-m")
+        .arg("-v")
+        .arg(&src_dir)
+        .arg("--")
+        .arg(&des_dir);
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let output = String::from_utf8(output).unwrap();
+
+    // the entries of the renamed directory are not reported again
+    assert_eq!(
+        output.lines().collect::<Vec<_>>(),
+        [format!(
+            "renamed '{}' -> '{}'",
+            src_dir.display(),
+            des_dir.join("src").display()
+        )]
+    );
+}
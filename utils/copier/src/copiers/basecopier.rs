@@ -1,24 +1,173 @@
-use super::super::FileCopy;
+use super::super::{preallocate, FileCopy, InCopyAction};
 use std::io::{Read, Write};
+use std::sync::mpsc;
+use std::time::Instant;
+use storage::{FileHandle, Handle};
+
+const MIN_BUFFER: usize = 128 * 1024;
+const MAX_BUFFER: usize = 16 * 1024 * 1024;
+// for the sources without a length, until the adaptive mode has tuned a size
+const STREAM_BUFFER: usize = 4 * 1024 * 1024;
 
 pub struct Copier {
     buffer: Vec<u8>,
+    // the buffer size, tuned after every file in the adaptive mode
+    buf_sz: usize,
+    adaptive: bool,
+    tuned: bool,
 }
 
 impl Copier {
     pub fn new(buf_sz: usize) -> Self {
         Self {
-            buffer: vec![0u8; buf_sz],
+            buffer: Vec::new(),
+            buf_sz: buf_sz.max(1),
+            adaptive: false,
+            tuned: false,
+        }
+    }
+
+    /// Picks the buffer size from the block size, the file size and the
+    /// measured throughput instead of a fixed one.
+    pub fn adaptive() -> Self {
+        Self {
+            buffer: Vec::new(),
+            buf_sz: MIN_BUFFER,
+            adaptive: true,
+            tuned: false,
+        }
+    }
+
+    fn chunk_size(&self, len: u64, blksize: usize) -> usize {
+        // stdin and procfs files report no length, so it says nothing of the size
+        if len == 0 {
+            let size = match self.adaptive && !self.tuned {
+                true => STREAM_BUFFER,
+                false => self.buf_sz,
+            };
+            return size.max(blksize).div_ceil(blksize) * blksize;
+        }
+        let size = self.buf_sz.max(blksize);
+        // no need for more than the whole file, plus the block telling the end
+        let whole = (len as usize).div_ceil(blksize) * blksize + blksize;
+        let size = size.min(whole);
+        size.div_ceil(blksize) * blksize
+    }
+
+    // Reads on another thread, so that reading the next buffer overlaps
+    // writing the current one
+    fn double_buffered_copy(
+        &mut self,
+        mut src: Handle,
+        des: &mut dyn FileHandle,
+        size: usize,
+        progress_callback: &dyn InCopyAction,
+    ) -> std::io::Result<u64> {
+        let mut tuner = Tuner::new(size, self.adaptive);
+
+        let copied = std::thread::scope(|s| {
+            let (full_tx, full_rx) = mpsc::sync_channel::<std::io::Result<(Vec<u8>, usize)>>(1);
+            let (free_tx, free_rx) = mpsc::channel::<Vec<u8>>();
+
+            for _ in 0..2 {
+                free_tx.send(vec![0u8; size]).unwrap();
+            }
+
+            s.spawn(move || {
+                for mut buf in free_rx {
+                    match src.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(n) => {
+                            if full_tx.send(Ok((buf, n))).is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => {
+                            let _ = full_tx.send(Err(e));
+                            break;
+                        }
+                    }
+                }
+            });
+
+            let mut copied = 0;
+            for msg in full_rx {
+                let (mut buf, n) = msg?;
+                des.write_all(&buf[..n])?;
+                copied += n as u64;
+                progress_callback.in_copy_run(copied);
+
+                buf.resize(tuner.next(n), 0);
+                if free_tx.send(buf).is_err() {
+                    break;
+                }
+            }
+
+            Ok::<u64, std::io::Error>(copied)
+        })?;
+
+        if self.adaptive {
+            self.buf_sz = tuner.size;
+            self.tuned = true;
         }
+
+        Ok(copied)
+    }
+}
+
+// Doubles the buffer while the throughput keeps growing and halves it once it
+// drops by half
+struct Tuner {
+    size: usize,
+    enabled: bool,
+    best_rate: f64,
+    last: Instant,
+}
+
+impl Tuner {
+    fn new(size: usize, enabled: bool) -> Self {
+        Self {
+            size,
+            enabled,
+            best_rate: 0.0,
+            last: Instant::now(),
+        }
+    }
+
+    fn next(&mut self, n: usize) -> usize {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+
+        // the tail says nothing about the throughput
+        if !self.enabled || n < self.size || elapsed <= 0.0 {
+            return self.size;
+        }
+
+        let rate = n as f64 / elapsed;
+        if rate > self.best_rate * 1.1 {
+            self.best_rate = rate;
+            if self.size < MAX_BUFFER {
+                self.size *= 2;
+            }
+        } else if rate < self.best_rate / 2.0 && self.size > MIN_BUFFER {
+            self.best_rate = rate;
+            self.size /= 2;
+        }
+        self.size
     }
 }
 
 impl FileCopy for Copier {
     fn simple_copy_once(
         &mut self,
-        src: &mut std::fs::File,
-        des: &mut std::fs::File,
+        src: &mut dyn FileHandle,
+        des: &mut dyn FileHandle,
     ) -> std::io::Result<u64> {
+        if self.buffer.is_empty() {
+            self.buffer.resize(self.buf_sz, 0);
+        }
+
         match src.read(&mut self.buffer) {
             Ok(0) => Ok(0),
             Ok(n) => match des.write_all(&self.buffer[..n]) {
@@ -28,6 +177,47 @@ impl FileCopy for Copier {
             Err(e) => Err(e),
         }
     }
+
+    fn copy<'a>(
+        &'a mut self,
+        mut src: Handle,
+        mut des: Handle,
+        progress_callback: &'a dyn InCopyAction,
+    ) -> std::io::Result<u64> {
+        let stat = src.stat()?;
+        let len = stat.len;
+        let blksize = (stat.blksize as usize)
+            .max(des.stat()?.blksize as usize)
+            .max(512);
+        let size = self.chunk_size(len, blksize);
+
+        progress_callback.set_length(len);
+        preallocate(&*des, len)?;
+
+        // pipes are read ahead too, their length is unknown
+        if len > size as u64 || (len == 0 && !stat.is_file()) {
+            return self.double_buffered_copy(src, &mut *des, size, progress_callback);
+        }
+
+        // a single buffer is enough for small files
+        if self.buffer.len() < size {
+            self.buffer.resize(size, 0);
+        }
+        let mut copied = 0;
+        loop {
+            match src.read(&mut self.buffer[..size]) {
+                Ok(0) => break,
+                Ok(n) => {
+                    des.write_all(&self.buffer[..n])?;
+                    copied += n as u64;
+                    progress_callback.in_copy_run(copied);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(copied)
+    }
 }
 
 #[cfg(test)]
@@ -65,7 +255,7 @@ mod tests {
 
         {
             let src_file_reopen = File::open(&src_file_path).unwrap();
-            let des_file = File::create(&des_file_path).unwrap();
+            let des_file = File::create(des_file_path).unwrap();
 
             print!(
                 "from {} to {}",
@@ -74,17 +264,62 @@ mod tests {
             );
 
             let ret = copier
-                .copy(src_file_reopen, des_file, &mock_in_copy_action)
+                .copy(
+                    Box::new(src_file_reopen),
+                    Box::new(des_file),
+                    &mock_in_copy_action,
+                )
                 .unwrap();
 
             println!(", {} bytes copied.", ret);
         }
 
-        let mut des_file = File::open(&des_file_path).unwrap();
+        let mut des_file = File::open(des_file_path).unwrap();
         let mut des_content = String::new();
 
         des_file.read_to_string(&mut des_content).unwrap();
 
         assert_eq!(test_str, des_content);
     }
+
+    #[test]
+    fn adaptive_copy_works() {
+        let mut copier = Copier::adaptive();
+        let content: Vec<u8> = (0..3 * MIN_BUFFER as u32 + 77)
+            .map(|i| (i % 251) as u8)
+            .collect();
+
+        let temp_dir = tempfile::tempdir_in(".").unwrap();
+        let src_file_path = temp_dir.path().join("src.bin");
+        let des_file_path = temp_dir.path().join("des.bin");
+        File::create(&src_file_path)
+            .unwrap()
+            .write_all(&content)
+            .unwrap();
+
+        for _ in 0..2 {
+            let ret = copier
+                .copy(
+                    Box::new(File::open(&src_file_path).unwrap()),
+                    Box::new(File::create(&des_file_path).unwrap()),
+                    &MockInCopyAction,
+                )
+                .unwrap();
+            assert_eq!(ret, content.len() as u64);
+            assert_eq!(std::fs::read(&des_file_path).unwrap(), content);
+        }
+        assert!(copier.buf_sz >= MIN_BUFFER && copier.buf_sz <= MAX_BUFFER);
+    }
+
+    #[test]
+    fn chunk_size_without_length() {
+        assert_eq!(Copier::new(4096 * 1024).chunk_size(0, 4096), 4096 * 1024);
+        assert_eq!(Copier::new(4096 * 1024).chunk_size(100, 4096), 8192);
+
+        let mut copier = Copier::adaptive();
+        assert_eq!(copier.chunk_size(0, 4096), STREAM_BUFFER);
+        copier.buf_sz = 2 * MIN_BUFFER;
+        copier.tuned = true;
+        assert_eq!(copier.chunk_size(0, 4096), 2 * MIN_BUFFER);
+    }
 }
@@ -0,0 +1,373 @@
+use super::super::{FileCopy, InCopyAction};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use storage::{FileHandle, Handle};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    Zstd,
+    Gzip,
+    Xz,
+}
+
+impl Codec {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "zstd" | "zst" => Some(Codec::Zstd),
+            "gzip" | "gz" => Some(Codec::Gzip),
+            "xz" => Some(Codec::Xz),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Codec::Zstd => "zst",
+            Codec::Gzip => "gz",
+            Codec::Xz => "xz",
+        }
+    }
+
+    /// The codec a compressed file name ends with.
+    pub fn from_path(path: &str) -> Option<Self> {
+        let (_, ext) = path.rsplit_once('.')?;
+        [Codec::Zstd, Codec::Gzip, Codec::Xz]
+            .into_iter()
+            .find(|codec| codec.extension() == ext)
+    }
+
+    fn levels(self) -> (u32, u32, u32) {
+        // (min, default, max)
+        match self {
+            Codec::Zstd => (1, 3, 22),
+            Codec::Gzip => (0, 6, 9),
+            Codec::Xz => (0, 6, 9),
+        }
+    }
+
+    // Tells the codec by the magic bytes of the stream
+    fn from_magic(head: &[u8]) -> Option<Self> {
+        if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Codec::Zstd)
+        } else if head.starts_with(&[0x1f, 0x8b]) {
+            Some(Codec::Gzip)
+        } else if head.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Codec::Xz)
+        } else {
+            None
+        }
+    }
+}
+
+/// Tells the codec of a stream by peeking at its first bytes, `None` when it
+/// is not compressed.
+pub fn detect<R: BufRead>(src: &mut R) -> io::Result<Option<Codec>> {
+    Ok(Codec::from_magic(src.fill_buf()?))
+}
+
+/// A reader decompressing `src`.
+pub fn decoder<'a, R: BufRead + 'a>(codec: Codec, src: R) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(match codec {
+        Codec::Zstd => Box::new(zstd::Decoder::with_buffer(src)?),
+        Codec::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(src)),
+        Codec::Xz => Box::new(xz2::bufread::XzDecoder::new_multi_decoder(src)),
+    })
+}
+
+/// A writer compressing into `W`, `finish` ends the stream.
+pub enum Encoder<W: Write> {
+    Zstd(zstd::Encoder<'static, W>),
+    Gzip(flate2::write::GzEncoder<W>),
+    Xz(xz2::write::XzEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(codec: Codec, level: u32, inner: W) -> io::Result<Self> {
+        Ok(match codec {
+            Codec::Zstd => Encoder::Zstd(zstd::Encoder::new(inner, level as i32)?),
+            Codec::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                inner,
+                flate2::Compression::new(level),
+            )),
+            Codec::Xz => Encoder::Xz(xz2::write::XzEncoder::new(inner, level)),
+        })
+    }
+
+    /// Compresses with the default level of the codec.
+    pub fn with_default_level(codec: Codec, inner: W) -> io::Result<Self> {
+        Self::new(codec, codec.levels().1, inner)
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Xz(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Xz(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Zstd(encoder) => encoder.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Xz(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transform {
+    Compress(Codec, u32),
+    /// the codec is told from the stream itself
+    Decompress,
+}
+
+impl Transform {
+    /// Parses `CODEC[:LEVEL]` as given to `--compress`.
+    pub fn parse_compress(s: &str) -> Result<Self, String> {
+        let (name, level) = match s.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (s, None),
+        };
+        let codec = Codec::parse(name).ok_or_else(|| format!("invalid codec: {}", name))?;
+        let (min, default, max) = codec.levels();
+        let level = match level {
+            Some(level) => level
+                .parse::<u32>()
+                .ok()
+                .filter(|level| (min..=max).contains(level))
+                .ok_or_else(|| {
+                    format!(
+                        "invalid {} level: {}, should be {} to {}",
+                        name, level, min, max
+                    )
+                })?,
+            None => default,
+        };
+
+        Ok(Transform::Compress(codec, level))
+    }
+}
+
+// Reports the progress against the bytes read from the source
+struct Progress<'a, R> {
+    inner: R,
+    read: u64,
+    callback: &'a dyn InCopyAction,
+}
+
+impl<R: Read> Read for Progress<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        self.callback.in_copy_run(self.read);
+        Ok(n)
+    }
+}
+
+struct Quiet;
+
+impl InCopyAction for Quiet {
+    fn set_length(&self, _: u64) {}
+    fn in_copy_run(&self, _: u64) {}
+}
+
+struct Counter<W> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Compresses or decompresses the data on the way to the destination.
+pub struct Copier {
+    transform: Transform,
+    buf_sz: usize,
+    output_len: u64,
+}
+
+impl Copier {
+    pub fn new(transform: Transform, buf_sz: usize) -> Self {
+        Self {
+            transform,
+            buf_sz,
+            output_len: 0,
+        }
+    }
+
+    fn run<R: Read>(&mut self, src: R, des: &mut dyn FileHandle) -> io::Result<()> {
+        let mut src = BufReader::with_capacity(self.buf_sz, src);
+        let mut des = Counter {
+            inner: BufWriter::with_capacity(self.buf_sz, des),
+            written: 0,
+        };
+
+        match self.transform {
+            Transform::Compress(codec, level) => {
+                let mut encoder = Encoder::new(codec, level, &mut des)?;
+                io::copy(&mut src, &mut encoder)?;
+                encoder.finish()?;
+            }
+            Transform::Decompress => {
+                if !src.fill_buf()?.is_empty() {
+                    let codec = detect(&mut src)?.ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "not a zstd, gzip or xz stream")
+                    })?;
+                    io::copy(&mut decoder(codec, src)?, &mut des)?;
+                }
+            }
+        }
+
+        des.flush()?;
+        self.output_len = des.written;
+        Ok(())
+    }
+}
+
+impl FileCopy for Copier {
+    // Transforms the rest of the source at once, the codecs keep state across the file
+    fn simple_copy_once(
+        &mut self,
+        src: &mut dyn FileHandle,
+        des: &mut dyn FileHandle,
+    ) -> io::Result<u64> {
+        let mut src = BufReader::with_capacity(self.buf_sz, src);
+        if src.fill_buf()?.is_empty() {
+            return Ok(0);
+        }
+
+        let mut src = Progress {
+            inner: src,
+            read: 0,
+            callback: &Quiet,
+        };
+        self.run(&mut src, des)?;
+        Ok(src.read)
+    }
+
+    fn copy<'a>(
+        &'a mut self,
+        src: Handle,
+        mut des: Handle,
+        progress_callback: &'a dyn InCopyAction,
+    ) -> io::Result<u64> {
+        progress_callback.set_length(src.stat()?.len);
+
+        let mut src = Progress {
+            inner: src,
+            read: 0,
+            callback: progress_callback,
+        };
+        self.run(&mut src, &mut *des)?;
+
+        Ok(src.read)
+    }
+
+    fn output_len(&self) -> Option<u64> {
+        Some(self.output_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    struct MockInCopyAction;
+
+    impl InCopyAction for MockInCopyAction {
+        fn set_length(&self, _: u64) {}
+        fn in_copy_run(&self, _: u64) {}
+    }
+
+    #[test]
+    fn parse_compress() {
+        assert_eq!(
+            Transform::parse_compress("zstd").unwrap(),
+            Transform::Compress(Codec::Zstd, 3)
+        );
+        assert_eq!(
+            Transform::parse_compress("gzip:9").unwrap(),
+            Transform::Compress(Codec::Gzip, 9)
+        );
+        assert!(Transform::parse_compress("xz:10").is_err());
+        assert!(Transform::parse_compress("lz4").is_err());
+        assert_eq!(Codec::from_path("a/b.log.gz"), Some(Codec::Gzip));
+        assert_eq!(Codec::from_path("a/b.log"), None);
+    }
+
+    #[test]
+    fn round_trip_works() {
+        let content: Vec<u8> = (0..200_000u32)
+            .flat_map(|i| format!("line {}\n", i % 1000).into_bytes())
+            .collect();
+
+        let temp_dir = tempfile::tempdir_in(".").unwrap();
+        let src_file_path = temp_dir.path().join("src.log");
+        let packed_path = temp_dir.path().join("src.log.packed");
+        let unpacked_path = temp_dir.path().join("unpacked.log");
+        std::fs::write(&src_file_path, &content).unwrap();
+
+        for codec in ["zstd", "gzip:1", "xz"] {
+            let mut copier = Copier::new(Transform::parse_compress(codec).unwrap(), 64 * 1024);
+            let read = copier
+                .copy(
+                    Box::new(File::open(&src_file_path).unwrap()),
+                    Box::new(File::create(&packed_path).unwrap()),
+                    &MockInCopyAction,
+                )
+                .unwrap();
+            assert_eq!(read, content.len() as u64);
+            let packed_len = std::fs::metadata(&packed_path).unwrap().len();
+            assert_eq!(copier.output_len(), Some(packed_len));
+            assert!(packed_len < content.len() as u64);
+
+            let mut copier = Copier::new(Transform::Decompress, 64 * 1024);
+            let read = copier
+                .copy(
+                    Box::new(File::open(&packed_path).unwrap()),
+                    Box::new(File::create(&unpacked_path).unwrap()),
+                    &MockInCopyAction,
+                )
+                .unwrap();
+            assert_eq!(read, packed_len);
+            assert_eq!(copier.output_len(), Some(content.len() as u64));
+            assert_eq!(std::fs::read(&unpacked_path).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn decompress_rejects_plain_data() {
+        let temp_dir = tempfile::tempdir_in(".").unwrap();
+        let src_file_path = temp_dir.path().join("plain.txt");
+        std::fs::write(&src_file_path, "plain text").unwrap();
+
+        let mut copier = Copier::new(Transform::Decompress, 4096);
+        let ret = copier.copy(
+            Box::new(File::open(&src_file_path).unwrap()),
+            Box::new(File::create(temp_dir.path().join("des.txt")).unwrap()),
+            &MockInCopyAction,
+        );
+
+        assert_eq!(ret.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
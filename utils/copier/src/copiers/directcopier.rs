@@ -0,0 +1,275 @@
+use super::super::{copy_span, preallocate, FileCopy, InCopyAction};
+use log::debug;
+use std::alloc::{self, Layout};
+use storage::{FileHandle, Handle};
+
+// O_DIRECT wants the buffer, offsets and lengths aligned to the logical block size
+const ALIGN: usize = 4096;
+
+struct AlignedBuffer {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuffer {
+    fn new(size: usize) -> Self {
+        let layout = Layout::from_size_align(size, ALIGN).unwrap();
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        Self { ptr, layout }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, self.layout) };
+    }
+}
+
+/// Copies without filling the page cache: through `O_DIRECT` when the file
+/// system supports it, otherwise by dropping the pages behind the cursors.
+pub struct Copier {
+    buffer: AlignedBuffer,
+    src_direct: bool,
+    des_direct: bool,
+}
+
+impl Copier {
+    pub fn new(buf_sz: usize) -> Self {
+        let buf_sz = buf_sz.div_ceil(ALIGN).max(1) * ALIGN;
+        Self {
+            buffer: AlignedBuffer::new(buf_sz),
+            src_direct: false,
+            des_direct: false,
+        }
+    }
+
+    // O_DIRECT on the files whose cursor is aligned, the others go through the
+    // page cache, like the parts of a split file may have to
+    fn start(&mut self, src: &mut dyn FileHandle, des: &mut dyn FileHandle) -> std::io::Result<()> {
+        self.src_direct = direct_if_aligned(src)?;
+        self.des_direct = direct_if_aligned(des)?;
+        debug!(
+            "O_DIRECT on source: {}, on destination: {}",
+            self.src_direct, self.des_direct
+        );
+        Ok(())
+    }
+}
+
+fn direct_if_aligned(file: &mut dyn FileHandle) -> std::io::Result<bool> {
+    let aligned = file.stream_position()? % ALIGN as u64 == 0;
+    Ok(set_direct(file, aligned).is_ok() && aligned)
+}
+
+fn set_direct(file: &dyn FileHandle, on: bool) -> std::io::Result<()> {
+    let fd = file.raw_fd().ok_or(std::io::ErrorKind::Unsupported)?;
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let flags = if on {
+        flags | libc::O_DIRECT
+    } else {
+        flags & !libc::O_DIRECT
+    };
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Writes back the range and drops it from the page cache, failures only cost
+// cache space so they are ignored
+fn drop_cache(file: &dyn FileHandle, offset: u64, len: u64, written: bool) {
+    let Some(fd) = file.raw_fd() else {
+        return;
+    };
+    unsafe {
+        if written {
+            libc::sync_file_range(
+                fd,
+                offset as libc::off64_t,
+                len as libc::off64_t,
+                libc::SYNC_FILE_RANGE_WAIT_BEFORE
+                    | libc::SYNC_FILE_RANGE_WRITE
+                    | libc::SYNC_FILE_RANGE_WAIT_AFTER,
+            );
+        }
+        libc::posix_fadvise(
+            fd,
+            offset as libc::off_t,
+            len as libc::off_t,
+            libc::POSIX_FADV_DONTNEED,
+        );
+    }
+}
+
+impl FileCopy for Copier {
+    fn simple_copy_once(
+        &mut self,
+        src: &mut dyn FileHandle,
+        des: &mut dyn FileHandle,
+    ) -> std::io::Result<u64> {
+        // the cursors differ when copying the parts of split files
+        let src_offset = src.stream_position()?;
+        let des_offset = des.stream_position()?;
+        let n = src.read(self.buffer.as_mut_slice())?;
+        if n == 0 {
+            return Ok(0);
+        }
+
+        // only the tail may be unaligned, it goes through the page cache
+        if n % ALIGN != 0 {
+            if self.src_direct {
+                set_direct(src, false)?;
+                self.src_direct = false;
+            }
+            if self.des_direct {
+                set_direct(des, false)?;
+                self.des_direct = false;
+            }
+        }
+        des.write_all(&self.buffer.as_slice()[..n])?;
+
+        if !self.src_direct {
+            drop_cache(src, src_offset, n as u64, false);
+        }
+        if !self.des_direct {
+            drop_cache(des, des_offset, n as u64, true);
+        }
+
+        Ok(n as u64)
+    }
+
+    fn copy<'a>(
+        &'a mut self,
+        mut src: Handle,
+        mut des: Handle,
+        progress_callback: &'a dyn InCopyAction,
+    ) -> std::io::Result<u64> {
+        let mut copied = 0;
+
+        let len = src.stat()?.len;
+
+        progress_callback.set_length(len);
+        preallocate(&*des, len)?;
+
+        self.start(&mut *src, &mut *des)?;
+
+        loop {
+            match self.simple_copy_once(&mut *src, &mut *des) {
+                Ok(0) => break,
+                Ok(n) => {
+                    copied += n;
+                    progress_callback.in_copy_run(copied);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(copied)
+    }
+
+    fn copy_span(
+        &mut self,
+        src: &mut dyn FileHandle,
+        des: &mut dyn FileHandle,
+        limit: Option<u64>,
+        base: u64,
+        progress_callback: &dyn InCopyAction,
+    ) -> std::io::Result<u64> {
+        self.start(src, des)?;
+        copy_span(self, src, des, limit, base, progress_callback)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+
+    struct MockInCopyAction;
+
+    impl InCopyAction for MockInCopyAction {
+        fn set_length(&self, _: u64) {}
+        fn in_copy_run(&self, _: u64) {}
+    }
+
+    #[test]
+    fn copy_file_works() {
+        let mut copier = Copier::new(2 * ALIGN);
+        let content: Vec<u8> = (0..(3 * ALIGN + 100) as u32)
+            .map(|i| (i % 251) as u8)
+            .collect();
+
+        let temp_dir = tempfile::tempdir_in(".").unwrap();
+        let src_file_path = temp_dir.path().join("src.bin");
+        let des_file_path = temp_dir.path().join("des.bin");
+        File::create(&src_file_path)
+            .unwrap()
+            .write_all(&content)
+            .unwrap();
+
+        let ret = copier
+            .copy(
+                Box::new(File::open(&src_file_path).unwrap()),
+                Box::new(File::create(&des_file_path).unwrap()),
+                &MockInCopyAction,
+            )
+            .unwrap();
+
+        assert_eq!(ret, content.len() as u64);
+        assert_eq!(std::fs::read(&des_file_path).unwrap(), content);
+    }
+
+    #[test]
+    fn copy_span_splits_and_joins() {
+        let mut copier = Copier::new(2 * ALIGN);
+        let content: Vec<u8> = (0..(5 * ALIGN + 100) as u32)
+            .map(|i| (i % 251) as u8)
+            .collect();
+
+        let temp_dir = tempfile::tempdir_in(".").unwrap();
+        let src_file_path = temp_dir.path().join("src.bin");
+        std::fs::write(&src_file_path, &content).unwrap();
+
+        // an unaligned part size leaves the next cursors unaligned
+        let mut src = File::open(&src_file_path).unwrap();
+        let mut parts = Vec::new();
+        for limit in [3 * ALIGN as u64, 1000, 10 * ALIGN as u64] {
+            let part_path = temp_dir.path().join(format!("part{}", parts.len()));
+            let mut part = File::create(&part_path).unwrap();
+            copier
+                .copy_span(&mut src, &mut part, Some(limit), 0, &MockInCopyAction)
+                .unwrap();
+            parts.push(part_path);
+        }
+
+        let joined_path = temp_dir.path().join("joined.bin");
+        let mut joined = File::create(&joined_path).unwrap();
+        for part in parts.iter() {
+            copier
+                .copy_span(
+                    &mut File::open(part).unwrap(),
+                    &mut joined,
+                    None,
+                    0,
+                    &MockInCopyAction,
+                )
+                .unwrap();
+        }
+        assert_eq!(std::fs::read(&joined_path).unwrap(), content);
+    }
+}
@@ -1,2 +1,6 @@
 pub mod basecopier;
+pub mod compresscopier;
+pub mod directcopier;
+pub mod teecopier;
+pub mod uringcopier;
 pub mod zerocopier;
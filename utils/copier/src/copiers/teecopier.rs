@@ -0,0 +1,194 @@
+use super::super::{preallocate, FileCopy, InCopyAction};
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use storage::{FileHandle, Handle};
+
+// chunks queued per destination, a slow destination holds back the reads once they run out
+const QUEUE_DEPTH: usize = 4;
+
+/// Reads every chunk of the source once and writes it to several destinations,
+/// each from a thread of its own.
+pub struct Copier {
+    buf_sz: usize,
+    buffer: Vec<u8>,
+}
+
+impl Copier {
+    pub fn new(buf_sz: usize) -> Self {
+        Self {
+            buf_sz,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Copies `src` to all the destinations, their indexes tell the progress
+    /// of each apart. A failing source fails the copy, a failing destination
+    /// only its own result while the others go on.
+    pub fn copy_to_all(
+        &mut self,
+        mut src: Handle,
+        des: Vec<(usize, Handle)>,
+        progress_callback: &dyn InCopyAction,
+    ) -> std::io::Result<Vec<std::io::Result<u64>>> {
+        let len = src.stat()?.len;
+        progress_callback.set_length(len);
+
+        std::thread::scope(|s| {
+            let mut senders = Vec::with_capacity(des.len());
+            let mut handles = Vec::with_capacity(des.len());
+
+            for (index, mut file) in des {
+                let (tx, rx) = mpsc::sync_channel::<Arc<Vec<u8>>>(QUEUE_DEPTH);
+                let written = Arc::new(AtomicU64::new(0));
+                let counter = written.clone();
+
+                // returning early drops the receiver, the reader stops feeding it then
+                handles.push(s.spawn(move || {
+                    preallocate(&*file, len)?;
+                    for chunk in rx {
+                        file.write_all(&chunk)?;
+                        counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                    }
+                    Ok(counter.load(Ordering::Relaxed))
+                }));
+                senders.push((index, Some(tx), written));
+            }
+
+            let mut read = 0;
+            while senders.iter().any(|(_, tx, _)| tx.is_some()) {
+                let mut chunk = vec![0u8; self.buf_sz];
+                let n = match src.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                chunk.truncate(n);
+                let chunk = Arc::new(chunk);
+
+                for (_, tx, _) in senders.iter_mut() {
+                    if tx
+                        .as_ref()
+                        .is_some_and(|tx| tx.send(chunk.clone()).is_err())
+                    {
+                        *tx = None;
+                    }
+                }
+
+                read += n as u64;
+                progress_callback.in_copy_run(read);
+                for (index, _, written) in senders.iter() {
+                    progress_callback.in_copy_run_to(*index, written.load(Ordering::Relaxed));
+                }
+            }
+
+            let indexes: Vec<usize> = senders.drain(..).map(|(index, _, _)| index).collect();
+            let results: Vec<std::io::Result<u64>> = handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|_| Err(std::io::Error::other("writer thread panicked")))
+                })
+                .collect();
+
+            for (index, result) in indexes.iter().zip(results.iter()) {
+                if let Ok(written) = result {
+                    progress_callback.in_copy_run_to(*index, *written);
+                }
+            }
+
+            Ok(results)
+        })
+    }
+}
+
+impl FileCopy for Copier {
+    fn simple_copy_once(
+        &mut self,
+        src: &mut dyn FileHandle,
+        des: &mut dyn FileHandle,
+    ) -> std::io::Result<u64> {
+        if self.buffer.is_empty() {
+            self.buffer.resize(self.buf_sz, 0);
+        }
+
+        let n = src.read(&mut self.buffer)?;
+        des.write_all(&self.buffer[..n])?;
+        Ok(n as u64)
+    }
+
+    fn copy<'a>(
+        &'a mut self,
+        src: Handle,
+        des: Handle,
+        progress_callback: &'a dyn InCopyAction,
+    ) -> std::io::Result<u64> {
+        self.copy_to_all(src, vec![(0, des)], progress_callback)?
+            .remove(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::fs::File;
+    use std::os::fd::FromRawFd;
+
+    struct MockInCopyAction {
+        written: RefCell<Vec<u64>>,
+    }
+
+    impl InCopyAction for MockInCopyAction {
+        fn set_length(&self, _: u64) {}
+        fn in_copy_run(&self, _: u64) {}
+        fn in_copy_run_to(&self, index: usize, written: u64) {
+            self.written.borrow_mut()[index] = written;
+        }
+    }
+
+    #[test]
+    fn copy_to_all_isolates_failures() {
+        let content: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let mock_in_copy_action = MockInCopyAction {
+            written: RefCell::new(vec![0; 3]),
+        };
+        let mut copier = Copier::new(4096);
+
+        let temp_dir = tempfile::tempdir_in(".").unwrap();
+        let src_file_path = temp_dir.path().join("src.bin");
+        std::fs::write(&src_file_path, &content).unwrap();
+
+        // a pipe without a reader fails every write
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        unsafe { libc::close(fds[0]) };
+        let broken = unsafe { File::from_raw_fd(fds[1]) };
+
+        let create =
+            |name: &str| -> Handle { Box::new(File::create(temp_dir.path().join(name)).unwrap()) };
+        let results = copier
+            .copy_to_all(
+                Box::new(File::open(&src_file_path).unwrap()),
+                vec![
+                    (0, create("des0.bin")),
+                    (1, Box::new(broken)),
+                    (2, create("des2.bin")),
+                ],
+                &mock_in_copy_action,
+            )
+            .unwrap();
+
+        assert_eq!(results[0].as_ref().unwrap(), &(content.len() as u64));
+        assert!(results[1].is_err());
+        assert_eq!(results[2].as_ref().unwrap(), &(content.len() as u64));
+        for des in ["des0.bin", "des2.bin"] {
+            assert_eq!(std::fs::read(temp_dir.path().join(des)).unwrap(), content);
+        }
+        let written = mock_in_copy_action.written.borrow();
+        assert_eq!(written[0], content.len() as u64);
+        assert_eq!(written[2], content.len() as u64);
+    }
+}
@@ -0,0 +1,625 @@
+use super::super::{preallocate, FileCopy, InCopyAction};
+use super::basecopier;
+use io_uring::{opcode, squeue, types, IoUring};
+use log::debug;
+use std::io::SeekFrom;
+use std::os::fd::RawFd;
+use storage::{FileHandle, Handle};
+
+const READ: u64 = 0;
+const WRITE: u64 = 1;
+
+// A buffer and the chunk of the file it carries
+struct Slot {
+    buffer: Vec<u8>,
+    offset: u64,
+    len: usize,
+    filled: usize,
+    written: usize,
+    // the read came back short, so the linked write gets cancelled
+    short: bool,
+    // the chunk belongs to the next file, it is only read for now
+    ahead: bool,
+}
+
+// The device, inode and modification time behind a descriptor, telling the
+// file read ahead again once it gets copied
+type FileId = (libc::dev_t, libc::ino_t, libc::time_t, libc::c_long);
+
+fn file_id(fd: RawFd) -> Option<FileId> {
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } != 0 {
+        return None;
+    }
+    Some((stat.st_dev, stat.st_ino, stat.st_mtime, stat.st_mtime_nsec))
+}
+
+// The head of the next source, read while the current file drains
+struct Ahead {
+    fd: RawFd,
+    id: FileId,
+    size: u64,
+    // the offset of the next chunk to read
+    next: u64,
+    eof: bool,
+    // a read failed, the copy of the file reads it all again
+    failed: bool,
+    // keeps the descriptor open
+    _file: Handle,
+}
+
+impl Ahead {
+    fn new(file: Handle) -> Option<Self> {
+        let fd = file.raw_fd()?;
+        let stat = file
+            .stat()
+            .ok()
+            .filter(|stat| stat.is_file() && stat.len > 0)?;
+        Some(Self {
+            fd,
+            id: file_id(fd)?,
+            size: stat.len,
+            next: 0,
+            eof: false,
+            failed: false,
+            _file: file,
+        })
+    }
+}
+
+/// Keeps several linked reads and writes in flight within a file, and reads
+/// the head of the next source given to `prefetch` into the buffers the
+/// current file no longer needs, so the files overlap in the ring.
+pub struct Copier {
+    ring: IoUring,
+    slots: Vec<Slot>,
+    // whether the buffers are registered to the ring
+    fixed: bool,
+    inflight: usize,
+    // the source of the next copy, and the one being read ahead
+    offered: Option<Handle>,
+    ahead: Option<Ahead>,
+    // for the files without a descriptor, such as the ones of other storages
+    fallback: basecopier::Copier,
+}
+
+impl Copier {
+    /// Sets up a ring keeping up to `depth` chunks of `buf_sz` bytes in flight,
+    /// fails when the kernel has no io_uring support.
+    pub fn new(buf_sz: usize, depth: usize) -> std::io::Result<Self> {
+        let ring = IoUring::new((depth * 2) as u32)?;
+        let mut slots: Vec<Slot> = (0..depth)
+            .map(|_| Slot {
+                buffer: vec![0u8; buf_sz],
+                offset: 0,
+                len: 0,
+                filled: 0,
+                written: 0,
+                short: false,
+                ahead: false,
+            })
+            .collect();
+
+        let iovecs: Vec<libc::iovec> = slots
+            .iter_mut()
+            .map(|slot| libc::iovec {
+                iov_base: slot.buffer.as_mut_ptr().cast(),
+                iov_len: slot.buffer.len(),
+            })
+            .collect();
+        // registering needs locked memory, plain reads and writes work without it
+        let fixed = match unsafe { ring.submitter().register_buffers(&iovecs) } {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("Failed to register buffers: {}", e);
+                false
+            }
+        };
+
+        Ok(Self {
+            ring,
+            slots,
+            fixed,
+            inflight: 0,
+            offered: None,
+            ahead: None,
+            fallback: basecopier::Copier::new(buf_sz),
+        })
+    }
+
+    fn read_entry(&mut self, idx: usize, fd: RawFd) -> squeue::Entry {
+        let slot = &mut self.slots[idx];
+        let buf = unsafe { slot.buffer.as_mut_ptr().add(slot.filled) };
+        let len = (slot.len - slot.filled) as u32;
+        let offset = slot.offset + slot.filled as u64;
+
+        let entry = if self.fixed {
+            opcode::ReadFixed::new(types::Fd(fd), buf, len, idx as u16)
+                .offset(offset)
+                .build()
+        } else {
+            opcode::Read::new(types::Fd(fd), buf, len)
+                .offset(offset)
+                .build()
+        };
+        entry.user_data((idx as u64) << 1 | READ)
+    }
+
+    // Writes the rest of the chunk, assuming the read before it fills the buffer
+    fn write_entry(&self, idx: usize, fd: RawFd) -> squeue::Entry {
+        let slot = &self.slots[idx];
+        let buf = unsafe { slot.buffer.as_ptr().add(slot.written) };
+        let len = (slot.len - slot.written) as u32;
+        let offset = slot.offset + slot.written as u64;
+
+        let entry = if self.fixed {
+            opcode::WriteFixed::new(types::Fd(fd), buf, len, idx as u16)
+                .offset(offset)
+                .build()
+        } else {
+            opcode::Write::new(types::Fd(fd), buf, len)
+                .offset(offset)
+                .build()
+        };
+        entry.user_data((idx as u64) << 1 | WRITE)
+    }
+
+    fn push(&mut self, entries: &[squeue::Entry]) -> std::io::Result<()> {
+        let mut sq = self.ring.submission();
+        // every slot has at most a read and a write queued, the ring holds both
+        unsafe { sq.push_multiple(entries) }
+            .map_err(|_| std::io::Error::other("io_uring submission queue is full"))?;
+        drop(sq);
+        self.inflight += entries.len();
+        Ok(())
+    }
+
+    // Queues a read linked to the write of the same buffer
+    fn push_pair(&mut self, idx: usize, sfd: RawFd, dfd: RawFd) -> std::io::Result<()> {
+        let read = self.read_entry(idx, sfd).flags(squeue::Flags::IO_LINK);
+        let write = self.write_entry(idx, dfd);
+        self.push(&[read, write])
+    }
+
+    fn start_chunk(
+        &mut self,
+        idx: usize,
+        offset: u64,
+        len: usize,
+        sfd: RawFd,
+        dfd: RawFd,
+    ) -> std::io::Result<()> {
+        let slot = &mut self.slots[idx];
+        slot.offset = offset;
+        slot.len = len;
+        slot.filled = 0;
+        slot.written = 0;
+        slot.short = false;
+        self.push_pair(idx, sfd, dfd)
+    }
+
+    // Reads the next chunk of the next file into an idle slot, the first slot
+    // is left to simple_copy_once
+    fn read_ahead(&mut self, idx: usize) -> std::io::Result<()> {
+        let buf_sz = self.slots[idx].buffer.len() as u64;
+        let Some(ahead) = self
+            .ahead
+            .as_mut()
+            .filter(|ahead| idx > 0 && !ahead.eof && !ahead.failed && ahead.next < ahead.size)
+        else {
+            return Ok(());
+        };
+        let len = buf_sz.min(ahead.size - ahead.next);
+        let fd = ahead.fd;
+        let slot = &mut self.slots[idx];
+        slot.ahead = true;
+        slot.offset = ahead.next;
+        slot.len = len as usize;
+        slot.filled = 0;
+        slot.written = 0;
+        slot.short = false;
+        ahead.next += len;
+
+        let read = self.read_entry(idx, fd);
+        self.push(&[read])
+    }
+
+    // A read of the next file is done, a short one is resumed
+    fn ahead_read(&mut self, idx: usize, res: i32) -> std::io::Result<()> {
+        let Some(ahead) = self.ahead.as_mut() else {
+            return Ok(());
+        };
+        let slot = &mut self.slots[idx];
+        if res <= 0 {
+            // the copy of the file reads the rest itself
+            slot.len = slot.filled;
+            ahead.eof = true;
+            ahead.failed |= res < 0;
+            return Ok(());
+        }
+        slot.filled += res as usize;
+        if slot.filled < slot.len {
+            let fd = ahead.fd;
+            let read = self.read_entry(idx, fd);
+            self.push(&[read])?;
+        }
+        Ok(())
+    }
+
+    fn forget_ahead(&mut self) {
+        self.offered = None;
+        self.ahead = None;
+        for slot in self.slots.iter_mut() {
+            slot.ahead = false;
+        }
+    }
+
+    // Waits for the operations still in flight, so that none of them completes
+    // into the next copy
+    fn drain(&mut self) {
+        while self.inflight > 0 {
+            if self.ring.submit_and_wait(1).is_err() {
+                break;
+            }
+            let done = self.ring.completion().count();
+            self.inflight = self.inflight.saturating_sub(done);
+        }
+    }
+
+    fn run(
+        &mut self,
+        src: &dyn FileHandle,
+        des: &dyn FileHandle,
+        progress_callback: &dyn InCopyAction,
+    ) -> std::io::Result<u64> {
+        let (Some(sfd), Some(dfd)) = (src.raw_fd(), des.raw_fd()) else {
+            return Err(std::io::ErrorKind::Unsupported.into());
+        };
+        let size = src.stat()?.len;
+        let buf_sz = self.slots[0].buffer.len() as u64;
+        let mut next = 0u64;
+        let mut copied = 0u64;
+        let mut eof = false;
+
+        progress_callback.set_length(size);
+        preallocate(des, size)?;
+
+        // the head of this file may have been read during the last copy
+        let id = file_id(sfd);
+        let ready = self
+            .ahead
+            .take()
+            .filter(|ahead| !ahead.failed && Some(ahead.id) == id && ahead.size == size);
+        if let Some(ready) = &ready {
+            next = ready.next;
+            eof = ready.eof;
+        }
+        self.ahead = self.offered.take().and_then(Ahead::new);
+
+        for idx in 0..self.slots.len() {
+            if std::mem::take(&mut self.slots[idx].ahead) && ready.is_some() {
+                // only the write is left
+                let slot = &mut self.slots[idx];
+                slot.written = 0;
+                if slot.len > 0 {
+                    let write = self.write_entry(idx, dfd);
+                    self.push(&[write])?;
+                }
+                continue;
+            }
+            if !eof && next < size {
+                let len = buf_sz.min(size - next);
+                self.start_chunk(idx, next, len as usize, sfd, dfd)?;
+                next += len;
+            } else {
+                self.read_ahead(idx)?;
+            }
+        }
+
+        while self.inflight > 0 {
+            self.ring.submit_and_wait(1)?;
+            let cqes: Vec<(u64, i32)> = self
+                .ring
+                .completion()
+                .map(|cqe| (cqe.user_data(), cqe.result()))
+                .collect();
+            self.inflight -= cqes.len();
+
+            for (user_data, res) in cqes {
+                let idx = (user_data >> 1) as usize;
+
+                if user_data & 1 == READ {
+                    if self.slots[idx].ahead {
+                        self.ahead_read(idx, res)?;
+                        continue;
+                    }
+                    if res < 0 {
+                        return Err(std::io::Error::from_raw_os_error(-res));
+                    }
+                    let slot = &mut self.slots[idx];
+                    slot.filled += res as usize;
+                    if slot.filled < slot.len {
+                        slot.short = true;
+                        if res == 0 {
+                            // the source shrank while being copied
+                            slot.len = slot.filled;
+                            eof = true;
+                        }
+                    }
+                    continue;
+                }
+
+                if res == -libc::ECANCELED && self.slots[idx].short {
+                    self.slots[idx].short = false;
+                    let slot = &self.slots[idx];
+                    if slot.filled < slot.len {
+                        self.push_pair(idx, sfd, dfd)?;
+                    } else if slot.written < slot.len {
+                        let write = self.write_entry(idx, dfd);
+                        self.push(&[write])?;
+                    } else {
+                        self.read_ahead(idx)?;
+                    }
+                    continue;
+                }
+                if res < 0 {
+                    return Err(std::io::Error::from_raw_os_error(-res));
+                }
+
+                let slot = &mut self.slots[idx];
+                slot.written += res as usize;
+                copied += res as u64;
+                progress_callback.in_copy_run(copied);
+
+                if slot.written < slot.len {
+                    let write = self.write_entry(idx, dfd);
+                    self.push(&[write])?;
+                } else if !eof && next < size {
+                    let len = buf_sz.min(size - next);
+                    self.start_chunk(idx, next, len as usize, sfd, dfd)?;
+                    next += len;
+                } else {
+                    self.read_ahead(idx)?;
+                }
+            }
+        }
+
+        Ok(copied)
+    }
+}
+
+impl FileCopy for Copier {
+    fn prefetch(&mut self, src: Handle) {
+        self.offered = Some(src);
+    }
+
+    fn simple_copy_once(
+        &mut self,
+        src: &mut dyn FileHandle,
+        des: &mut dyn FileHandle,
+    ) -> std::io::Result<u64> {
+        let (Some(sfd), Some(dfd)) = (src.raw_fd(), des.raw_fd()) else {
+            return self.fallback.simple_copy_once(src, des);
+        };
+        let offset = src.stream_position()?;
+        let len = self.slots[0].buffer.len();
+        self.slots[0].offset = offset;
+        self.slots[0].len = len;
+        self.slots[0].filled = 0;
+        self.slots[0].written = 0;
+
+        let read = self.read_entry(0, sfd);
+        self.push(&[read])?;
+        self.ring.submit_and_wait(1)?;
+        let res = self.ring.completion().next().map_or(0, |cqe| cqe.result());
+        self.inflight -= 1;
+        if res <= 0 {
+            return if res < 0 {
+                Err(std::io::Error::from_raw_os_error(-res))
+            } else {
+                Ok(0)
+            };
+        }
+
+        self.slots[0].len = res as usize;
+        self.slots[0].offset = des.stream_position()?;
+        while self.slots[0].written < self.slots[0].len {
+            let write = self.write_entry(0, dfd);
+            self.push(&[write])?;
+            self.ring.submit_and_wait(1)?;
+            let res = self.ring.completion().next().map_or(0, |cqe| cqe.result());
+            self.inflight -= 1;
+            if res < 0 {
+                return Err(std::io::Error::from_raw_os_error(-res));
+            }
+            self.slots[0].written += res as usize;
+        }
+
+        src.seek(SeekFrom::Current(res as i64))?;
+        des.seek(SeekFrom::Current(res as i64))?;
+        Ok(res as u64)
+    }
+
+    fn copy<'a>(
+        &'a mut self,
+        mut src: Handle,
+        mut des: Handle,
+        progress_callback: &'a dyn InCopyAction,
+    ) -> std::io::Result<u64> {
+        // pipes and devices cannot be read at an offset
+        if src.raw_fd().is_none() || des.raw_fd().is_none() || !src.stat()?.is_file() {
+            return self.fallback.copy(src, des, progress_callback);
+        }
+
+        let mut copied = match self.run(&*src, &*des, progress_callback) {
+            Ok(copied) => copied,
+            Err(e) => {
+                self.drain();
+                self.forget_ahead();
+                return Err(e);
+            }
+        };
+
+        // the size is only a hint, procfs files have none and sources may grow
+        src.seek(SeekFrom::Start(copied))?;
+        des.seek(SeekFrom::Start(copied))?;
+        loop {
+            let n = self.simple_copy_once(&mut *src, &mut *des)?;
+            if n == 0 {
+                break;
+            }
+            copied += n;
+            progress_callback.in_copy_run(copied);
+        }
+        Ok(copied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use tempfile;
+
+    struct MockInCopyAction {
+        copied: Cell<u64>,
+    }
+
+    impl InCopyAction for MockInCopyAction {
+        fn set_length(&self, _: u64) {}
+        fn in_copy_run(&self, copied: u64) {
+            self.copied.set(copied);
+        }
+    }
+
+    #[test]
+    fn copy_file_works() {
+        // io_uring may be disabled, the job falls back to the other copiers then
+        let Ok(mut copier) = Copier::new(4096, 4) else {
+            return;
+        };
+        let mock_in_copy_action = MockInCopyAction {
+            copied: Cell::new(0),
+        };
+        let content: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+
+        let temp_dir = tempfile::tempdir_in(".").unwrap();
+        let src_file_path = temp_dir.path().join("src.bin");
+        let des_file_path = temp_dir.path().join("des.bin");
+        File::create(&src_file_path)
+            .unwrap()
+            .write_all(&content)
+            .unwrap();
+
+        for _ in 0..2 {
+            let ret = copier
+                .copy(
+                    Box::new(File::open(&src_file_path).unwrap()),
+                    Box::new(File::create(&des_file_path).unwrap()),
+                    &mock_in_copy_action,
+                )
+                .unwrap();
+            assert_eq!(ret, content.len() as u64);
+            assert_eq!(mock_in_copy_action.copied.get(), content.len() as u64);
+
+            let mut des_content = Vec::new();
+            File::open(&des_file_path)
+                .unwrap()
+                .read_to_end(&mut des_content)
+                .unwrap();
+            assert_eq!(content, des_content);
+        }
+    }
+
+    #[test]
+    fn copy_reads_past_the_stat_size() {
+        let Ok(mut copier) = Copier::new(4096, 4) else {
+            return;
+        };
+        let mock_in_copy_action = MockInCopyAction {
+            copied: Cell::new(0),
+        };
+        let temp_dir = tempfile::tempdir_in(".").unwrap();
+        let des_file_path = temp_dir.path().join("des.txt");
+
+        // procfs files report a zero size
+        let ret = copier
+            .copy(
+                Box::new(File::open("/proc/self/status").unwrap()),
+                Box::new(File::create(&des_file_path).unwrap()),
+                &mock_in_copy_action,
+            )
+            .unwrap();
+        let des_content = std::fs::read_to_string(&des_file_path).unwrap();
+        assert!(des_content.starts_with("Name:"));
+        assert_eq!(ret, des_content.len() as u64);
+        assert_eq!(mock_in_copy_action.copied.get(), ret);
+    }
+
+    #[test]
+    fn copy_reads_the_next_file_ahead() {
+        let Ok(mut copier) = Copier::new(4096, 4) else {
+            return;
+        };
+        let mock_in_copy_action = MockInCopyAction {
+            copied: Cell::new(0),
+        };
+        let temp_dir = tempfile::tempdir_in(".").unwrap();
+        let contents: Vec<Vec<u8>> = [3_000u32, 10_000, 50_000]
+            .iter()
+            .enumerate()
+            .map(|(i, len)| (0..*len).map(|j| ((j + i as u32) % 251) as u8).collect())
+            .collect();
+        let src_paths: Vec<_> = contents
+            .iter()
+            .enumerate()
+            .map(|(i, content)| {
+                let path = temp_dir.path().join(format!("src{}.bin", i));
+                std::fs::write(&path, content).unwrap();
+                path
+            })
+            .collect();
+
+        for (i, content) in contents.iter().enumerate() {
+            if let Some(next) = src_paths.get(i + 1) {
+                copier.prefetch(Box::new(File::open(next).unwrap()));
+            }
+            let des_path = temp_dir.path().join(format!("des{}.bin", i));
+            let ret = copier
+                .copy(
+                    Box::new(File::open(&src_paths[i]).unwrap()),
+                    Box::new(File::create(&des_path).unwrap()),
+                    &mock_in_copy_action,
+                )
+                .unwrap();
+            assert_eq!(ret, content.len() as u64);
+            assert_eq!(&std::fs::read(&des_path).unwrap(), content);
+            // the idle buffers hold the head of the next file
+            assert_eq!(
+                copier.slots.iter().any(|slot| slot.ahead),
+                i + 1 < contents.len()
+            );
+        }
+
+        // a file read ahead but not copied is dropped
+        copier.prefetch(Box::new(File::open(&src_paths[2]).unwrap()));
+        let des_path = temp_dir.path().join("other.bin");
+        copier
+            .copy(
+                Box::new(File::open(&src_paths[0]).unwrap()),
+                Box::new(File::create(&des_path).unwrap()),
+                &mock_in_copy_action,
+            )
+            .unwrap();
+        copier
+            .copy(
+                Box::new(File::open(&src_paths[1]).unwrap()),
+                Box::new(File::create(&des_path).unwrap()),
+                &mock_in_copy_action,
+            )
+            .unwrap();
+        assert_eq!(std::fs::read(&des_path).unwrap(), contents[1]);
+    }
+}
@@ -1,56 +1,217 @@
-use super::super::FileCopy;
-use std::os::fd::{AsRawFd, RawFd};
+use super::super::{preallocate, FileCopy, InCopyAction};
+use super::basecopier;
+use log::debug;
+use std::io::{Seek, SeekFrom};
+use std::os::fd::RawFd;
+use storage::{FileHandle, Handle, Kind};
+
 pub struct Copier {
     buf_sz: usize,
+    // the source offset reached so far, a failed copy can resume from it
+    offset: libc::off_t,
+    // for the files sendfile refuses
+    fallback: basecopier::Copier,
+}
+
+// Reports the progress of the fallback after what sendfile has copied
+struct Shifted<'a> {
+    inner: &'a dyn InCopyAction,
+    base: u64,
+}
+
+impl InCopyAction for Shifted<'_> {
+    fn set_length(&self, _: u64) {}
+
+    fn in_copy_run(&self, copied: u64) {
+        self.inner.in_copy_run(self.base + copied);
+    }
 }
 
 impl Copier {
     pub fn new(buf_sz: usize) -> Self {
-        Self { buf_sz }
+        Self {
+            buf_sz,
+            offset: 0,
+            fallback: basecopier::Copier::new(buf_sz),
+        }
+    }
+
+    /// The source offset the last copy reached.
+    pub fn offset(&self) -> u64 {
+        self.offset as u64
+    }
+
+    /// Continues a copy that stopped at `offset`, the destination is taken as
+    /// holding the first `offset` bytes already.
+    pub fn resume(
+        &mut self,
+        src: Handle,
+        mut des: Handle,
+        offset: u64,
+        progress_callback: &dyn InCopyAction,
+    ) -> std::io::Result<u64> {
+        des.seek(SeekFrom::Start(offset))?;
+        self.copy_from(src, des, offset, progress_callback)
     }
 
-    fn zero_copy(sfd: RawFd, dfd: RawFd, count: usize) -> std::io::Result<u64> {
-        let ret = unsafe { libc::sendfile(dfd, sfd, std::ptr::null_mut(), count) };
-        if ret < 0 {
-            Err(std::io::Error::last_os_error())
-        } else {
-            Ok(ret as u64)
+    fn zero_copy(
+        sfd: RawFd,
+        dfd: RawFd,
+        offset: &mut libc::off_t,
+        count: usize,
+    ) -> std::io::Result<u64> {
+        loop {
+            let ret = unsafe { libc::sendfile(dfd, sfd, offset, count) };
+            if ret >= 0 {
+                return Ok(ret as u64);
+            }
+
+            let err = std::io::Error::last_os_error();
+            match err.kind() {
+                std::io::ErrorKind::Interrupted => continue,
+                // a non-blocking destination is full, wait until it drains
+                std::io::ErrorKind::WouldBlock => {
+                    let mut pfd = libc::pollfd {
+                        fd: dfd,
+                        events: libc::POLLOUT,
+                        revents: 0,
+                    };
+                    unsafe { libc::poll(&mut pfd, 1, 100) };
+                }
+                _ => return Err(err),
+            }
+        }
+    }
+
+    // Whether sendfile does not support the files, rather than failing on them
+    fn refused(err: &std::io::Error) -> bool {
+        matches!(
+            err.raw_os_error(),
+            Some(libc::EINVAL) | Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) | Some(libc::ESPIPE)
+        )
+    }
+
+    fn copy_from(
+        &mut self,
+        mut src: Handle,
+        mut des: Handle,
+        start: u64,
+        progress_callback: &dyn InCopyAction,
+    ) -> std::io::Result<u64> {
+        let stat = src.stat()?;
+        let is_file = stat.kind == Kind::File;
+        let mut copied = 0;
+
+        self.offset = start as libc::off_t;
+        progress_callback.set_length(stat.len);
+        preallocate(&*des, stat.len)?;
+
+        // procfs and sysfs files claim a size of 0, sendfile copies nothing from them,
+        // nor from or to the files of other storages
+        let mut use_fallback =
+            !is_file || stat.len == 0 || src.raw_fd().is_none() || des.raw_fd().is_none();
+        if !use_fallback {
+            src.seek(SeekFrom::Start(start))?;
         }
+
+        while !use_fallback {
+            match self.simple_copy_once(&mut *src, &mut *des) {
+                Ok(0) => return Ok(copied),
+                Ok(n) => {
+                    copied += n;
+                    progress_callback.in_copy_run(start + copied);
+                }
+                Err(e) if Self::refused(&e) => {
+                    debug!("sendfile refused ({}), falling back to buffered copy", e);
+                    use_fallback = true;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        if is_file {
+            src.seek(SeekFrom::Start(self.offset as u64))?;
+        }
+        let shifted = Shifted {
+            inner: progress_callback,
+            base: start + copied,
+        };
+        let rest = self.fallback.copy(src, des, &shifted)?;
+        self.offset += rest as libc::off_t;
+
+        Ok(copied + rest)
     }
 }
 
 impl FileCopy for Copier {
     fn simple_copy_once(
         &mut self,
-        src: &mut std::fs::File,
-        des: &mut std::fs::File,
+        src: &mut dyn FileHandle,
+        des: &mut dyn FileHandle,
     ) -> std::io::Result<u64> {
-        let sfd = src.as_raw_fd();
-        let dfd = des.as_raw_fd();
+        let (Some(sfd), Some(dfd)) = (src.raw_fd(), des.raw_fd()) else {
+            return self.fallback.simple_copy_once(src, des);
+        };
 
-        Self::zero_copy(sfd, dfd, self.buf_sz)
+        // starts from the cursor of the source and moves it on, like a read does
+        self.offset = src.stream_position()? as libc::off_t;
+        let n = Self::zero_copy(sfd, dfd, &mut self.offset, self.buf_sz)?;
+        src.seek(SeekFrom::Start(self.offset as u64))?;
+        Ok(n)
+    }
+
+    fn copy<'a>(
+        &'a mut self,
+        src: Handle,
+        des: Handle,
+        progress_callback: &'a dyn InCopyAction,
+    ) -> std::io::Result<u64> {
+        self.copy_from(src, des, 0, progress_callback)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::InCopyAction;
+    use std::cell::Cell;
     use std::fs::File;
     use std::io::{Read, Write};
+    use std::os::fd::{AsRawFd, FromRawFd};
     use tempfile;
 
-    struct MockInCopyAction;
+    struct MockInCopyAction {
+        copied: Cell<u64>,
+    }
+
+    impl MockInCopyAction {
+        fn new() -> Self {
+            Self {
+                copied: Cell::new(0),
+            }
+        }
+    }
 
     impl InCopyAction for MockInCopyAction {
         fn set_length(&self, _: u64) {}
-        fn in_copy_run(&self, _: u64) {}
+        fn in_copy_run(&self, copied: u64) {
+            self.copied.set(copied);
+        }
+    }
+
+    fn test_content(len: u32) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn pipe() -> (File, File) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
     }
 
     #[test]
     fn copy_file_works() {
         let test_str = String::from("copy_file_works test content!");
-        let mock_in_copy_action = MockInCopyAction;
+        let mock_in_copy_action = MockInCopyAction::new();
         let mut copier = Copier::new(4096 * 1024);
 
         let temp_dir = tempfile::tempdir_in(".").unwrap();
@@ -68,7 +229,7 @@ mod tests {
 
         {
             let src_file_reopen = File::open(&src_file_path).unwrap();
-            let des_file = File::create(&des_file_path).unwrap();
+            let des_file = File::create(des_file_path).unwrap();
 
             print!(
                 "from {} to {}",
@@ -77,17 +238,185 @@ mod tests {
             );
 
             let ret = copier
-                .copy(src_file_reopen, des_file, &mock_in_copy_action)
+                .copy(
+                    Box::new(src_file_reopen),
+                    Box::new(des_file),
+                    &mock_in_copy_action,
+                )
                 .unwrap();
 
             println!(", {} bytes copied.", ret);
         }
 
-        let mut des_file = File::open(&des_file_path).unwrap();
+        let mut des_file = File::open(des_file_path).unwrap();
         let mut des_content = String::new();
 
         des_file.read_to_string(&mut des_content).unwrap();
 
         assert_eq!(test_str, des_content);
     }
+
+    #[test]
+    fn copy_in_chunks_tracks_offset() {
+        let content = test_content(100_000);
+        let mock_in_copy_action = MockInCopyAction::new();
+        let mut copier = Copier::new(4096);
+
+        let temp_dir = tempfile::tempdir_in(".").unwrap();
+        let src_file_path = temp_dir.path().join("src.bin");
+        let des_file_path = temp_dir.path().join("des.bin");
+        std::fs::write(&src_file_path, &content).unwrap();
+
+        let ret = copier
+            .copy(
+                Box::new(File::open(&src_file_path).unwrap()),
+                Box::new(File::create(&des_file_path).unwrap()),
+                &mock_in_copy_action,
+            )
+            .unwrap();
+
+        assert_eq!(ret, content.len() as u64);
+        assert_eq!(copier.offset(), content.len() as u64);
+        assert_eq!(mock_in_copy_action.copied.get(), content.len() as u64);
+        assert_eq!(std::fs::read(&des_file_path).unwrap(), content);
+    }
+
+    #[test]
+    fn resume_copy() {
+        let content = test_content(100_000);
+        let mock_in_copy_action = MockInCopyAction::new();
+        let mut copier = Copier::new(4096);
+
+        let temp_dir = tempfile::tempdir_in(".").unwrap();
+        let src_file_path = temp_dir.path().join("src.bin");
+        let des_file_path = temp_dir.path().join("des.bin");
+        std::fs::write(&src_file_path, &content).unwrap();
+        // an interrupted copy left the first part behind
+        std::fs::write(&des_file_path, &content[..30_000]).unwrap();
+
+        let ret = copier
+            .resume(
+                Box::new(File::open(&src_file_path).unwrap()),
+                Box::new(File::options().write(true).open(&des_file_path).unwrap()),
+                30_000,
+                &mock_in_copy_action,
+            )
+            .unwrap();
+
+        assert_eq!(ret, 70_000);
+        assert_eq!(mock_in_copy_action.copied.get(), content.len() as u64);
+        assert_eq!(std::fs::read(&des_file_path).unwrap(), content);
+    }
+
+    #[test]
+    fn fallback_for_pipe_source() {
+        let content = test_content(200_000);
+        let mock_in_copy_action = MockInCopyAction::new();
+        let mut copier = Copier::new(4096);
+
+        let temp_dir = tempfile::tempdir_in(".").unwrap();
+        let des_file_path = temp_dir.path().join("des.bin");
+
+        let (reader, mut writer) = pipe();
+        let to_write = content.clone();
+        let handle = std::thread::spawn(move || writer.write_all(&to_write).unwrap());
+
+        let ret = copier
+            .copy(
+                Box::new(reader),
+                Box::new(File::create(&des_file_path).unwrap()),
+                &mock_in_copy_action,
+            )
+            .unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(ret, content.len() as u64);
+        assert_eq!(std::fs::read(&des_file_path).unwrap(), content);
+    }
+
+    #[test]
+    fn fallback_when_sendfile_refuses() {
+        let content = test_content(100_000);
+        let mock_in_copy_action = MockInCopyAction::new();
+        let mut copier = Copier::new(4096);
+
+        let temp_dir = tempfile::tempdir_in(".").unwrap();
+        let src_file_path = temp_dir.path().join("src.bin");
+        let des_file_path = temp_dir.path().join("des.bin");
+        std::fs::write(&src_file_path, &content).unwrap();
+
+        // sendfile refuses destinations opened for appending with EINVAL
+        let des = File::options()
+            .create(true)
+            .append(true)
+            .open(&des_file_path)
+            .unwrap();
+        let ret = copier
+            .copy(
+                Box::new(File::open(&src_file_path).unwrap()),
+                Box::new(des),
+                &mock_in_copy_action,
+            )
+            .unwrap();
+
+        assert_eq!(ret, content.len() as u64);
+        assert_eq!(mock_in_copy_action.copied.get(), content.len() as u64);
+        assert_eq!(std::fs::read(&des_file_path).unwrap(), content);
+    }
+
+    #[test]
+    fn fallback_for_procfs_source() {
+        let mock_in_copy_action = MockInCopyAction::new();
+        let mut copier = Copier::new(4096);
+
+        let temp_dir = tempfile::tempdir_in(".").unwrap();
+        let des_file_path = temp_dir.path().join("status");
+
+        let ret = copier
+            .copy(
+                Box::new(File::open("/proc/self/status").unwrap()),
+                Box::new(File::create(&des_file_path).unwrap()),
+                &mock_in_copy_action,
+            )
+            .unwrap();
+
+        assert!(ret > 0);
+        assert!(std::fs::read_to_string(&des_file_path)
+            .unwrap()
+            .starts_with("Name:"));
+    }
+
+    #[test]
+    fn retry_on_full_nonblocking_destination() {
+        // larger than the pipe buffer, so sendfile hits EAGAIN
+        let content = test_content(1024 * 1024);
+        let mock_in_copy_action = MockInCopyAction::new();
+        let mut copier = Copier::new(1024 * 1024);
+
+        let temp_dir = tempfile::tempdir_in(".").unwrap();
+        let src_file_path = temp_dir.path().join("src.bin");
+        std::fs::write(&src_file_path, &content).unwrap();
+
+        let (mut reader, writer) = pipe();
+        unsafe {
+            let flags = libc::fcntl(writer.as_raw_fd(), libc::F_GETFL);
+            libc::fcntl(writer.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK);
+        }
+        let handle = std::thread::spawn(move || {
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        let ret = copier
+            .copy(
+                Box::new(File::open(&src_file_path).unwrap()),
+                Box::new(writer),
+                &mock_in_copy_action,
+            )
+            .unwrap();
+
+        assert_eq!(ret, content.len() as u64);
+        assert_eq!(handle.join().unwrap(), content);
+    }
 }
@@ -1,24 +1,50 @@
 pub mod copiers;
 
+use std::io::SeekFrom;
+use storage::{FileHandle, Handle};
+
+/// Reserves `len` bytes for the destination up front, so that a full disk
+/// fails the copy at once and the file gets less fragmented.
+pub fn preallocate(des: &dyn FileHandle, len: u64) -> std::io::Result<()> {
+    // only local files can reserve their space
+    let Some(fd) = des.raw_fd().filter(|_| len > 0) else {
+        return Ok(());
+    };
+
+    let ret = unsafe { libc::fallocate(fd, libc::FALLOC_FL_KEEP_SIZE, 0, len as libc::off_t) };
+    if ret == 0 {
+        return Ok(());
+    }
+
+    let err = std::io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::ENOSPC) | Some(libc::EDQUOT) | Some(libc::EFBIG) => Err(err),
+        // not every file system or file type supports it
+        _ => Ok(()),
+    }
+}
+
 pub trait FileCopy {
     fn simple_copy_once(
         &mut self,
-        src: &mut std::fs::File,
-        des: &mut std::fs::File,
+        src: &mut dyn FileHandle,
+        des: &mut dyn FileHandle,
     ) -> std::io::Result<u64>;
 
     fn copy<'a>(
         &'a mut self,
-        mut src: std::fs::File,
-        mut des: std::fs::File,
+        mut src: Handle,
+        mut des: Handle,
         progress_callback: &'a dyn InCopyAction,
     ) -> std::io::Result<u64> {
         let mut copied = 0;
+        let len = src.stat()?.len;
 
-        progress_callback.set_length(src.metadata().unwrap().len());
+        progress_callback.set_length(len);
+        preallocate(&*des, len)?;
 
         loop {
-            match Self::simple_copy_once(self, &mut src, &mut des) {
+            match Self::simple_copy_once(self, &mut *src, &mut *des) {
                 Ok(0) => break,
                 Ok(n) => {
                     copied += n;
@@ -30,9 +56,222 @@ pub trait FileCopy {
 
         Ok(copied)
     }
+
+    /// Copies from the cursor of `src` to the cursor of `des` until the end of
+    /// `src` or `limit` bytes, reporting the progress on top of `base`. The
+    /// parts of split and joined files are copied with it.
+    fn copy_span(
+        &mut self,
+        src: &mut dyn FileHandle,
+        des: &mut dyn FileHandle,
+        limit: Option<u64>,
+        base: u64,
+        progress_callback: &dyn InCopyAction,
+    ) -> std::io::Result<u64> {
+        copy_span(self, src, des, limit, base, progress_callback)
+    }
+
+    /// Offers the source of the next copy, to be read while the current one
+    /// is written. The copiers working a file at a time ignore it.
+    fn prefetch(&mut self, _src: Handle) {}
+
+    /// The bytes the last copy wrote, when they differ from the bytes read.
+    fn output_len(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// The loop of `FileCopy::copy_span`, for the copiers preparing the files first.
+pub fn copy_span<C: FileCopy + ?Sized>(
+    copier: &mut C,
+    src: &mut dyn FileHandle,
+    des: &mut dyn FileHandle,
+    limit: Option<u64>,
+    base: u64,
+    progress_callback: &dyn InCopyAction,
+) -> std::io::Result<u64> {
+    let mut copied = 0;
+
+    loop {
+        let n = copier.simple_copy_once(src, des)?;
+        if n == 0 {
+            break;
+        }
+        copied += n;
+
+        if let Some(limit) = limit.filter(|limit| copied >= *limit) {
+            // the last chunk may run past the limit, the rest goes back to the source
+            let excess = (copied - limit) as i64;
+            if excess > 0 {
+                src.seek(SeekFrom::Current(-excess))?;
+                let end = des.seek(SeekFrom::Current(-excess))?;
+                des.set_len(end)?;
+            }
+            progress_callback.in_copy_run(base + limit);
+            return Ok(limit);
+        }
+        progress_callback.in_copy_run(base + copied);
+    }
+
+    Ok(copied)
 }
 
 pub trait InCopyAction {
     fn set_length(&self, length: u64);
     fn in_copy_run(&self, copied: u64);
+
+    /// The bytes written to the destination `index` when copying to several at once.
+    fn in_copy_run_to(&self, _index: usize, _written: u64) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+    use storage::storages::memstorage::{Faults, MemStorage};
+    use storage::Storage;
+
+    struct Quiet;
+
+    impl InCopyAction for Quiet {
+        fn set_length(&self, _: u64) {}
+        fn in_copy_run(&self, _: u64) {}
+    }
+
+    #[derive(Default)]
+    struct Progress {
+        length: RefCell<u64>,
+        copied: RefCell<Vec<u64>>,
+    }
+
+    impl InCopyAction for Progress {
+        fn set_length(&self, length: u64) {
+            *self.length.borrow_mut() = length;
+        }
+        fn in_copy_run(&self, copied: u64) {
+            self.copied.borrow_mut().push(copied);
+        }
+    }
+
+    fn mem_copy(
+        copier: &mut dyn FileCopy,
+        faults: Faults,
+    ) -> (MemStorage, Progress, std::io::Result<u64>) {
+        let content: Vec<u8> = (0..40_000u32).map(|i| (i % 251) as u8).collect();
+        let storage = MemStorage::new();
+        storage.write_file("/src.bin", &content).unwrap();
+        storage.set_faults(faults);
+
+        let progress = Progress::default();
+        let src = storage.open_read("/src.bin").unwrap();
+        let des = storage.open_write("/des.bin").unwrap();
+        let ret = copier.copy(src, des, &progress);
+        (storage, progress, ret)
+    }
+
+    #[test]
+    fn copy_survives_short_reads() {
+        let mut copiers: Vec<Box<dyn FileCopy>> = vec![
+            Box::new(copiers::basecopier::Copier::new(4096)),
+            Box::new(copiers::basecopier::Copier::adaptive()),
+            Box::new(copiers::zerocopier::Copier::new(4096)),
+        ];
+        for copier in copiers.iter_mut() {
+            let faults = Faults {
+                short_reads: Some(1000),
+                ..Default::default()
+            };
+            let (storage, progress, ret) = mem_copy(&mut **copier, faults);
+
+            assert_eq!(ret.unwrap(), 40_000);
+            assert_eq!(
+                storage.read_file("/des.bin").unwrap(),
+                storage.read_file("/src.bin").unwrap()
+            );
+            assert_eq!(*progress.length.borrow(), 40_000);
+            assert_eq!(progress.copied.borrow().last(), Some(&40_000));
+            assert!(progress.copied.borrow().windows(2).all(|w| w[0] < w[1]));
+        }
+    }
+
+    #[test]
+    fn copy_stops_at_failed_write() {
+        let faults = Faults {
+            fail_write: Some(3),
+            ..Default::default()
+        };
+        let (storage, progress, ret) =
+            mem_copy(&mut copiers::basecopier::Copier::new(4096), faults);
+
+        assert_eq!(ret.unwrap_err().raw_os_error(), Some(libc::EIO));
+        // the chunks follow the block size of the storage
+        assert_eq!(*progress.copied.borrow(), [4096, 8192]);
+        assert_eq!(storage.read_file("/des.bin").unwrap().len(), 8192);
+    }
+
+    #[test]
+    fn copy_stops_when_full() {
+        let faults = Faults {
+            capacity: Some(40_000 + 10_000),
+            ..Default::default()
+        };
+        let (storage, progress, ret) =
+            mem_copy(&mut copiers::basecopier::Copier::new(4096), faults);
+
+        assert_eq!(ret.unwrap_err().kind(), std::io::ErrorKind::StorageFull);
+        assert_eq!(*progress.copied.borrow(), [4096, 8192]);
+        assert_eq!(storage.read_file("/des.bin").unwrap().len(), 10_000);
+    }
+
+    #[test]
+    fn preallocate_keeps_size() {
+        let temp_dir = tempfile::tempdir_in(".").unwrap();
+        let probe = std::fs::File::create(temp_dir.path().join("probe.bin")).unwrap();
+        let ret = unsafe { libc::fallocate(probe.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, 1) };
+        if ret != 0 && std::io::Error::last_os_error().raw_os_error() == Some(libc::EOPNOTSUPP) {
+            return;
+        }
+        let des = std::fs::File::create(temp_dir.path().join("des.bin")).unwrap();
+
+        preallocate(&des, 1024 * 1024).unwrap();
+
+        let metadata = des.metadata().unwrap();
+        assert_eq!(metadata.len(), 0);
+        assert!(metadata.blocks() * 512 >= 1024 * 1024);
+    }
+
+    #[test]
+    fn copy_span_stops_at_limit() {
+        let content: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let temp_dir = tempfile::tempdir_in(".").unwrap();
+        let src_path = temp_dir.path().join("src.bin");
+        std::fs::write(&src_path, &content).unwrap();
+
+        let mut copier = copiers::basecopier::Copier::new(4096);
+        let mut src = std::fs::File::open(&src_path).unwrap();
+        let mut parts = Vec::new();
+        for i in 0..3 {
+            let part_path = temp_dir.path().join(format!("part{}", i));
+            let mut part = std::fs::File::create(&part_path).unwrap();
+            let n = copier
+                .copy_span(&mut src, &mut part, Some(3_000), 0, &Quiet)
+                .unwrap();
+            assert_eq!(n, 3_000);
+            parts.push(part_path);
+        }
+        let mut rest = std::fs::File::create(temp_dir.path().join("rest")).unwrap();
+        let n = copier
+            .copy_span(&mut src, &mut rest, Some(3_000), 0, &Quiet)
+            .unwrap();
+        assert_eq!(n, 1_000);
+
+        let mut joined = Vec::new();
+        for part in parts.iter() {
+            joined.extend(std::fs::read(part).unwrap());
+        }
+        joined.extend(std::fs::read(temp_dir.path().join("rest")).unwrap());
+        assert_eq!(joined, content);
+    }
 }
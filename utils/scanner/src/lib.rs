@@ -1,13 +1,23 @@
 pub mod scanners;
 
+#[derive(Debug, thiserror::Error)]
+#[error("failed to scan {path}: {source}")]
+pub struct ScanError {
+    pub path: String,
+    #[source]
+    pub source: std::io::Error,
+}
+
+pub type Result<T> = std::result::Result<T, ScanError>;
+
 pub trait DirScan {
     fn in_scan_action(
         &self,
         cur_entry: &str,
         strip_depth: u32,
-    ) -> anyhow::Result<(Vec<String>, Vec<String>)>;
+    ) -> Result<(Vec<String>, Vec<String>)>;
 
-    fn scan(&self, paths: &[String], strip: bool) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+    fn scan(&self, paths: &[String], strip: bool) -> Result<(Vec<String>, Vec<String>)> {
         let mut src_paths: Vec<String> = Vec::with_capacity(paths.len() * 2);
         let mut des_paths: Vec<String> = Vec::with_capacity(paths.len() * 2);
 
@@ -1,16 +1,47 @@
-use super::super::DirScan;
-use anyhow::{Context, Result};
-use log::trace;
-use std::fs;
-use walkdir::WalkDir;
+use super::super::{DirScan, Result, ScanError};
+use log::{debug, trace};
+use std::path::Path;
+use storage::storages::localstorage::LocalStorage;
+use storage::Storage;
 
 pub struct BaseScanner<'a> {
     des_path: &'a str,
+    storage: &'a dyn Storage,
 }
 
 impl BaseScanner<'_> {
     pub fn new<'a>(des_path: &'a str) -> BaseScanner<'a> {
-        BaseScanner { des_path }
+        BaseScanner::with_storage(des_path, &LocalStorage)
+    }
+
+    /// Scans the sources found in `storage` instead of the local file systems.
+    pub fn with_storage<'a>(des_path: &'a str, storage: &'a dyn Storage) -> BaseScanner<'a> {
+        BaseScanner { des_path, storage }
+    }
+
+    // Lists the tree depth first without following symlinks, the entries that
+    // cannot be read are left out
+    fn walk(&self, path: &str, found: &mut Vec<String>) {
+        found.push(path.to_string());
+
+        let names = match self.storage.list(path) {
+            Ok(names) => names,
+            Err(e) => {
+                debug!("Failed to list {}: {}", path, e);
+                return;
+            }
+        };
+        for name in names {
+            let child = Path::new(path).join(name);
+            let child = child.to_str().unwrap();
+            trace!("{} found!", child);
+
+            match self.storage.lstat(child) {
+                Ok(stat) if stat.is_dir() => self.walk(child, found),
+                Ok(_) => found.push(child.to_string()),
+                Err(e) => debug!("Failed to stat {}: {}", child, e),
+            }
+        }
     }
 }
 
@@ -28,22 +59,23 @@ impl DirScan for BaseScanner<'_> {
             .trim_end_matches(cur_entry.trim_end_matches('/').rsplit('/').next().unwrap());
         trace!("parent_entry: {}", parent_entry);
 
-        let metadata = fs::metadata(cur_entry)
-            .with_context(|| format!("failed to read metadata of {}", cur_entry))?;
-
-        if metadata.is_dir() {
-            for entry in WalkDir::new(cur_entry)
-                .follow_root_links(false)
-                .into_iter()
-                .filter_map(Result::ok)
-            {
-                let src_entry = entry.path().to_str().unwrap();
-                trace!("{} found!", src_entry);
-
-                src_paths.push(src_entry.to_string());
+        let stat = self.storage.stat(cur_entry).map_err(|e| ScanError {
+            path: cur_entry.to_string(),
+            source: e,
+        })?;
+
+        if stat.is_dir() {
+            // a symlink to a directory is copied as it is
+            let mut entries = Vec::new();
+            match self.storage.lstat(cur_entry) {
+                Ok(stat) if stat.is_symlink() => entries.push(cur_entry.to_string()),
+                _ => self.walk(cur_entry, &mut entries),
+            }
 
+            for src_entry in entries {
                 let des_entry =
-                    generate_destination_path(src_entry, parent_entry, self.des_path, strip_depth);
+                    generate_destination_path(&src_entry, parent_entry, self.des_path, strip_depth);
+                src_paths.push(src_entry);
                 des_paths.push(des_entry);
             }
         } else {
@@ -86,7 +118,7 @@ fn generate_destination_path(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::{fs::File, vec};
+    use std::fs::{self, File};
     use tempfile;
 
     #[test]
@@ -109,7 +141,7 @@ mod tests {
 
         let (src_paths, des_paths) = scanner
             .scan(
-                &vec![
+                &[
                     src_dir_path.to_str().unwrap().to_string(),
                     relative_src.clone(),
                 ],
@@ -154,7 +186,7 @@ mod tests {
 
         let (src_paths, des_paths) = scanner
             .scan(
-                &vec![src_file_path.to_str().unwrap().to_string()],
+                &[src_file_path.to_str().unwrap().to_string()],
                 false, // No stripping
             )
             .unwrap();
@@ -180,7 +212,7 @@ mod tests {
         let src_dir = tempfile::tempdir_in(".").unwrap();
         let src_dir_path = src_dir.path();
         let src_link_path = src_dir_path.join("my-temporary-note.link");
-        let _link = std::os::unix::fs::symlink(temp_dir_path, &src_link_path).unwrap();
+        std::os::unix::fs::symlink(temp_dir_path, &src_link_path).unwrap();
 
         let des_dir = tempfile::tempdir_in(".").unwrap();
         let des_dir_path = des_dir.path();
@@ -188,7 +220,7 @@ mod tests {
         let scanner = BaseScanner::new(des_dir_path.to_str().unwrap());
         let (src_paths, des_paths) = scanner
             .scan(
-                &vec![src_dir_path.to_str().unwrap().to_string()],
+                &[src_dir_path.to_str().unwrap().to_string()],
                 false, // No stripping
             )
             .unwrap();
@@ -213,7 +245,7 @@ mod tests {
 
         let scanner = BaseScanner::new(des_dir_path.to_str().unwrap());
         let ret = scanner.scan(
-            &vec!["nonexistent".to_string()],
+            &["nonexistent".to_string()],
             false, // No stripping
         );
         assert!(ret.is_err());
@@ -236,7 +268,7 @@ mod tests {
         // Test with strip = false (include the parent directory)
         let (src_paths_no_strip, des_paths_no_strip) = scanner
             .scan(
-                &vec![src_path.to_string()],
+                &[src_path.to_string()],
                 false, // No stripping
             )
             .unwrap();
@@ -250,7 +282,7 @@ mod tests {
         // Test with strip = true (exclude the parent directory)
         let (src_paths_strip, des_paths_strip) = scanner
             .scan(
-                &vec![src_path.to_string()],
+                &[src_path.to_string()],
                 true, // Strip parent directory
             )
             .unwrap();
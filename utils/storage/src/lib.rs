@@ -0,0 +1,149 @@
+pub mod storages;
+
+use std::io::{self, Read, Seek, Write};
+use std::os::fd::RawFd;
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Dir,
+    Symlink,
+    Fifo,
+    CharDevice,
+    BlockDevice,
+    Socket,
+}
+
+impl Kind {
+    /// Whether the entry is a node such as a fifo or a device.
+    pub fn is_special(self) -> bool {
+        matches!(
+            self,
+            Kind::Fifo | Kind::CharDevice | Kind::BlockDevice | Kind::Socket
+        )
+    }
+}
+
+/// The status of an entry, what the pipeline needs out of a `stat`.
+#[derive(Debug, Clone)]
+pub struct Stat {
+    pub kind: Kind,
+    pub len: u64,
+    /// the permission bits, with setuid, setgid and sticky
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    /// the device holding the entry
+    pub dev: u64,
+    /// the device a special file stands for
+    pub rdev: u64,
+    /// the preferred I/O size
+    pub blksize: u64,
+}
+
+impl Stat {
+    pub fn is_file(&self) -> bool {
+        self.kind == Kind::File
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind == Kind::Dir
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.kind == Kind::Symlink
+    }
+}
+
+/// The attributes to set on an entry, the unset ones are left alone.
+#[derive(Debug, Clone, Default)]
+pub struct Attrs {
+    pub mode: Option<u32>,
+    /// the user and the group
+    pub owner: Option<(u32, u32)>,
+    /// the access and the modification times
+    pub times: Option<(SystemTime, SystemTime)>,
+}
+
+/// A file opened by a storage.
+pub trait FileHandle: Read + Write + Seek + Send {
+    fn stat(&self) -> io::Result<Stat>;
+
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// The descriptor of a local file, for the copies the kernel does.
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+pub type Handle = Box<dyn FileHandle>;
+
+/// Where the sources are read from and the destinations written to. The
+/// paths are the ones given on the command line and found by the scanner.
+pub trait Storage {
+    /// The status of the entry, following symlinks.
+    fn stat(&self, path: &str) -> io::Result<Stat>;
+
+    /// The status of the entry itself, symlinks included.
+    fn lstat(&self, path: &str) -> io::Result<Stat>;
+
+    /// The names of the entries of a directory, in no particular order.
+    fn list(&self, path: &str) -> io::Result<Vec<String>>;
+
+    fn open_read(&self, path: &str) -> io::Result<Handle>;
+
+    /// Opens the file for writing, creating or truncating it.
+    fn open_write(&self, path: &str) -> io::Result<Handle>;
+
+    /// Opens an existing file for writing, keeping its content.
+    fn open_update(&self, path: &str) -> io::Result<Handle>;
+
+    fn mkdir(&self, path: &str) -> io::Result<()>;
+
+    fn symlink(&self, target: &str, path: &str) -> io::Result<()>;
+
+    fn read_link(&self, path: &str) -> io::Result<String>;
+
+    fn set_attrs(&self, path: &str, attrs: &Attrs) -> io::Result<()>;
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    fn remove_file(&self, path: &str) -> io::Result<()>;
+
+    /// Removes an empty directory.
+    fn remove_dir(&self, path: &str) -> io::Result<()>;
+
+    /// Removes the entry and everything below it.
+    fn remove_all(&self, path: &str) -> io::Result<()> {
+        if !self.lstat(path)?.is_dir() {
+            return self.remove_file(path);
+        }
+        for name in self.list(path)? {
+            self.remove_all(&format!("{}/{}", path, name))?;
+        }
+        self.remove_dir(path)
+    }
+
+    /// Creates a special file like the one `stat` describes.
+    fn mknod(&self, path: &str, _stat: &Stat) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("cannot create the special file {}", path),
+        ))
+    }
+
+    /// The bytes free for the files under `path`, None when unknown.
+    fn free_space(&self, _path: &str) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Whether the paths are the ones of the local file system, which the
+    /// archive and remote copies work on directly.
+    fn is_local(&self) -> bool {
+        false
+    }
+}
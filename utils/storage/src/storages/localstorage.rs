@@ -0,0 +1,262 @@
+use super::super::{Attrs, FileHandle, Handle, Kind, Stat, Storage};
+use std::ffi::CString;
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The local file systems, through `std::fs`.
+pub struct LocalStorage;
+
+fn time(secs: i64, nsecs: i64) -> SystemTime {
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::new(secs as u64, nsecs as u32)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + Duration::from_nanos(nsecs as u64)
+    }
+}
+
+impl From<&Metadata> for Stat {
+    fn from(metadata: &Metadata) -> Self {
+        let file_type = metadata.file_type();
+        let kind = if file_type.is_dir() {
+            Kind::Dir
+        } else if file_type.is_symlink() {
+            Kind::Symlink
+        } else if file_type.is_fifo() {
+            Kind::Fifo
+        } else if file_type.is_char_device() {
+            Kind::CharDevice
+        } else if file_type.is_block_device() {
+            Kind::BlockDevice
+        } else if file_type.is_socket() {
+            Kind::Socket
+        } else {
+            Kind::File
+        };
+
+        Stat {
+            kind,
+            len: metadata.len(),
+            mode: metadata.mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            atime: time(metadata.atime(), metadata.atime_nsec()),
+            mtime: time(metadata.mtime(), metadata.mtime_nsec()),
+            dev: metadata.dev(),
+            rdev: metadata.rdev(),
+            blksize: metadata.blksize(),
+        }
+    }
+}
+
+impl FileHandle for File {
+    fn stat(&self) -> io::Result<Stat> {
+        Ok(Stat::from(&self.metadata()?))
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+fn c_path(path: &str) -> io::Result<CString> {
+    CString::new(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+impl Storage for LocalStorage {
+    fn stat(&self, path: &str) -> io::Result<Stat> {
+        Ok(Stat::from(&fs::metadata(path)?))
+    }
+
+    fn lstat(&self, path: &str) -> io::Result<Stat> {
+        Ok(Stat::from(&fs::symlink_metadata(path)?))
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        fs::read_dir(path)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect()
+    }
+
+    fn open_read(&self, path: &str) -> io::Result<Handle> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn open_write(&self, path: &str) -> io::Result<Handle> {
+        Ok(Box::new(File::create(path)?))
+    }
+
+    fn open_update(&self, path: &str) -> io::Result<Handle> {
+        Ok(Box::new(File::options().write(true).open(path)?))
+    }
+
+    fn mkdir(&self, path: &str) -> io::Result<()> {
+        fs::create_dir(path)
+    }
+
+    fn symlink(&self, target: &str, path: &str) -> io::Result<()> {
+        std::os::unix::fs::symlink(target, path)
+    }
+
+    fn read_link(&self, path: &str) -> io::Result<String> {
+        Ok(fs::read_link(path)?.to_string_lossy().into_owned())
+    }
+
+    fn set_attrs(&self, path: &str, attrs: &Attrs) -> io::Result<()> {
+        if let Some(mode) = attrs.mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        if let Some((uid, gid)) = attrs.owner {
+            std::os::unix::fs::chown(path, Some(uid), Some(gid))?;
+        }
+        if let Some((atime, mtime)) = attrs.times {
+            filetime::set_file_times(
+                path,
+                filetime::FileTime::from_system_time(atime),
+                filetime::FileTime::from_system_time(mtime),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn remove_all(&self, path: &str) -> io::Result<()> {
+        if fs::symlink_metadata(path)?.is_dir() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        }
+    }
+
+    fn mknod(&self, path: &str, stat: &Stat) -> io::Result<()> {
+        let kind = match stat.kind {
+            Kind::Fifo => libc::S_IFIFO,
+            Kind::CharDevice => libc::S_IFCHR,
+            Kind::BlockDevice => libc::S_IFBLK,
+            Kind::Socket => libc::S_IFSOCK,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is not a special file", path),
+                ))
+            }
+        };
+
+        let path = c_path(path)?;
+        let ret = unsafe {
+            libc::mknod(
+                path.as_ptr(),
+                kind | (stat.mode & 0o7777) as libc::mode_t,
+                stat.rdev as libc::dev_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn free_space(&self, path: &str) -> io::Result<Option<u64>> {
+        let path = c_path(path)?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn stat_tells_kinds_apart() {
+        let temp_dir = tempfile::tempdir_in(".").unwrap();
+        let dir = temp_dir.path().to_str().unwrap();
+        let file = format!("{}/file.txt", dir);
+        let link = format!("{}/file.link", dir);
+        fs::write(&file, "Hello, world!").unwrap();
+
+        let storage = LocalStorage;
+        storage.symlink("file.txt", &link).unwrap();
+
+        assert!(storage.stat(dir).unwrap().is_dir());
+        assert_eq!(storage.stat(&link).unwrap().len, 13);
+        assert!(storage.lstat(&link).unwrap().is_symlink());
+        assert_eq!(storage.read_link(&link).unwrap(), "file.txt");
+
+        let mut names = storage.list(dir).unwrap();
+        names.sort();
+        assert_eq!(names, ["file.link", "file.txt"]);
+    }
+
+    #[test]
+    fn write_read_and_set_attrs() {
+        let temp_dir = tempfile::tempdir_in(".").unwrap();
+        let path = format!("{}/file.txt", temp_dir.path().to_str().unwrap());
+        let storage = LocalStorage;
+
+        let mut des = storage.open_write(&path).unwrap();
+        des.write_all(b"Hello, world!").unwrap();
+        assert_eq!(des.stat().unwrap().len, 13);
+        drop(des);
+
+        let mtime = UNIX_EPOCH + Duration::new(1_000_000_000, 500);
+        storage
+            .set_attrs(
+                &path,
+                &Attrs {
+                    mode: Some(0o640),
+                    times: Some((mtime, mtime)),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let stat = storage.stat(&path).unwrap();
+        assert_eq!(stat.mode, 0o640);
+        assert_eq!(stat.mtime, mtime);
+
+        let mut content = String::new();
+        storage
+            .open_read(&path)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "Hello, world!");
+    }
+
+    #[test]
+    fn remove_all_removes_trees() {
+        let temp_dir = tempfile::tempdir_in(".").unwrap();
+        let root = format!("{}/tree", temp_dir.path().to_str().unwrap());
+        fs::create_dir_all(format!("{}/sub", root)).unwrap();
+        fs::write(format!("{}/sub/file.txt", root), "Hello").unwrap();
+
+        LocalStorage.remove_all(&root).unwrap();
+        assert!(LocalStorage.lstat(&root).is_err());
+    }
+}
@@ -0,0 +1,761 @@
+use super::super::{Attrs, FileHandle, Handle, Kind, Stat, Storage};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+// symlinks followed while resolving a path before giving up, like Linux does
+const MAX_HOPS: usize = 40;
+
+/// The failures a `MemStorage` makes up, to test the error paths with.
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    /// fails the Nth write to any file, counted from 1, with `EIO`
+    pub fail_write: Option<usize>,
+    /// the bytes all the files may hold, writing past it fails with `ENOSPC`
+    pub capacity: Option<u64>,
+    /// the most bytes a read returns
+    pub short_reads: Option<usize>,
+    /// how long every read and write takes
+    pub delay: Option<Duration>,
+}
+
+enum Data {
+    File(Vec<u8>),
+    Dir,
+    Symlink(String),
+    Special(Kind, u64),
+}
+
+struct Inode {
+    data: Data,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    atime: SystemTime,
+    mtime: SystemTime,
+}
+
+impl Inode {
+    fn new(data: Data, mode: u32) -> Self {
+        let now = SystemTime::now();
+        Inode {
+            data,
+            mode,
+            uid: 0,
+            gid: 0,
+            atime: now,
+            mtime: now,
+        }
+    }
+
+    fn stat(&self) -> Stat {
+        let (kind, len, rdev) = match &self.data {
+            Data::File(data) => (Kind::File, data.len() as u64, 0),
+            Data::Dir => (Kind::Dir, 0, 0),
+            Data::Symlink(target) => (Kind::Symlink, target.len() as u64, 0),
+            Data::Special(kind, rdev) => (*kind, 0, *rdev),
+        };
+        Stat {
+            kind,
+            len,
+            mode: self.mode,
+            uid: self.uid,
+            gid: self.gid,
+            atime: self.atime,
+            mtime: self.mtime,
+            dev: 0,
+            rdev,
+            blksize: 4096,
+        }
+    }
+}
+
+struct State {
+    // the absolute paths without trailing slashes, the root is "/"
+    paths: BTreeMap<String, u64>,
+    inodes: HashMap<u64, Inode>,
+    next_ino: u64,
+    faults: Faults,
+    writes: usize,
+}
+
+fn error(errno: i32) -> io::Error {
+    io::Error::from_raw_os_error(errno)
+}
+
+fn parent(path: &str) -> &str {
+    match path.rsplit_once('/') {
+        Some(("", _)) | None => "/",
+        Some((parent, _)) => parent,
+    }
+}
+
+fn child(dir: &str, name: &str) -> String {
+    if dir == "/" {
+        format!("/{}", name)
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+impl State {
+    fn inode(&self, path: &str) -> Option<&Inode> {
+        self.paths.get(path).and_then(|ino| self.inodes.get(ino))
+    }
+
+    fn inode_mut(&mut self, path: &str) -> io::Result<&mut Inode> {
+        let ino = self.paths.get(path).ok_or_else(|| error(libc::ENOENT))?;
+        self.inodes.get_mut(ino).ok_or_else(|| error(libc::ENOENT))
+    }
+
+    // The path with the symlinks along it followed, the last one only when
+    // `follow` is set. The entry itself may not exist.
+    fn resolve(&self, path: &str, follow: bool) -> io::Result<String> {
+        let mut pending: Vec<String> = path
+            .split('/')
+            .filter(|name| !name.is_empty() && *name != ".")
+            .rev()
+            .map(str::to_string)
+            .collect();
+        let mut resolved = "/".to_string();
+        let mut hops = 0;
+
+        while let Some(name) = pending.pop() {
+            if name == ".." {
+                resolved = parent(&resolved).to_string();
+                continue;
+            }
+
+            let next = child(&resolved, &name);
+            let last = pending.is_empty();
+            match self.inode(&next).map(|inode| &inode.data) {
+                Some(Data::Symlink(target)) if !last || follow => {
+                    hops += 1;
+                    if hops > MAX_HOPS {
+                        return Err(error(libc::ELOOP));
+                    }
+                    if target.starts_with('/') {
+                        resolved = "/".to_string();
+                    }
+                    pending.extend(
+                        target
+                            .split('/')
+                            .filter(|name| !name.is_empty() && *name != ".")
+                            .rev()
+                            .map(str::to_string),
+                    );
+                    continue;
+                }
+                Some(Data::Dir) => {}
+                Some(_) if !last => return Err(error(libc::ENOTDIR)),
+                None if !last => return Err(error(libc::ENOENT)),
+                _ => {}
+            }
+            resolved = next;
+        }
+
+        Ok(resolved)
+    }
+
+    // Fails unless the parent of `path` is a directory
+    fn check_parent(&self, path: &str) -> io::Result<()> {
+        match self.inode(parent(path)).map(|inode| &inode.data) {
+            Some(Data::Dir) => Ok(()),
+            Some(_) => Err(error(libc::ENOTDIR)),
+            None => Err(error(libc::ENOENT)),
+        }
+    }
+
+    fn insert(&mut self, path: String, inode: Inode) -> u64 {
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.inodes.insert(ino, inode);
+        self.paths.insert(path, ino);
+        ino
+    }
+
+    // Creates an entry where none is, like mkdir and symlink do
+    fn create(&mut self, path: &str, data: Data, mode: u32) -> io::Result<u64> {
+        let path = self.resolve(path, false)?;
+        if self.paths.contains_key(&path) {
+            return Err(error(libc::EEXIST));
+        }
+        self.check_parent(&path)?;
+        Ok(self.insert(path, Inode::new(data, mode)))
+    }
+
+    fn children(&self, dir: &str) -> Vec<String> {
+        let prefix = child(dir, "");
+        self.paths
+            .range(prefix.clone()..)
+            .take_while(|(path, _)| path.starts_with(&prefix))
+            .map(|(path, _)| &path[prefix.len()..])
+            .filter(|name| !name.is_empty() && !name.contains('/'))
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn used(&self) -> u64 {
+        self.paths
+            .values()
+            .filter_map(|ino| match &self.inodes.get(ino)?.data {
+                Data::File(data) => Some(data.len() as u64),
+                _ => None,
+            })
+            .sum()
+    }
+
+    // The bytes a file may grow by before the storage is full
+    fn room(&self) -> u64 {
+        match self.faults.capacity {
+            Some(capacity) => capacity.saturating_sub(self.used()),
+            None => u64::MAX,
+        }
+    }
+}
+
+/// A file system in memory, with the failures of `Faults` made up on demand.
+/// The clones share the same files.
+#[derive(Clone)]
+pub struct MemStorage {
+    state: Arc<Mutex<State>>,
+}
+
+impl Default for MemStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemStorage {
+    /// An empty storage holding the root directory only.
+    pub fn new() -> Self {
+        let mut state = State {
+            paths: BTreeMap::new(),
+            inodes: HashMap::new(),
+            next_ino: 1,
+            faults: Faults::default(),
+            writes: 0,
+        };
+        state.insert("/".to_string(), Inode::new(Data::Dir, 0o755));
+        MemStorage {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    pub fn with_faults(faults: Faults) -> Self {
+        let storage = Self::new();
+        storage.set_faults(faults);
+        storage
+    }
+
+    /// Replaces the faults, the writes are counted from 0 again.
+    pub fn set_faults(&self, faults: Faults) {
+        let mut state = self.lock();
+        state.faults = faults;
+        state.writes = 0;
+    }
+
+    /// The writes made since the faults were last set.
+    pub fn writes(&self) -> usize {
+        self.lock().writes
+    }
+
+    /// Creates the directory and its missing parents, the faults do not apply.
+    pub fn create_dir_all(&self, path: &str) -> io::Result<()> {
+        let mut dir = String::new();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            dir.push('/');
+            dir.push_str(name);
+            match self.mkdir(&dir) {
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                ret => ret?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates or replaces a file in an existing directory, the faults do not apply.
+    pub fn write_file(&self, path: &str, content: &[u8]) -> io::Result<()> {
+        let mut state = self.lock();
+        let path = state.resolve(path, true)?;
+        match state.inode_mut(&path) {
+            Ok(inode) => match &mut inode.data {
+                Data::File(data) => *data = content.to_vec(),
+                Data::Dir => return Err(error(libc::EISDIR)),
+                _ => return Err(error(libc::EINVAL)),
+            },
+            Err(_) => {
+                state.check_parent(&path)?;
+                state.insert(path, Inode::new(Data::File(content.to_vec()), 0o644));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_file(&self, path: &str) -> io::Result<Vec<u8>> {
+        let state = self.lock();
+        let path = state.resolve(path, true)?;
+        match state.inode(&path).map(|inode| &inode.data) {
+            Some(Data::File(data)) => Ok(data.clone()),
+            Some(Data::Dir) => Err(error(libc::EISDIR)),
+            Some(_) => Err(error(libc::EINVAL)),
+            None => Err(error(libc::ENOENT)),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // a panicking test thread leaves nothing half done behind
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn open(&self, ino: u64, read: bool, write: bool) -> Handle {
+        Box::new(MemFile {
+            state: self.state.clone(),
+            ino,
+            pos: 0,
+            read,
+            write,
+        })
+    }
+}
+
+impl Storage for MemStorage {
+    fn stat(&self, path: &str) -> io::Result<Stat> {
+        let state = self.lock();
+        let path = state.resolve(path, true)?;
+        state
+            .inode(&path)
+            .map(Inode::stat)
+            .ok_or_else(|| error(libc::ENOENT))
+    }
+
+    fn lstat(&self, path: &str) -> io::Result<Stat> {
+        let state = self.lock();
+        let path = state.resolve(path, false)?;
+        state
+            .inode(&path)
+            .map(Inode::stat)
+            .ok_or_else(|| error(libc::ENOENT))
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let state = self.lock();
+        let path = state.resolve(path, true)?;
+        match state.inode(&path).map(|inode| &inode.data) {
+            Some(Data::Dir) => Ok(state.children(&path)),
+            Some(_) => Err(error(libc::ENOTDIR)),
+            None => Err(error(libc::ENOENT)),
+        }
+    }
+
+    fn open_read(&self, path: &str) -> io::Result<Handle> {
+        let state = self.lock();
+        let path = state.resolve(path, true)?;
+        match state.inode(&path).map(|inode| &inode.data) {
+            Some(Data::File(_)) => Ok(self.open(state.paths[&path], true, false)),
+            Some(Data::Dir) => Err(error(libc::EISDIR)),
+            Some(_) => Err(error(libc::EINVAL)),
+            None => Err(error(libc::ENOENT)),
+        }
+    }
+
+    fn open_write(&self, path: &str) -> io::Result<Handle> {
+        let mut state = self.lock();
+        let path = state.resolve(path, true)?;
+        if let Ok(inode) = state.inode_mut(&path) {
+            return match &mut inode.data {
+                Data::File(data) => {
+                    data.clear();
+                    inode.mtime = SystemTime::now();
+                    let ino = state.paths[&path];
+                    Ok(self.open(ino, false, true))
+                }
+                Data::Dir => Err(error(libc::EISDIR)),
+                _ => Err(error(libc::EINVAL)),
+            };
+        }
+
+        state.check_parent(&path)?;
+        let ino = state.insert(path, Inode::new(Data::File(Vec::new()), 0o644));
+        Ok(self.open(ino, false, true))
+    }
+
+    fn open_update(&self, path: &str) -> io::Result<Handle> {
+        let state = self.lock();
+        let path = state.resolve(path, true)?;
+        match state.inode(&path).map(|inode| &inode.data) {
+            Some(Data::File(_)) => Ok(self.open(state.paths[&path], false, true)),
+            Some(Data::Dir) => Err(error(libc::EISDIR)),
+            Some(_) => Err(error(libc::EINVAL)),
+            None => Err(error(libc::ENOENT)),
+        }
+    }
+
+    fn mkdir(&self, path: &str) -> io::Result<()> {
+        self.lock().create(path, Data::Dir, 0o755).map(|_| ())
+    }
+
+    fn symlink(&self, target: &str, path: &str) -> io::Result<()> {
+        self.lock()
+            .create(path, Data::Symlink(target.to_string()), 0o777)
+            .map(|_| ())
+    }
+
+    fn read_link(&self, path: &str) -> io::Result<String> {
+        let state = self.lock();
+        let path = state.resolve(path, false)?;
+        match state.inode(&path).map(|inode| &inode.data) {
+            Some(Data::Symlink(target)) => Ok(target.clone()),
+            Some(_) => Err(error(libc::EINVAL)),
+            None => Err(error(libc::ENOENT)),
+        }
+    }
+
+    fn set_attrs(&self, path: &str, attrs: &Attrs) -> io::Result<()> {
+        let mut state = self.lock();
+        let path = state.resolve(path, true)?;
+        let inode = state.inode_mut(&path)?;
+        if let Some(mode) = attrs.mode {
+            inode.mode = mode & 0o7777;
+        }
+        if let Some((uid, gid)) = attrs.owner {
+            inode.uid = uid;
+            inode.gid = gid;
+        }
+        if let Some((atime, mtime)) = attrs.times {
+            inode.atime = atime;
+            inode.mtime = mtime;
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut state = self.lock();
+        let from = state.resolve(from, false)?;
+        let to = state.resolve(to, false)?;
+        let from_is_dir = match state.inode(&from) {
+            Some(inode) => matches!(inode.data, Data::Dir),
+            None => return Err(error(libc::ENOENT)),
+        };
+        if from == to {
+            return Ok(());
+        }
+        if to.starts_with(&child(&from, "")) {
+            return Err(error(libc::EINVAL));
+        }
+        state.check_parent(&to)?;
+        match state.inode(&to).map(|inode| &inode.data) {
+            Some(Data::Dir) if !from_is_dir => return Err(error(libc::EISDIR)),
+            Some(Data::Dir) if !state.children(&to).is_empty() => {
+                return Err(error(libc::ENOTEMPTY))
+            }
+            Some(Data::Dir) | None => {}
+            Some(_) if from_is_dir => return Err(error(libc::ENOTDIR)),
+            Some(_) => {}
+        }
+
+        state.paths.remove(&to);
+        let prefix = child(&from, "");
+        let moved: Vec<String> = state
+            .paths
+            .keys()
+            .filter(|path| **path == from || path.starts_with(&prefix))
+            .cloned()
+            .collect();
+        for path in moved {
+            let ino = state.paths.remove(&path).unwrap();
+            state
+                .paths
+                .insert(format!("{}{}", to, &path[from.len()..]), ino);
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        let mut state = self.lock();
+        let path = state.resolve(path, false)?;
+        match state.inode(&path).map(|inode| &inode.data) {
+            Some(Data::Dir) => Err(error(libc::EISDIR)),
+            Some(_) => {
+                state.paths.remove(&path);
+                Ok(())
+            }
+            None => Err(error(libc::ENOENT)),
+        }
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        let mut state = self.lock();
+        let path = state.resolve(path, false)?;
+        match state.inode(&path).map(|inode| &inode.data) {
+            Some(Data::Dir) if path == "/" => Err(error(libc::EBUSY)),
+            Some(Data::Dir) if !state.children(&path).is_empty() => Err(error(libc::ENOTEMPTY)),
+            Some(Data::Dir) => {
+                state.paths.remove(&path);
+                Ok(())
+            }
+            Some(_) => Err(error(libc::ENOTDIR)),
+            None => Err(error(libc::ENOENT)),
+        }
+    }
+
+    fn mknod(&self, path: &str, stat: &Stat) -> io::Result<()> {
+        if !stat.kind.is_special() {
+            return Err(error(libc::EINVAL));
+        }
+        self.lock()
+            .create(path, Data::Special(stat.kind, stat.rdev), stat.mode)
+            .map(|_| ())
+    }
+
+    fn free_space(&self, _path: &str) -> io::Result<Option<u64>> {
+        let state = self.lock();
+        Ok(state.faults.capacity.map(|_| state.room()))
+    }
+}
+
+// An open file of a `MemStorage`, it keeps the file even once removed
+struct MemFile {
+    state: Arc<Mutex<State>>,
+    ino: u64,
+    pos: u64,
+    read: bool,
+    write: bool,
+}
+
+impl MemFile {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Sleeps without holding the lock, the other handles go on meanwhile
+    fn delay(&self) {
+        if let Some(delay) = self.lock().faults.delay {
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+impl Read for MemFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.read {
+            return Err(error(libc::EBADF));
+        }
+        self.delay();
+
+        let mut state = self.lock();
+        let limit = state.faults.short_reads.unwrap_or(usize::MAX);
+        let inode = state
+            .inodes
+            .get_mut(&self.ino)
+            .ok_or_else(|| error(libc::ENOENT))?;
+        let Data::File(data) = &inode.data else {
+            return Err(error(libc::EINVAL));
+        };
+
+        let start = (self.pos as usize).min(data.len());
+        let n = buf.len().min(limit).min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        inode.atime = SystemTime::now();
+        drop(state);
+
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.write {
+            return Err(error(libc::EBADF));
+        }
+        self.delay();
+
+        let mut state = self.lock();
+        state.writes += 1;
+        if state.faults.fail_write == Some(state.writes) {
+            return Err(error(libc::EIO));
+        }
+
+        let room = state.room();
+        let inode = state
+            .inodes
+            .get_mut(&self.ino)
+            .ok_or_else(|| error(libc::ENOENT))?;
+        let Data::File(data) = &mut inode.data else {
+            return Err(error(libc::EINVAL));
+        };
+
+        // a full storage takes what fits, the next write fails
+        let start = self.pos as usize;
+        let growth = (start + buf.len()).saturating_sub(data.len()) as u64;
+        let n = if growth > room {
+            buf.len() - (growth - room) as usize
+        } else {
+            buf.len()
+        };
+        if n == 0 && !buf.is_empty() {
+            return Err(error(libc::ENOSPC));
+        }
+
+        if data.len() < start + n {
+            data.resize(start + n, 0);
+        }
+        data[start..start + n].copy_from_slice(&buf[..n]);
+        inode.mtime = SystemTime::now();
+        drop(state);
+
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.stat()?.len;
+        let pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = pos.ok_or_else(|| error(libc::EINVAL))?;
+        Ok(self.pos)
+    }
+}
+
+impl FileHandle for MemFile {
+    fn stat(&self) -> io::Result<Stat> {
+        self.lock()
+            .inodes
+            .get(&self.ino)
+            .map(Inode::stat)
+            .ok_or_else(|| error(libc::ENOENT))
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        if !self.write {
+            return Err(error(libc::EINVAL));
+        }
+
+        let mut state = self.lock();
+        let room = state.room();
+        let inode = state
+            .inodes
+            .get_mut(&self.ino)
+            .ok_or_else(|| error(libc::ENOENT))?;
+        let Data::File(data) = &mut inode.data else {
+            return Err(error(libc::EINVAL));
+        };
+        if len.saturating_sub(data.len() as u64) > room {
+            return Err(error(libc::ENOSPC));
+        }
+        data.resize(len as usize, 0);
+        inode.mtime = SystemTime::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tree_works() {
+        let storage = MemStorage::new();
+        storage.create_dir_all("/src/sub").unwrap();
+        storage.write_file("/src/sub/file.txt", b"Hello").unwrap();
+        storage.symlink("sub", "/src/link").unwrap();
+
+        let mut names = storage.list("/src").unwrap();
+        names.sort();
+        assert_eq!(names, ["link", "sub"]);
+        assert!(storage.lstat("/src/link").unwrap().is_symlink());
+        assert_eq!(storage.stat("src/link/file.txt").unwrap().len, 5);
+        assert_eq!(
+            storage.mkdir("/src/sub").unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(
+            storage.remove_dir("/src").unwrap_err().kind(),
+            io::ErrorKind::DirectoryNotEmpty
+        );
+
+        storage.rename("/src", "/des").unwrap();
+        assert_eq!(storage.read_file("/des/link/file.txt").unwrap(), b"Hello");
+        assert!(storage.stat("/src").is_err());
+
+        storage.remove_all("/des").unwrap();
+        assert!(storage.list("/").unwrap().is_empty());
+    }
+
+    #[test]
+    fn handles_read_and_write() {
+        let storage = MemStorage::new();
+        let mut des = storage.open_write("/file.bin").unwrap();
+        des.write_all(b"Hello, world!").unwrap();
+        des.seek(SeekFrom::Start(7)).unwrap();
+        des.write_all(b"there").unwrap();
+        des.set_len(12).unwrap();
+        drop(des);
+
+        let mut content = String::new();
+        storage
+            .open_read("/file.bin")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "Hello, there");
+        assert!(storage.open_read("/file.bin").unwrap().write(b"x").is_err());
+    }
+
+    #[test]
+    fn faults_are_made_up() {
+        let storage = MemStorage::with_faults(Faults {
+            fail_write: Some(3),
+            short_reads: Some(2),
+            delay: Some(Duration::from_millis(10)),
+            ..Default::default()
+        });
+        storage.write_file("/src.txt", b"Hello").unwrap();
+        let start = std::time::Instant::now();
+
+        let mut buf = [0u8; 8];
+        assert_eq!(
+            storage
+                .open_read("/src.txt")
+                .unwrap()
+                .read(&mut buf)
+                .unwrap(),
+            2
+        );
+        assert!(start.elapsed() >= Duration::from_millis(10));
+
+        let mut des = storage.open_write("/des.txt").unwrap();
+        des.write_all(b"a").unwrap();
+        des.write_all(b"b").unwrap();
+        assert_eq!(
+            des.write_all(b"c").unwrap_err().raw_os_error(),
+            Some(libc::EIO)
+        );
+        des.write_all(b"d").unwrap();
+        assert_eq!(storage.writes(), 4);
+
+        storage.set_faults(Faults {
+            capacity: Some(9),
+            ..Default::default()
+        });
+        assert_eq!(storage.free_space("/").unwrap(), Some(1));
+        let mut des = storage.open_write("/full.txt").unwrap();
+        assert_eq!(des.write(b"abc").unwrap(), 1);
+        assert_eq!(
+            des.write_all(b"bc").unwrap_err().kind(),
+            io::ErrorKind::StorageFull
+        );
+    }
+}
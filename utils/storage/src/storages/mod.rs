@@ -0,0 +1,2 @@
+pub mod localstorage;
+pub mod memstorage;